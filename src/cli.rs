@@ -0,0 +1,768 @@
+//! Command-line interface of the ray tracer
+//!
+//! ray_tracer render <scene> [-o <image>] [--width N] [--height N] [--spp N] [--threads N] [--max-depth N]
+//!                    [--integrator NAME] [--spectral] [--checkpoint <file>] [--checkpoint-every SECONDS]
+//!                    [--aov NAME:FILE]...
+//!                    [--projection NAME] [--stereo IOD [--convergence D] [--stereo-layout LAYOUT]]
+//!                    [--exposure STOPS] [--tonemap NAME] [--transfer NAME]
+//! ray_tracer info <scene>
+//! ray_tracer demo [clock|spheres|planes|patterns]
+
+use ray_tracer::render::aov::Aov;
+use ray_tracer::render::image::{ImageError, ImageFormat};
+use ray_tracer::render::integrator::{self, SharedIntegrator};
+use ray_tracer::render::progress::{CancelToken, Progress, RenderObserver};
+use ray_tracer::render::shapes::ShapeKind;
+use ray_tracer::render::spectrum::Spectral;
+use ray_tracer::render::stereo::{Eye, Stereo, StereoImage, StereoLayout, StereoMode};
+use ray_tracer::render::tonemap::{PostProcess, ToneMap, Transfer};
+use ray_tracer::render::{self, Canvas, Projection, RenderError, Renderer};
+use ray_tracer::scene::{self, SceneError};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::projects;
+
+const USAGE: &str = "\
+Usage:
+    ray_tracer render <scene.yaml|scene.json> [options]
+    ray_tracer info <scene.yaml|scene.json>
+    ray_tracer demo [clock|spheres|planes|patterns]
+
+Render options:
+    -o, --output <file>   output image, the format is chosen by the extension (default: out.png)
+    --width <N>           image width in pixels (default: the scene camera)
+    --height <N>          image height in pixels (default: the scene camera)
+    --spp <N>             samples per pixel (default: 1)
+    --threads <N>         number of rendering threads (default: all cores)
+    --max-depth <N>       maximum recursion depth of a light path (default: 5)
+    --integrator <name>   whitted (Phong shading, default) or path (Monte Carlo path tracer)
+    --spectral            trace every sample at one wavelength, so dispersive materials
+                          split white light into colors (each sample is converted to RGB
+                          when it is traced, not when the image is written)
+    --projection <name>   camera projection: perspective, fisheye, equirectangular
+                          or orthographic:<view width> (default: the scene camera)
+    --stereo <iod>        render both eyes of a stereo rig with the interocular distance
+                          (in world units) around the scene camera
+    --convergence <d>     turn the eyes towards the point d units in front of the camera
+                          (toe-in), instead of keeping them parallel
+    --stereo-layout <l>   side-by-side (default), over-under, or separate, which writes
+                          <output>-left and <output>-right
+    --checkpoint <file>   save finished tiles into the file and resume from it when it exists;
+                          the file is removed once the image is written
+    --checkpoint-every <N>
+                          seconds between checkpoint saves (default: 60)
+    --aov <name>:<file>   also write an output variable (depth, normal, albedo, object-id
+                          or visibility) into the file, e.g. --aov depth:depth.pfm;
+                          they are written linear, except albedo, which gets the transfer
+                          of the format
+    --exposure <stops>    exposure compensation of the image (default: 0)
+    --tonemap <name>      tone mapping operator: none (default), clamp, reinhard or aces
+    --transfer <name>     transfer encoding: linear or srgb (default: srgb for PNG and PPM,
+                          linear for PFM and HDR)";
+
+/// Exit code of a successful run
+const EXIT_OK: u8 = 0;
+
+/// Exit code used when the scene could not be loaded
+const EXIT_SCENE: u8 = 1;
+
+/// Exit code used when the command line is invalid
+const EXIT_USAGE: u8 = 2;
+
+/// Exit code used when the image could not be written
+const EXIT_OUTPUT: u8 = 3;
+
+/// Parsed command
+enum Command {
+    Render(Box<RenderArgs>),
+    Info(PathBuf),
+    Demo(String),
+    Help,
+}
+
+/// Arguments of the render command
+struct RenderArgs {
+    scene: PathBuf,
+    output: PathBuf,
+    width: Option<usize>,
+    height: Option<usize>,
+    spp: Option<usize>,
+    threads: Option<usize>,
+    max_depth: Option<usize>,
+    integrator: Option<SharedIntegrator>,
+    spectral: bool,
+    checkpoint: Option<PathBuf>,
+    checkpoint_every: u64,
+    aovs: Vec<(Aov, PathBuf)>,
+    projection: Option<Projection>,
+    stereo: Option<Stereo>,
+
+    /// None writes the eyes into separate files
+    stereo_layout: Option<StereoLayout>,
+
+    exposure: f64,
+    tone_map: ToneMap,
+
+    /// None picks the transfer of the output format
+    transfer: Option<Transfer>,
+}
+
+impl RenderArgs {
+    /// Returns the post-processing stage of an image written in a given format
+    fn post_process(&self, format: ImageFormat) -> PostProcess {
+        let transfer = self.transfer.unwrap_or_else(|| default_transfer(format));
+        PostProcess::new(self.exposure, self.tone_map, transfer)
+    }
+}
+
+/// Error in the command line
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Runs the command line and returns the exit code of the process
+pub fn run<I: IntoIterator<Item = String>>(args: I) -> ExitCode {
+    let cmd = match parse(args.into_iter()) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let code = match cmd {
+        Command::Render(args) => render(*args),
+        Command::Info(path) => info(path),
+        Command::Demo(name) => demo(&name),
+        Command::Help => {
+            println!("{}", USAGE);
+            EXIT_OK
+        }
+    };
+    ExitCode::from(code)
+}
+
+/// Parses the arguments (without the program name)
+fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, UsageError> {
+    let cmd = match args.next() {
+        Some(cmd) => cmd,
+        None => return Err(UsageError("missing command".to_string())),
+    };
+
+    match cmd.as_str() {
+        "render" => parse_render(args).map(|a| Command::Render(Box::new(a))),
+        "info" => {
+            let scene = positional(&mut args, "info")?;
+            Ok(Command::Info(scene))
+        }
+        "demo" => {
+            let name = args.next().unwrap_or_else(|| "patterns".to_string());
+            no_more(&mut args)?;
+            Ok(Command::Demo(name))
+        }
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        _ => Err(UsageError(format!("unknown command '{}'", cmd))),
+    }
+}
+
+/// Parses the arguments of the render command
+fn parse_render<I: Iterator<Item = String>>(mut args: I) -> Result<RenderArgs, UsageError> {
+    let mut res = RenderArgs {
+        scene: PathBuf::new(),
+        output: PathBuf::from("out.png"),
+        width: None,
+        height: None,
+        spp: None,
+        threads: None,
+        max_depth: None,
+        integrator: None,
+        spectral: false,
+        checkpoint: None,
+        checkpoint_every: 60,
+        aovs: vec![],
+        projection: None,
+        stereo: None,
+        stereo_layout: Some(StereoLayout::SideBySide),
+        exposure: 0.0,
+        tone_map: ToneMap::None,
+        transfer: None,
+    };
+    let mut scene = None;
+    let (mut iod, mut convergence, mut layout) = (None, None, None);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => res.output = PathBuf::from(value(&mut args, &arg)?),
+            "--width" => res.width = Some(number(&mut args, &arg)?),
+            "--height" => res.height = Some(number(&mut args, &arg)?),
+            "--spp" => res.spp = Some(number(&mut args, &arg)?),
+            "--threads" => res.threads = Some(number(&mut args, &arg)?),
+            "--max-depth" => res.max_depth = Some(number(&mut args, &arg)?),
+            "--integrator" => {
+                let val = value(&mut args, &arg)?;
+                res.integrator = Some(integrator::from_name(&val).map_err(UsageError)?);
+            }
+            "--spectral" => res.spectral = true,
+            "--checkpoint" => res.checkpoint = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--checkpoint-every" => res.checkpoint_every = number(&mut args, &arg)? as u64,
+            "--aov" => res.aovs.push(aov(&value(&mut args, &arg)?)?),
+            "--stereo" => iod = Some(float(&mut args, &arg)?),
+            "--convergence" => convergence = Some(float(&mut args, &arg)?),
+            "--stereo-layout" => layout = Some(value(&mut args, &arg)?),
+            "--projection" => {
+                let val = value(&mut args, &arg)?;
+                res.projection = Some(val.parse().map_err(UsageError)?);
+            }
+            "--exposure" => res.exposure = finite(&mut args, &arg)?,
+            "--tonemap" => {
+                let val = value(&mut args, &arg)?;
+                res.tone_map = val.parse().map_err(UsageError)?;
+            }
+            "--transfer" => {
+                let val = value(&mut args, &arg)?;
+                res.transfer = Some(val.parse().map_err(UsageError)?);
+            }
+            _ if arg.starts_with('-') => {
+                return Err(UsageError(format!("unknown option '{}'", arg)))
+            }
+            _ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+            _ => return Err(UsageError(format!("unexpected argument '{}'", arg))),
+        }
+    }
+
+    res.scene = scene.ok_or_else(|| UsageError("render: missing scene file".to_string()))?;
+
+    match iod {
+        Some(iod) => {
+            res.stereo = Some(match convergence {
+                Some(d) => Stereo::new(iod, d, StereoMode::ToeIn),
+                None => Stereo::new(iod, 0.0, StereoMode::Parallel),
+            });
+            if res.checkpoint.is_some() || !res.aovs.is_empty() {
+                let msg = "--stereo cannot be combined with --checkpoint or --aov";
+                return Err(UsageError(msg.to_string()));
+            }
+        }
+        None if convergence.is_some() || layout.is_some() => {
+            let msg = "--convergence and --stereo-layout require --stereo";
+            return Err(UsageError(msg.to_string()));
+        }
+        None => {}
+    }
+    res.stereo_layout = match layout.as_deref() {
+        None | Some("side-by-side") => Some(StereoLayout::SideBySide),
+        Some("over-under") => Some(StereoLayout::OverUnder),
+        Some("separate") => None,
+        Some(other) => return Err(UsageError(format!("unknown stereo layout '{}'", other))),
+    };
+
+    // the eye images of the separate layout share the extension of the output
+    let outputs = iter::once(&res.output).chain(res.aovs.iter().map(|(_, path)| path));
+    for path in outputs {
+        ImageFormat::detect(path).map_err(|e| UsageError(e.to_string()))?;
+    }
+    Ok(res)
+}
+
+/// Returns the value of an option
+fn value<I: Iterator<Item = String>>(args: &mut I, opt: &str) -> Result<String, UsageError> {
+    args.next()
+        .ok_or_else(|| UsageError(format!("{} requires a value", opt)))
+}
+
+/// Returns the value of an option that has to be a positive integer
+fn number<I: Iterator<Item = String>>(args: &mut I, opt: &str) -> Result<usize, UsageError> {
+    let val = value(args, opt)?;
+    match val.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(UsageError(format!(
+            "{} expects a positive integer, got '{}'",
+            opt, val
+        ))),
+    }
+}
+
+/// Returns the value of an option that has to be a positive number
+fn float<I: Iterator<Item = String>>(args: &mut I, opt: &str) -> Result<f64, UsageError> {
+    let val = value(args, opt)?;
+    match val.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err(UsageError(format!(
+            "{} expects a positive number, got '{}'",
+            opt, val
+        ))),
+    }
+}
+
+/// Returns the value of an option that has to be a finite number
+fn finite<I: Iterator<Item = String>>(args: &mut I, opt: &str) -> Result<f64, UsageError> {
+    let val = value(args, opt)?;
+    match val.parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(UsageError(format!(
+            "{} expects a number, got '{}'",
+            opt, val
+        ))),
+    }
+}
+
+/// Parses the value of the --aov option (name:file)
+fn aov(val: &str) -> Result<(Aov, PathBuf), UsageError> {
+    let (name, file) = val
+        .split_once(':')
+        .ok_or_else(|| UsageError(format!("--aov expects <name>:<file>, got '{}'", val)))?;
+    let aov = name.parse::<Aov>().map_err(UsageError)?;
+    Ok((aov, PathBuf::from(file)))
+}
+
+/// Returns the only positional argument of a command
+fn positional<I: Iterator<Item = String>>(args: &mut I, cmd: &str) -> Result<PathBuf, UsageError> {
+    let res = args
+        .next()
+        .ok_or_else(|| UsageError(format!("{}: missing scene file", cmd)))?;
+    no_more(args)?;
+    Ok(PathBuf::from(res))
+}
+
+/// Checks that there are no arguments left
+fn no_more<I: Iterator<Item = String>>(args: &mut I) -> Result<(), UsageError> {
+    match args.next() {
+        Some(arg) => Err(UsageError(format!("unexpected argument '{}'", arg))),
+        None => Ok(()),
+    }
+}
+
+/// Loads the scene and applies the overrides of the command line
+fn load(args: &RenderArgs) -> Result<Renderer, SceneError> {
+    let mut r = scene::load_file(&args.scene)?;
+
+    if let Some(projection) = args.projection {
+        r.camera_mut().set_projection(projection);
+    }
+    if args.width.is_some() || args.height.is_some() {
+        let c = r.camera();
+        let width = args.width.unwrap_or(c.hsize);
+        let height = args.height.unwrap_or(c.vsize);
+        r.resize(width, height);
+    }
+    if let Some(spp) = args.spp {
+        r.spp = spp;
+    }
+    if let Some(max_depth) = args.max_depth {
+        r.max_depth = max_depth;
+    }
+    if let Some(integrator) = &args.integrator {
+        r.integrator = integrator.clone();
+    }
+    if args.spectral {
+        r.integrator = Arc::new(Spectral::new(r.integrator.clone()));
+    }
+    for (aov, _) in args.aovs.iter() {
+        r.enable_aov(*aov);
+    }
+    Ok(r)
+}
+
+/// Reports a scene error and returns the matching exit code
+fn scene_error(path: &Path, e: &SceneError) -> u8 {
+    match e {
+        // I/O errors already name the file
+        SceneError::Io { .. } => eprintln!("error: {}", e),
+        _ => eprintln!("error: {}: {}", path.display(), e),
+    }
+    EXIT_SCENE
+}
+
+/// Renders a scene file into an image
+fn render(args: RenderArgs) -> u8 {
+    let threads = args
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    if let Some(stereo) = args.stereo {
+        return render_stereo(&args, &stereo, threads);
+    }
+
+    let mut report = Reporter::new();
+    let cancel = CancelToken::new();
+    let res = match &args.checkpoint {
+        Some(path) => {
+            let every = Duration::from_secs(args.checkpoint_every);
+            render::render_parallel_resumable(
+                || load(&args),
+                threads,
+                path,
+                every,
+                &mut report,
+                &cancel,
+            )
+        }
+        None => render::render_parallel_with(|| load(&args), threads, &mut report, &cancel)
+            .map_err(RenderError::Scene),
+    };
+    report.finish();
+    let r = match res {
+        Ok((r, _)) => r,
+        Err(RenderError::Scene(e)) => return scene_error(&args.scene, &e),
+        Err(RenderError::Checkpoint(e)) => {
+            eprintln!("error: {}", e);
+            return EXIT_OUTPUT;
+        }
+    };
+
+    if let Err(e) = save_image(&args, r.canvas(), &args.output) {
+        eprintln!("error: {}", e);
+        return EXIT_OUTPUT;
+    }
+    for (aov, path) in args.aovs.iter() {
+        let cv = r
+            .aov(*aov)
+            .expect("Output variables are enabled when the scene is loaded");
+        if let Err(e) = save_aov(*aov, cv, path) {
+            eprintln!("error: {}", e);
+            return EXIT_OUTPUT;
+        }
+    }
+    if let Some(path) = &args.checkpoint {
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("warning: could not remove {}: {}", path.display(), e);
+        }
+    }
+
+    let cv = r.canvas();
+    println!(
+        "{}: {}x{} px, {} spp, {} thread(s)",
+        args.output.display(),
+        cv.width,
+        cv.height,
+        r.spp,
+        threads
+    );
+    EXIT_OK
+}
+
+/// Renders both eyes of a stereo rig into a composite image or two separate files
+fn render_stereo(args: &RenderArgs, stereo: &Stereo, threads: usize) -> u8 {
+    let mut eyes = vec![];
+    let mut spp = 0;
+    for eye in [Eye::Left, Eye::Right] {
+        let build = || {
+            let mut r = load(args)?;
+            *r.camera_mut() = stereo.eye(r.camera(), eye);
+            Ok(r)
+        };
+
+        let mut report = Reporter::new();
+        let res = render::render_parallel_with(build, threads, &mut report, &CancelToken::new());
+        report.finish();
+        match res {
+            Ok((r, _)) => {
+                spp = r.spp;
+                eyes.push(r.canvas().clone());
+            }
+            Err(e) => return scene_error(&args.scene, &e),
+        }
+    }
+
+    let right = eyes.pop().expect("Both eyes are rendered");
+    let left = eyes.pop().expect("Both eyes are rendered");
+    let (width, height) = (left.width, left.height);
+    let img = StereoImage { left, right };
+
+    let (res, written) = match args.stereo_layout {
+        Some(layout) => (
+            save_image(args, &img.compose(layout), &args.output),
+            args.output.display().to_string(),
+        ),
+        None => {
+            let (left, right) = (
+                eye_path(&args.output, "left"),
+                eye_path(&args.output, "right"),
+            );
+            let written = format!("{}, {}", left.display(), right.display());
+            let res = save_image(args, &img.left, &left)
+                .and_then(|_| save_image(args, &img.right, &right));
+            (res, written)
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        return EXIT_OUTPUT;
+    }
+
+    println!(
+        "{}: 2 x {}x{} px, {} spp, {} thread(s)",
+        written, width, height, spp, threads
+    );
+    EXIT_OK
+}
+
+/// Returns the transfer of a format: sRGB for the 8-bit images, which are displayed as they
+/// are, and linear for the float ones
+fn default_transfer(format: ImageFormat) -> Transfer {
+    match format {
+        ImageFormat::Ppm(_) | ImageFormat::Png(_) => Transfer::Srgb,
+        ImageFormat::Pfm | ImageFormat::Hdr => Transfer::Linear,
+    }
+}
+
+/// Saves an image through the post-processing stage of the command line
+fn save_image(args: &RenderArgs, cv: &Canvas, path: &Path) -> Result<(), ImageError> {
+    let format = ImageFormat::detect(path)?;
+    cv.save_with(path, format, &args.post_process(format))
+}
+
+/// Saves an output variable: albedo is a color and gets the transfer of the format,
+/// the others hold data and are written linear and unchanged
+fn save_aov(aov: Aov, cv: &Canvas, path: &Path) -> Result<(), ImageError> {
+    let format = ImageFormat::detect(path)?;
+    let transfer = match aov {
+        Aov::Albedo => default_transfer(format),
+        _ => Transfer::Linear,
+    };
+    cv.save_with(
+        path,
+        format,
+        &PostProcess::new(0.0, ToneMap::None, transfer),
+    )
+}
+
+/// Returns the path of an eye image: out.png becomes out-left.png
+fn eye_path(output: &Path, eye: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut name = format!("{}-{}", stem, eye);
+    if let Some(ext) = output.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    output.with_file_name(name)
+}
+
+/// Shows the progress of a render on stderr, if it is a terminal,
+/// so that redirected logs stay clean
+struct Reporter {
+    show: bool,
+    last: Option<u32>,
+}
+
+impl Reporter {
+    fn new() -> Self {
+        Self {
+            show: io::stderr().is_terminal(),
+            last: None,
+        }
+    }
+
+    /// Ends the progress line
+    fn finish(&self) {
+        if self.show {
+            eprintln!();
+        }
+    }
+}
+
+impl RenderObserver for Reporter {
+    fn progress(&mut self, p: &Progress) {
+        let percent = (p.fraction() * 100.0) as u32;
+        if self.show && self.last != Some(percent) {
+            self.last = Some(percent);
+            eprint!("\r{}", progress_line(p));
+        }
+    }
+}
+
+/// Formats a progress line: percentage, finished tiles, elapsed and remaining time
+fn progress_line(p: &Progress) -> String {
+    let eta = match p.eta {
+        Some(eta) => format!("{:.1}s", eta.as_secs_f64()),
+        None => "?".to_string(),
+    };
+    format!(
+        "rendering {:3.0}% ({}/{} tiles), elapsed {:.1}s, eta {}",
+        p.fraction() * 100.0,
+        p.done,
+        p.total,
+        p.elapsed.as_secs_f64(),
+        eta
+    )
+}
+
+/// Prints statistics of a scene file
+fn info(path: PathBuf) -> u8 {
+    let r = match scene::load_file(&path) {
+        Ok(r) => r,
+        Err(e) => return scene_error(&path, &e),
+    };
+
+    let (mut spheres, mut planes, mut other) = (0, 0, 0);
+    for obj in r.world.objects.iter() {
+        match obj.borrow().to_kind() {
+            Some(ShapeKind::Sphere(_)) => spheres += 1,
+            Some(ShapeKind::Plane(_)) => planes += 1,
+            None => other += 1,
+        }
+    }
+
+    let c = r.camera();
+    println!("scene:     {}", path.display());
+    println!(
+        "camera:    {}x{} px, fov {:.4} rad",
+        c.hsize, c.vsize, c.fov
+    );
+    println!(
+        "objects:   {} ({} spheres, {} planes, {} other)",
+        r.world.objects.len(),
+        spheres,
+        planes,
+        other
+    );
+    println!("points:    {}", r.world.points.len());
+    println!(
+        "lights:    {}",
+        r.world.sources.len() + r.world.directional.len()
+    );
+    println!("emitters:  {}", r.world.emitters().len());
+    EXIT_OK
+}
+
+/// Renders one of the built-in demo scenes into the img directory
+fn demo(name: &str) -> u8 {
+    match name {
+        "clock" => projects::draw_clock(),
+        "spheres" => projects::draw_spheres(),
+        "planes" => projects::draw_spheres_and_planes(),
+        "patterns" => projects::draw_patterns(),
+        _ => {
+            eprintln!("error: unknown demo '{}'\n\n{}", name, USAGE);
+            return EXIT_USAGE;
+        }
+    }
+    EXIT_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ray_tracer::math::Color;
+    use ray_tracer::render::image::PngDepth;
+
+    fn run_args(args: &[&str]) -> ExitCode {
+        run(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn exit_codes() {
+        let dir = std::env::temp_dir().join(format!("ray_tracer_cli_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/patterns.yaml");
+        let scene = scene.to_str().unwrap();
+        let out = dir.join("out.png");
+        let out = out.to_str().unwrap();
+        let small = [
+            "--width",
+            "8",
+            "--height",
+            "6",
+            "--spp",
+            "1",
+            "--threads",
+            "1",
+        ];
+
+        let mut args = vec!["render", scene, "-o", out];
+        args.extend(small);
+        assert_eq!(run_args(&args), ExitCode::from(EXIT_OK));
+        assert!(Path::new(out).exists());
+        assert_eq!(run_args(&["info", scene]), ExitCode::from(EXIT_OK));
+
+        // the scene cannot be read or parsed
+        let missing = dir.join("missing.yaml");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(
+            run_args(&["render", missing, "-o", out]),
+            ExitCode::from(EXIT_SCENE)
+        );
+        assert_eq!(run_args(&["info", missing]), ExitCode::from(EXIT_SCENE));
+        let malformed = dir.join("malformed.yaml");
+        fs::write(&malformed, "- add: sphere\n  material: [unclosed\n").unwrap();
+        let malformed = malformed.to_str().unwrap();
+        assert_eq!(
+            run_args(&["render", malformed, "-o", out]),
+            ExitCode::from(EXIT_SCENE)
+        );
+
+        // the command line is wrong, which is reported before the scene is loaded
+        let usage = ExitCode::from(EXIT_USAGE);
+        assert_eq!(run_args(&["render", scene, "--bogus"]), usage);
+        assert_eq!(run_args(&["frobnicate"]), usage);
+        assert_eq!(run_args(&["render", missing, "-o", "out.txt"]), usage);
+        assert_eq!(run_args(&["render", scene, "--aov", "depth:depth"]), usage);
+        assert_eq!(run_args(&["render", scene, "--spp", "0"]), usage);
+        assert_eq!(run_args(&["render", scene, "--tonemap", "filmic"]), usage);
+        assert_eq!(run_args(&["render", scene, "--exposure", "inf"]), usage);
+
+        // the image cannot be written
+        let unwritable = dir.join("missing").join("out.png");
+        let mut args = vec!["render", scene, "-o", unwritable.to_str().unwrap()];
+        args.extend(small);
+        assert_eq!(run_args(&args), ExitCode::from(EXIT_OUTPUT));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn post_processing() {
+        let parse_args = |args: &[&str]| {
+            let args = ["scene.yaml"].iter().chain(args).map(|s| s.to_string());
+            parse_render(args).unwrap_or_else(|e| panic!("{}", e))
+        };
+        let (png, hdr) = (ImageFormat::Png(PngDepth::Eight), ImageFormat::Hdr);
+
+        // 8-bit images are sRGB encoded, float images stay linear
+        let args = parse_args(&[]);
+        let post = |t| PostProcess::new(0.0, ToneMap::None, t);
+        assert_eq!(args.post_process(png), post(Transfer::Srgb));
+        assert_eq!(args.post_process(hdr), post(Transfer::Linear));
+
+        let args = parse_args(&[
+            "--exposure",
+            "-1.5",
+            "--tonemap",
+            "aces",
+            "--transfer",
+            "linear",
+        ]);
+        let post = PostProcess::new(-1.5, ToneMap::Aces, Transfer::Linear);
+        assert_eq!(
+            (args.post_process(png), args.post_process(hdr)),
+            (post, post)
+        );
+
+        // the tone mapped image is what gets written
+        let mut cv = Canvas::new(1, 1, Color::black());
+        cv.write(0, 0, Color::new(4.0, 0.5, 0.0)).unwrap();
+        let path = std::env::temp_dir().join(format!("ray_tracer_post_{}.pfm", std::process::id()));
+        let args = parse_args(&["--tonemap", "clamp", "--exposure", "1"]);
+        save_image(&args, &cv, &path).unwrap();
+        assert_eq!(
+            Canvas::read_image(&path).unwrap()[[0, 0]],
+            Color::new(1.0, 1.0, 0.0)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 pub mod math;
 pub mod render;
+pub mod scene;
 
 #[cfg(test)]
 mod tests;
@@ -1,5 +1,8 @@
+mod cli;
 pub mod projects;
 
-fn main() {
-    projects::draw_patterns();
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    cli::run(std::env::args().skip(1))
 }
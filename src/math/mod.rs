@@ -1,8 +1,10 @@
 use nalgebra as na;
+use serde::{Deserialize, Serialize};
 use std::{ops, rc::Rc};
 
 use super::render::core::Ray;
 
+pub mod sampler;
 pub mod utils;
 
 /// The only Vector-type used in the application.
@@ -15,7 +17,7 @@ pub type Matrix = na::Matrix4<f64>;
 /// r: red-value in the range 0..1
 /// g: green-value in the range 0..1
 /// b: blue-value in the range 0..1
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Color {
     pub r: f64,
     pub g: f64,
@@ -50,6 +52,21 @@ impl Color {
         )
     }
 
+    /// Converts Color to 8-bit RGB samples (used in binary image formats)
+    pub fn to_rgb8(&self) -> [u8; 3] {
+        [
+            Color::cvt(self.r) as u8,
+            Color::cvt(self.g) as u8,
+            Color::cvt(self.b) as u8,
+        ]
+    }
+
+    /// Converts Color to 16-bit RGB samples (used in high precision image formats)
+    pub fn to_rgb16(&self) -> [u16; 3] {
+        let cvt = |val: f64| (val.clamp(0.0, 1.0) * 65535.0).round() as u16;
+        [cvt(self.r), cvt(self.g), cvt(self.b)]
+    }
+
     pub fn black() -> Self {
         utils::color(0.0, 0.0, 0.0)
     }
@@ -174,7 +191,7 @@ impl ops::Mul<&Color> for &Color {
 }
 
 /// Enumeration that holds a convenient way of indicating single transformation units, and provides a framework for getting matrices.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TUnit {
     /// Translation(dx, dy, dz) represents a translation from the origin to (x0 + dx, y0 + dy, z0 + dz);
     Translate(f64, f64, f64),
@@ -287,7 +304,9 @@ impl ops::Mul<Ray> for &TUnit {
 }
 
 /// A data structure that represents an ordered set of TUnits (individual transformations). It stores the matrix form that must be computed once per set. However, when a new TUnit is added, new matrix is calculated by multiplying the old one with the new TUnit-matrix. On the other hand, whenever a TUnit is removed, the matrix must be re-computed.
-#[derive(Debug, Clone, PartialEq)]
+/// Transformation is serialized as the list of its TUnits, and the matrix is re-computed when it is loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Vec<TUnit>", into = "Vec<TUnit>")]
 pub struct Transformation {
     set: Vec<TUnit>,
     matrix: Matrix,
@@ -339,6 +358,30 @@ impl Transformation {
     pub fn inverse(&self) -> Option<Matrix> {
         self.matrix.try_inverse()
     }
+
+    /// Returns the ordered set of TUnits
+    pub fn units(&self) -> &[TUnit] {
+        &self.set
+    }
+}
+
+impl From<Vec<TUnit>> for Transformation {
+    /// Creates a Transformation from the owned TUnits, computing its matrix
+    fn from(set: Vec<TUnit>) -> Self {
+        let mut res = Self {
+            set,
+            matrix: Matrix::identity(),
+        };
+
+        res.adjust_matrix(0);
+        res
+    }
+}
+
+impl From<Transformation> for Vec<TUnit> {
+    fn from(t: Transformation) -> Self {
+        t.set
+    }
 }
 
 impl ops::Mul<Vector> for &Transformation {
@@ -0,0 +1,96 @@
+//! Contains the deterministic random number generator used for sampling.
+//! Every pixel sample gets its own independent stream, so the result of a render
+//! does not depend on the order (or the thread) in which the pixels are rendered.
+//! Also contains the warps of uniform numbers into directions used by the path tracer
+
+use std::f64::consts::PI;
+
+use super::{utils, Vector};
+
+/// PCG32 random number generator (XSH RR variant)
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    state: u64,
+    inc: u64,
+}
+
+impl Sampler {
+    /// Creates a new Sampler with a given seed and stream selector
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut res = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+
+        res.next_u32();
+        res.state = res.state.wrapping_add(seed);
+        res.next_u32();
+        res
+    }
+
+    /// Creates the Sampler of a given sample of the pixel (x, y)
+    pub fn for_pixel(x: usize, y: usize, sample: usize) -> Self {
+        let seed = splitmix64(((x as u64) << 32) ^ y as u64);
+        Self::new(seed, splitmix64(sample as u64))
+    }
+
+    /// Creates a Sampler seeded by hashing a sequence of words, so that equal keys
+    /// (e.g. the bits of a ray) always get the same numbers
+    pub fn hashed(key: &[u64]) -> Self {
+        let seed = key.iter().fold(0u64, |h, k| splitmix64(h ^ k));
+        Self::new(seed, splitmix64(seed))
+    }
+
+    /// Returns the next uniformly distributed u32
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Returns the next uniformly distributed number in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        let hi = (self.next_u32() as u64) << 21;
+        let lo = (self.next_u32() >> 11) as u64;
+        (hi | lo) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns the next pair of uniformly distributed numbers in [0, 1)
+    pub fn next_2d(&mut self) -> (f64, f64) {
+        (self.next_f64(), self.next_f64())
+    }
+}
+
+/// Returns two unit vectors that form an orthonormal basis together with the unit vector n
+pub fn orthonormal_basis(n: &Vector) -> (Vector, Vector) {
+    // Duff et al., "Building an Orthonormal Basis, Revisited"
+    let sign = 1.0_f64.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    let t = utils::vector(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let s = utils::vector(b, sign + n.y * n.y * a, -n.y);
+    (t, s)
+}
+
+/// Returns a direction in the hemisphere around the unit vector n with density cos(theta) / PI,
+/// warped from the uniform numbers u
+pub fn cosine_hemisphere(n: &Vector, u: (f64, f64)) -> Vector {
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    let z = (1.0 - u.0).max(0.0).sqrt();
+
+    let (t, s) = orthonormal_basis(n);
+    t * (r * phi.cos()) + s * (r * phi.sin()) + n * z
+}
+
+/// Scrambles a 64-bit value (used to derive independent seeds)
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
@@ -3,6 +3,7 @@ use ray_tracer::math::*;
 use ray_tracer::render::core::PointLight;
 use ray_tracer::*;
 use render::core::{Drawable, Pattern, PatternList};
+use render::image::PpmFormat;
 use render::shapes::{Plane, Sphere};
 use render::Renderer;
 
@@ -33,7 +34,8 @@ pub fn draw_clock() {
     }
 
     app.render();
-    app.generate_ppm("clock.ppm");
+    app.generate_ppm("clock.ppm")
+        .expect("Could not generate clock.ppm");
 }
 
 // pub fn draw_circle() {
@@ -199,7 +201,8 @@ pub fn draw_spheres() {
     app.world.add_src(light.wrap_box());
 
     app.render();
-    app.generate_ppm("spheres_shadows.ppm");
+    app.generate_ppm("spheres_shadows.ppm")
+        .expect("Could not generate spheres_shadows.ppm");
 }
 
 
@@ -260,7 +263,8 @@ pub fn draw_spheres_and_planes() {
     app.world.add_src(light.wrap_box());
 
     app.render();
-    app.generate_ppm("planes_with_strapes.ppm");
+    app.generate_ppm("planes_with_strapes.ppm")
+        .expect("Could not generate planes_with_strapes.ppm");
 }
 
 pub fn draw_patterns() {
@@ -329,5 +333,6 @@ pub fn draw_patterns() {
     app.world.add_src(light.wrap_box());
 
     app.render();
-    app.generate_ppm("patterns.ppm");
+    app.generate_ppm_as("patterns.ppm", PpmFormat::Raw)
+        .expect("Could not generate patterns.ppm");
 }
@@ -0,0 +1,139 @@
+//! Contains arbitrary output variables (AOVs): per-pixel data of the first hit that is written
+//! into separate Canvases next to the beauty image, for compositing and debugging
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::core::{Computations, Ray};
+use super::{Canvas, World};
+use crate::math::{utils, Color};
+
+/// Kind of an output variable. Pixels whose ray hits nothing are black in every AOV
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Aov {
+    /// Hit distance along the camera ray (Computations::t) in every channel
+    Depth,
+
+    /// World-space normal facing the camera (Computations::n) in the r, g, b channels
+    Normal,
+
+    /// Base color of the material (its pattern or color) at the hit
+    Albedo,
+
+    /// Index of the hit object in World::objects plus one in every channel
+    ObjectId,
+
+    /// Fraction of the light sources visible from the hit in every channel
+    Visibility,
+}
+
+impl Aov {
+    /// All the output variables
+    pub const ALL: [Aov; 5] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::Visibility,
+    ];
+
+    /// Returns the name of the output variable
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object-id",
+            Aov::Visibility => "visibility",
+        }
+    }
+
+    /// Returns the value of the output variable at a hit
+    pub fn eval(&self, world: &World, info: &Computations) -> Color {
+        match self {
+            Aov::Depth => gray(info.t),
+            Aov::Normal => utils::color(info.n.x, info.n.y, info.n.z),
+            Aov::Albedo => info.obj.borrow().get_material().albedo(&info.p),
+            Aov::ObjectId => match world.index_of(&info.obj) {
+                Some(i) => gray((i + 1) as f64),
+                None => Color::black(),
+            },
+            Aov::Visibility => gray(world.visibility(&info.over_p)),
+        }
+    }
+}
+
+impl fmt::Display for Aov {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .into_iter()
+            .find(|a| a.name() == s)
+            .ok_or_else(|| format!("unknown AOV '{}'", s))
+    }
+}
+
+/// Canvases of the enabled output variables
+#[derive(Debug, Clone, Default)]
+pub struct AovBuffers {
+    buffers: Vec<(Aov, Canvas)>,
+}
+
+impl AovBuffers {
+    /// Enables an output variable with a canvas of given dimensions (enabling it twice does nothing)
+    pub fn enable(&mut self, aov: Aov, width: usize, height: usize) {
+        if self.get(aov).is_none() {
+            self.buffers
+                .push((aov, Canvas::new(width, height, Color::black())));
+        }
+    }
+
+    /// Returns true if no output variable is enabled
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// Returns the enabled output variables
+    pub fn enabled(&self) -> Vec<Aov> {
+        self.buffers.iter().map(|(a, _)| *a).collect()
+    }
+
+    /// Returns the canvas of an output variable
+    pub fn get(&self, aov: Aov) -> Option<&Canvas> {
+        self.buffers.iter().find(|(a, _)| *a == aov).map(|(_, c)| c)
+    }
+
+    /// Recreates all canvases with new dimensions
+    pub fn resize(&mut self, width: usize, height: usize) {
+        for (_, cv) in self.buffers.iter_mut() {
+            *cv = Canvas::new(width, height, Color::black());
+        }
+    }
+
+    /// Writes the values of all output variables for a camera ray at the pixel (x, y)
+    pub fn write(&mut self, x: usize, y: usize, world: &World, r: &Ray) {
+        let xs = world.intersect(r);
+        let info = world
+            .surface_hit(&xs)
+            .map(|i| Computations::new(i.clone(), r));
+
+        for (aov, cv) in self.buffers.iter_mut() {
+            cv[[x, y]] = match &info {
+                Some(info) => aov.eval(world, info),
+                None => Color::black(),
+            };
+        }
+    }
+}
+
+/// Returns a Color with the same value in every channel
+fn gray(v: f64) -> Color {
+    utils::color(v, v, v)
+}
@@ -0,0 +1,575 @@
+//! Contains the BSDFs (bidirectional scattering distribution functions) of the materials:
+//! the Lambertian reflection used for Phong materials by the path tracer, the GGX
+//! (Trowbridge-Reitz) microfacet model of the metallic/roughness workflow, and smooth or rough
+//! dielectrics (glass) and conductors (metals with a complex index of refraction)
+//!
+//! Directions point away from the surface (wo towards the viewer, wi towards the light),
+//! and the BSDFs are evaluated in the local frame of the shading normal (z = n)
+
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use super::core::{Computations, Material};
+use crate::math::sampler::{cosine_hemisphere, orthonormal_basis, Sampler};
+use crate::math::{utils, Color, Vector};
+
+/// Smallest GGX alpha, which keeps smooth surfaces numerically stable
+const MIN_ALPHA: f64 = 1e-3;
+
+/// Smallest GGX alpha of rough dielectrics and conductors, below it they are perfectly smooth
+const SMOOTH_ALPHA: f64 = 1e-3;
+
+/// Reflectance of dielectrics at normal incidence in the metallic/roughness workflow
+const DIELECTRIC_F0: f64 = 0.04;
+
+/// Reflection model of a Material
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum BsdfKind {
+    /// Phong model: the light loop uses ambient, diffuse, specular and shininess,
+    /// the path tracer reflects diffusely with the color scaled by diffuse
+    #[default]
+    Phong,
+
+    /// GGX microfacet model with Smith shadowing and Schlick's Fresnel; the material color
+    /// (or pattern) is the base color, metallic and roughness are in 0..1
+    Microfacet { metallic: f64, roughness: f64 },
+
+    /// Glass-like dielectric with an index of refraction (relative to the outside of the object);
+    /// the material color tints the transmitted light, and roughness 0 is perfectly smooth
+    Dielectric { ior: f64, roughness: f64 },
+
+    /// Metal with the complex index of refraction eta + i k per channel; the material color tints
+    /// the reflected light, and roughness 0 is perfectly smooth
+    Conductor {
+        eta: Color,
+        k: Color,
+        roughness: f64,
+    },
+}
+
+/// Names of the conductors with built-in indices of refraction
+pub const CONDUCTORS: [&str; 3] = ["gold", "copper", "aluminium"];
+
+impl BsdfKind {
+    /// Returns the conductor with the index of refraction of a metal in CONDUCTORS
+    /// (measured at 650, 550 and 450 nm for the r, g and b channels)
+    pub fn conductor(name: &str, roughness: f64) -> Option<Self> {
+        let (eta, k) = match name {
+            "gold" => ((0.18299, 0.42108, 1.3734), (3.4242, 2.3459, 1.7704)),
+            "copper" => ((0.27105, 0.67693, 1.3164), (3.6092, 2.6248, 2.2921)),
+            "aluminium" | "aluminum" => ((1.65746, 0.88069, 0.52124), (9.22387, 6.26952, 4.837)),
+            _ => return None,
+        };
+
+        Some(BsdfKind::Conductor {
+            eta: utils::color(eta.0, eta.1, eta.2),
+            k: utils::color(k.0, k.1, k.2),
+            roughness,
+        })
+    }
+}
+
+/// Orthonormal frame around a normal
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    t: Vector,
+    s: Vector,
+    n: Vector,
+}
+
+impl Frame {
+    /// Creates the frame of a unit normal
+    pub fn new(n: &Vector) -> Self {
+        let (t, s) = orthonormal_basis(n);
+        Self { t, s, n: *n }
+    }
+
+    /// Transforms a world direction into the frame
+    pub fn to_local(&self, v: &Vector) -> Vector {
+        utils::vector(
+            utils::dot(v, &self.t),
+            utils::dot(v, &self.s),
+            utils::dot(v, &self.n),
+        )
+    }
+
+    /// Transforms a direction of the frame into the world
+    pub fn to_world(&self, v: &Vector) -> Vector {
+        self.t * v.x + self.s * v.y + self.n * v.z
+    }
+}
+
+/// Direction sampled from a BSDF:
+/// wi: sampled world direction
+/// weight: BSDF value times the cosine divided by the density (the throughput factor of the path)
+/// pdf: density of wi per solid angle (the probability of the chosen lobe for delta BSDFs)
+/// delta: true if wi was chosen from a perfectly smooth (delta) lobe
+#[derive(Debug, Clone, PartialEq)]
+pub struct BsdfSample {
+    pub wi: Vector,
+    pub weight: Color,
+    pub pdf: f64,
+    pub delta: bool,
+}
+
+/// BSDF of a Material at a point of the surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bsdf {
+    /// Ideal diffuse reflection
+    Lambert { albedo: Color },
+
+    /// GGX microfacet reflection over a diffuse base
+    Microfacet {
+        base: Color,
+        metallic: f64,
+        alpha: f64,
+    },
+
+    /// Reflection and refraction at the boundary of a dielectric:
+    /// eta: index of refraction of the side of wi over the side of wo
+    /// alpha: GGX alpha (0 for a smooth boundary)
+    Dielectric { eta: f64, alpha: f64, tint: Color },
+
+    /// Reflection from a conductor (alpha 0 for a smooth surface)
+    Conductor {
+        eta: Color,
+        k: Color,
+        alpha: f64,
+        tint: Color,
+    },
+}
+
+impl Bsdf {
+    /// Returns the BSDF of a Material at the point p, seen from the outside of the object
+    pub fn new(m: &Material, p: &Vector) -> Self {
+        Self::facing(m, p, false, None)
+    }
+
+    /// Returns the BSDF at a hit, seen from the side of the ray. Dielectrics with a Dispersion
+    /// use their index of refraction at the wavelength of the ray, if it has one
+    pub fn from_hit(info: &Computations) -> Self {
+        let obj = info.obj.borrow();
+        Self::facing(obj.get_material(), &info.p, info.inside, info.wavelength)
+    }
+
+    fn facing(m: &Material, p: &Vector, inside: bool, wavelength: Option<f64>) -> Self {
+        let color = m.albedo(p);
+        match m.bsdf {
+            BsdfKind::Phong => Bsdf::Lambert {
+                albedo: color * m.diffuse,
+            },
+            BsdfKind::Microfacet {
+                metallic,
+                roughness,
+            } => Bsdf::Microfacet {
+                base: color,
+                metallic: metallic.clamp(0.0, 1.0),
+                alpha: (roughness * roughness).max(MIN_ALPHA),
+            },
+            BsdfKind::Dielectric { ior, roughness } => {
+                let ior = match (m.dispersion, wavelength) {
+                    (Some(d), Some(l)) => d.ior(l),
+                    _ => ior,
+                };
+                Bsdf::Dielectric {
+                    eta: if inside { 1.0 / ior } else { ior },
+                    alpha: smooth_alpha(roughness),
+                    tint: color,
+                }
+            }
+            BsdfKind::Conductor { eta, k, roughness } => Bsdf::Conductor {
+                eta,
+                k,
+                alpha: smooth_alpha(roughness),
+                tint: color,
+            },
+        }
+    }
+
+    /// Returns true if the BSDF only scatters into discrete directions (perfectly smooth),
+    /// so eval() and pdf() are zero everywhere
+    pub fn is_delta(&self) -> bool {
+        match *self {
+            Bsdf::Dielectric { alpha, .. } | Bsdf::Conductor { alpha, .. } => alpha == 0.0,
+            _ => false,
+        }
+    }
+
+    /// Returns the value of the BSDF for the directions wo and wi around the unit normal n
+    pub fn eval(&self, wo: &Vector, wi: &Vector, n: &Vector) -> Color {
+        let frame = Frame::new(n);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z == 0.0 || self.is_delta() {
+            return Color::black();
+        }
+        if wi.z < 0.0 {
+            return match *self {
+                Bsdf::Dielectric { eta, alpha, tint } => {
+                    tint * rough_transmission(&wo, &wi, eta, alpha).0
+                }
+                _ => Color::black(),
+            };
+        }
+
+        match *self {
+            Bsdf::Lambert { albedo } => albedo * (1.0 / PI),
+            Bsdf::Microfacet {
+                base,
+                metallic,
+                alpha,
+            } => {
+                let h = (wo + wi).normalize();
+                let f = fresnel_schlick(&f0(&base, metallic), utils::dot(&wo, &h));
+                let specular = f * microfacet_reflection(&wo, &wi, alpha);
+
+                let white = utils::color(1.0, 1.0, 1.0);
+                let diffuse = (white - f) * base * ((1.0 - metallic) / PI);
+                specular + diffuse
+            }
+            Bsdf::Dielectric { eta, alpha, .. } => {
+                let h = (wo + wi).normalize();
+                let f = fresnel_dielectric(utils::dot(&wo, &h), eta);
+                gray(f * microfacet_reflection(&wo, &wi, alpha))
+            }
+            Bsdf::Conductor {
+                eta,
+                k,
+                alpha,
+                tint,
+            } => {
+                let h = (wo + wi).normalize();
+                let f = fresnel_conductor(utils::dot(&wo, &h), &eta, &k);
+                tint * f * microfacet_reflection(&wo, &wi, alpha)
+            }
+        }
+    }
+
+    /// Returns the density (per solid angle) with which sample() chooses wi
+    pub fn pdf(&self, wo: &Vector, wi: &Vector, n: &Vector) -> f64 {
+        let frame = Frame::new(n);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z == 0.0 || self.is_delta() {
+            return 0.0;
+        }
+        if wi.z < 0.0 {
+            return match *self {
+                Bsdf::Dielectric { eta, alpha, .. } => rough_transmission(&wo, &wi, eta, alpha).1,
+                _ => 0.0,
+            };
+        }
+
+        let h = (wo + wi).normalize();
+        match *self {
+            Bsdf::Lambert { .. } => wi.z / PI,
+            Bsdf::Microfacet {
+                metallic, alpha, ..
+            } => {
+                let specular = vndf_pdf(&wo, &h, alpha) / (4.0 * utils::dot(&wo, &h));
+                let p = specular_probability(metallic);
+                p * specular + (1.0 - p) * wi.z / PI
+            }
+            Bsdf::Dielectric { eta, alpha, .. } => {
+                let f = fresnel_dielectric(utils::dot(&wo, &h), eta);
+                f * vndf_pdf(&wo, &h, alpha) / (4.0 * utils::dot(&wo, &h))
+            }
+            Bsdf::Conductor { alpha, .. } => vndf_pdf(&wo, &h, alpha) / (4.0 * utils::dot(&wo, &h)),
+        }
+    }
+
+    /// Samples a direction wi for the direction wo around the unit normal n,
+    /// or returns None if the path is absorbed
+    pub fn sample(&self, wo: &Vector, n: &Vector, sampler: &mut Sampler) -> Option<BsdfSample> {
+        let frame = Frame::new(n);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+
+        if self.is_delta() {
+            // choose one of the lobes with the probability of its weight
+            let lobes = self.specular_lobes(wo, n);
+            let total: f64 = lobes.iter().map(|(_, w)| max_component(w)).sum();
+            if total <= 0.0 {
+                return None;
+            }
+            let mut u = sampler.next_f64() * total;
+            for (wi, weight) in lobes.iter() {
+                let p = max_component(weight);
+                if u < p || p == total {
+                    return Some(BsdfSample {
+                        wi: *wi,
+                        weight: *weight * (total / p),
+                        pdf: p / total,
+                        delta: true,
+                    });
+                }
+                u -= p;
+            }
+            return None;
+        }
+
+        let lobe = sampler.next_f64();
+        let u = sampler.next_2d();
+        let wi = match *self {
+            Bsdf::Lambert { albedo } => {
+                // the cosine-weighted density cancels the cosine and PI of the BSDF
+                let wi = cosine_hemisphere(n, u);
+                let pdf = utils::dot(&wi, n) / PI;
+                return (pdf > 0.0).then_some(BsdfSample {
+                    wi,
+                    weight: albedo,
+                    pdf,
+                    delta: false,
+                });
+            }
+            Bsdf::Microfacet {
+                metallic, alpha, ..
+            } => {
+                if lobe < specular_probability(metallic) {
+                    let h = sample_vndf(&wo_local, alpha, u);
+                    frame.to_world(&utils::reflect(&(-wo_local), &h))
+                } else {
+                    cosine_hemisphere(n, u)
+                }
+            }
+            Bsdf::Dielectric { eta, alpha, .. } => {
+                // reflect with the probability of the Fresnel reflectance of the microfacet
+                let h = sample_vndf(&wo_local, alpha, u);
+                let cos = utils::dot(&wo_local, &h);
+                let wi = match refract(&wo_local, &h, eta) {
+                    Some(t) if lobe >= fresnel_dielectric(cos, eta) => t,
+                    _ => utils::reflect(&(-wo_local), &h),
+                };
+
+                // the microfacet may scatter to the other side than its lobe
+                let transmitted = utils::dot(&wi, &h) < 0.0;
+                if transmitted != (wi.z < 0.0) {
+                    return None;
+                }
+                frame.to_world(&wi)
+            }
+            Bsdf::Conductor { alpha, .. } => {
+                let h = sample_vndf(&wo_local, alpha, u);
+                frame.to_world(&utils::reflect(&(-wo_local), &h))
+            }
+        };
+
+        let pdf = self.pdf(wo, &wi, n);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let cos = utils::dot(&wi, n).abs();
+        Some(BsdfSample {
+            wi,
+            weight: self.eval(wo, &wi, n) * (cos / pdf),
+            pdf,
+            delta: false,
+        })
+    }
+
+    /// Returns the discrete directions of a delta BSDF for the direction wo around the unit
+    /// normal n, with the fraction of light scattered into each of them (empty for other BSDFs)
+    pub fn specular_lobes(&self, wo: &Vector, n: &Vector) -> Vec<(Vector, Color)> {
+        if !self.is_delta() {
+            return vec![];
+        }
+        let cos = utils::dot(wo, n);
+        if cos <= 0.0 {
+            return vec![];
+        }
+        let mirror = utils::reflect(&(-wo), n);
+
+        match *self {
+            Bsdf::Dielectric { eta, tint, .. } => {
+                let f = fresnel_dielectric(cos, eta);
+                let mut res = vec![(mirror, gray(f))];
+                if f < 1.0 {
+                    let frame = Frame::new(n);
+                    let z = utils::vector(0.0, 0.0, 1.0);
+                    if let Some(t) = refract(&frame.to_local(wo), &z, eta) {
+                        res.push((frame.to_world(&t), tint * (1.0 - f)));
+                    }
+                }
+                res
+            }
+            Bsdf::Conductor { eta, k, tint, .. } => {
+                vec![(mirror, tint * fresnel_conductor(cos, &eta, &k))]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Returns the GGX alpha of a roughness, or 0 for surfaces that are perfectly smooth
+fn smooth_alpha(roughness: f64) -> f64 {
+    let alpha = roughness * roughness;
+    if alpha < SMOOTH_ALPHA {
+        0.0
+    } else {
+        alpha
+    }
+}
+
+/// Returns a Color with the same value in every channel
+fn gray(v: f64) -> Color {
+    utils::color(v, v, v)
+}
+
+/// Returns the largest channel of a color
+fn max_component(c: &Color) -> f64 {
+    c.r.max(c.g).max(c.b)
+}
+
+/// Returns the microfacet reflection without Fresnel: D * G / (4 cos_o cos_i) (local directions)
+fn microfacet_reflection(wo: &Vector, wi: &Vector, alpha: f64) -> f64 {
+    let h = (wo + wi).normalize();
+    let g = 1.0 / (1.0 + smith_lambda(wo, alpha) + smith_lambda(wi, alpha));
+    ggx_d(&h, alpha) * g / (4.0 * wo.z * wi.z)
+}
+
+/// Returns the value and the density of the rough dielectric transmission from the local
+/// direction wo above the surface to wi below it (Walter et al., "Microfacet Models for
+/// Refraction through Rough Surfaces"). The radiance is not scaled by the squared ratio of the
+/// indices of refraction, which cancels for light that enters and leaves a closed object
+fn rough_transmission(wo: &Vector, wi: &Vector, eta: f64, alpha: f64) -> (f64, f64) {
+    let mut h = (wo + wi * eta).normalize();
+    if h.z < 0.0 {
+        h = -h;
+    }
+    let (cos_o, cos_i) = (utils::dot(wo, &h), utils::dot(wi, &h));
+    if cos_o <= 0.0 || cos_i >= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let f = fresnel_dielectric(cos_o, eta);
+    let denom = (cos_o + eta * cos_i).powi(2);
+    let g = 1.0 / (1.0 + smith_lambda(wo, alpha) + smith_lambda(wi, alpha));
+    let value = (1.0 - f) * ggx_d(&h, alpha) * g * eta * eta * (cos_o * cos_i).abs()
+        / (wo.z * wi.z.abs() * denom);
+    let pdf = (1.0 - f) * vndf_pdf(wo, &h, alpha) * eta * eta * cos_i.abs() / denom;
+    (value, pdf)
+}
+
+/// Refracts the local direction wo (pointing away from the surface) through the unit normal n
+/// into the side with the relative index of refraction eta, or returns None on total
+/// internal reflection
+fn refract(wo: &Vector, n: &Vector, eta: f64) -> Option<Vector> {
+    let cos_i = utils::dot(wo, n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + n * (cos_i / eta - cos_t))
+}
+
+/// Fresnel reflectance of unpolarized light at a dielectric boundary, for the cosine of the
+/// incident angle and the relative index of refraction eta (1 on total internal reflection)
+pub fn fresnel_dielectric(cos: f64, eta: f64) -> f64 {
+    let cos_i = cos.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (rs * rs + rp * rp) / 2.0
+}
+
+/// Fresnel reflectance of unpolarized light on a conductor with the complex index of refraction
+/// eta + i k, for the cosine of the incident angle
+pub fn fresnel_conductor(cos: f64, eta: &Color, k: &Color) -> Color {
+    let channel = |eta: f64, k: f64| {
+        let cos2 = cos.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * cos.clamp(0.0, 1.0) * a;
+        let rs = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        (rs + rp) / 2.0
+    };
+    utils::color(
+        channel(eta.r, k.r),
+        channel(eta.g, k.g),
+        channel(eta.b, k.b),
+    )
+}
+
+/// Returns the reflectance at normal incidence of the metallic/roughness workflow
+fn f0(base: &Color, metallic: f64) -> Color {
+    let dielectric = utils::color(DIELECTRIC_F0, DIELECTRIC_F0, DIELECTRIC_F0);
+    dielectric * (1.0 - metallic) + *base * metallic
+}
+
+/// Schlick's approximation of the Fresnel reflectance
+pub fn fresnel_schlick(f0: &Color, cos: f64) -> Color {
+    let m = (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+    *f0 + (utils::color(1.0, 1.0, 1.0) - *f0) * m
+}
+
+/// GGX (Trowbridge-Reitz) distribution of the local microfacet normal h
+pub fn ggx_d(h: &Vector, alpha: f64) -> f64 {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Smith's Lambda of the GGX distribution for the local direction w
+pub fn smith_lambda(w: &Vector, alpha: f64) -> f64 {
+    let cos2 = w.z * w.z;
+    if cos2 <= 0.0 {
+        return f64::INFINITY;
+    }
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) / 2.0
+}
+
+/// Returns the probability of sampling the specular lobe of the microfacet model
+fn specular_probability(metallic: f64) -> f64 {
+    0.5 + 0.5 * metallic
+}
+
+/// Density of the visible normal h seen from the local direction wo
+fn vndf_pdf(wo: &Vector, h: &Vector, alpha: f64) -> f64 {
+    let g1 = 1.0 / (1.0 + smith_lambda(wo, alpha));
+    g1 * utils::dot(wo, h).max(0.0) * ggx_d(h, alpha) / wo.z
+}
+
+/// Samples a visible microfacet normal seen from the local direction wo
+/// (Heitz, "Sampling the GGX Distribution of Visible Normals")
+fn sample_vndf(wo: &Vector, alpha: f64, u: (f64, f64)) -> Vector {
+    // stretch the view direction to the hemisphere configuration
+    let vh = utils::vector(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+
+    let len2 = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len2 > 0.0 {
+        utils::vector(-vh.y, vh.x, 0.0) / len2.sqrt()
+    } else {
+        utils::vector(1.0, 0.0, 0.0)
+    };
+    let t2 = utils::cross(&vh, &t1);
+
+    // sample a point on the projected half disk
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+    let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+    // unstretch the normal
+    utils::vector(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).normalize()
+}
@@ -0,0 +1,289 @@
+//! Contains the tile grid of a render and checkpoints: the finished tiles and their accumulated
+//! sample sums, which can be saved to a file and used to resume an interrupted render
+//!
+//! A checkpoint file is little-endian binary:
+//! "RTCKPT2\n", then width, height, tile size, samples per pixel, maximum depth and the hash of the scene (u64),
+//! the name of the integrator (its length in bytes as u64, then UTF-8), the number of finished tiles (u64),
+//! then for every finished tile its index (u64) and the RGB sums of its pixels in row-major order (f64)
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::image::ImageError;
+use crate::math::{utils, Color};
+
+/// Magic bytes of a checkpoint file
+const MAGIC: &[u8; 8] = b"RTCKPT2\n";
+
+/// Longest integrator name a checkpoint file may hold
+const MAX_NAME_LEN: usize = 256;
+
+/// Rectangle of pixels x0..x1, y0..y1 rendered as one work item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    /// Returns the number of pixels in the tile
+    pub fn len(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+
+    /// Returns true if the tile has no pixels
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Splits a width x height image into square tiles of a given size, in row-major order
+/// (the tiles on the right and bottom edges may be smaller)
+pub fn tile_grid(width: usize, height: usize, size: usize) -> Vec<Tile> {
+    let size = size.max(1);
+    let mut res = vec![];
+
+    for y0 in (0..height).step_by(size) {
+        for x0 in (0..width).step_by(size) {
+            res.push(Tile {
+                x0,
+                y0,
+                x1: (x0 + size).min(width),
+                y1: (y0 + size).min(height),
+            });
+        }
+    }
+    res
+}
+
+/// State of a tiled render:
+/// width, height: dimensions of the image in pixels
+/// tile_size: side of a tile in pixels
+/// spp: samples per pixel accumulated in every finished tile
+/// max_depth: maximum depth of the light paths
+/// integrator: name of the integrator
+/// scene: hash of the serialized scene and the parameters of the integrator
+/// tiles: sample sums of the finished tiles by tile index
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: usize,
+    pub spp: usize,
+    pub max_depth: usize,
+    pub integrator: String,
+    pub scene: u64,
+    tiles: BTreeMap<usize, Vec<Color>>,
+}
+
+impl Checkpoint {
+    /// Creates a Checkpoint with no finished tiles
+    pub fn new(width: usize, height: usize, tile_size: usize, spp: usize) -> Self {
+        Self {
+            width,
+            height,
+            tile_size: tile_size.max(1),
+            spp,
+            max_depth: 0,
+            integrator: String::new(),
+            scene: 0,
+            tiles: BTreeMap::new(),
+        }
+    }
+
+    /// Sets the settings of the render that do not change the size of the tiles
+    pub fn with_settings(mut self, integrator: &str, max_depth: usize, scene: u64) -> Self {
+        self.integrator = integrator.to_string();
+        self.max_depth = max_depth;
+        self.scene = scene;
+        self
+    }
+
+    /// Returns all tiles of the image
+    pub fn grid(&self) -> Vec<Tile> {
+        tile_grid(self.width, self.height, self.tile_size)
+    }
+
+    /// Returns the indices of the tiles that are not finished yet
+    pub fn pending(&self) -> Vec<usize> {
+        (0..self.grid().len())
+            .filter(|i| !self.tiles.contains_key(i))
+            .collect()
+    }
+
+    /// Returns the number of finished tiles
+    pub fn finished(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Stores the sample sums of a finished tile
+    pub fn insert(&mut self, index: usize, sums: Vec<Color>) {
+        self.tiles.insert(index, sums);
+    }
+
+    /// Returns the sample sums of a finished tile
+    pub fn get(&self, index: usize) -> Option<&[Color]> {
+        self.tiles.get(&index).map(|t| t.as_slice())
+    }
+
+    /// Returns true if the checkpoint belongs to a render with the same settings
+    pub fn matches(&self, other: &Checkpoint) -> bool {
+        (self.width, self.height, self.tile_size, self.spp)
+            == (other.width, other.height, other.tile_size, other.spp)
+            && (&self.integrator, self.max_depth, self.scene)
+                == (&other.integrator, other.max_depth, other.scene)
+    }
+
+    /// Writes the checkpoint into a sink
+    pub fn write<W: Write>(&self, mut sink: W) -> io::Result<()> {
+        sink.write_all(MAGIC)?;
+        for v in [
+            self.width,
+            self.height,
+            self.tile_size,
+            self.spp,
+            self.max_depth,
+        ] {
+            sink.write_all(&(v as u64).to_le_bytes())?;
+        }
+        sink.write_all(&self.scene.to_le_bytes())?;
+        sink.write_all(&(self.integrator.len() as u64).to_le_bytes())?;
+        sink.write_all(self.integrator.as_bytes())?;
+        sink.write_all(&(self.tiles.len() as u64).to_le_bytes())?;
+
+        for (index, sums) in self.tiles.iter() {
+            sink.write_all(&(*index as u64).to_le_bytes())?;
+            for c in sums.iter() {
+                for v in [c.r, c.g, c.b] {
+                    sink.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        sink.flush()
+    }
+
+    /// Reads a checkpoint from a source
+    pub fn read<R: Read>(mut src: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        src.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a checkpoint file"));
+        }
+
+        let width = read_usize(&mut src)?;
+        let height = read_usize(&mut src)?;
+        let tile_size = read_usize(&mut src)?;
+        let spp = read_usize(&mut src)?;
+        let max_depth = read_usize(&mut src)?;
+        let scene = read_u64(&mut src)?;
+        let integrator = read_string(&mut src)?;
+        let count = read_usize(&mut src)?;
+
+        let mut res = Checkpoint::new(width, height, tile_size, spp).with_settings(
+            &integrator,
+            max_depth,
+            scene,
+        );
+        let grid = res.grid();
+        for _ in 0..count {
+            let index = read_usize(&mut src)?;
+            let tile = grid
+                .get(index)
+                .ok_or_else(|| invalid_data("tile index out of range"))?;
+
+            let mut sums = Vec::with_capacity(tile.len());
+            for _ in 0..tile.len() {
+                let r = read_f64(&mut src)?;
+                let g = read_f64(&mut src)?;
+                let b = read_f64(&mut src)?;
+                sums.push(utils::color(r, g, b));
+            }
+            res.tiles.insert(index, sums);
+        }
+        Ok(res)
+    }
+
+    /// Saves the checkpoint into a file. The file is replaced atomically,
+    /// so an interrupted save leaves the previous checkpoint intact
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let write = || -> io::Result<()> {
+            let file = File::create(&tmp)?;
+            let mut sink = BufWriter::new(file);
+            self.write(&mut sink)?;
+            sink.into_inner()?.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        write().map_err(|e| ImageError::from(e).at(path))
+    }
+
+    /// Loads a checkpoint from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| ImageError::from(e).at(path))?;
+        Checkpoint::read(BufReader::new(file)).map_err(|e| ImageError::from(e).at(path))
+    }
+}
+
+/// Returns the 64-bit FNV-1a hash of the JSON form of a value, which does not change
+/// between runs and versions of the compiler
+pub fn hash_serialized<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<u64> {
+    let mut hasher = Fnv1a(0xcbf29ce484222325);
+    serde_json::to_writer(&mut hasher, value)?;
+    Ok(hasher.0)
+}
+
+/// Sink that hashes the bytes written into it
+struct Fnv1a(u64);
+
+impl Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for b in buf {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100000001b3);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u64<R: Read>(src: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    src.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_usize<R: Read>(src: &mut R) -> io::Result<usize> {
+    usize::try_from(read_u64(src)?).map_err(|_| invalid_data("value out of range"))
+}
+
+fn read_string<R: Read>(src: &mut R) -> io::Result<String> {
+    let len = read_usize(src)?;
+    if len > MAX_NAME_LEN {
+        return Err(invalid_data("integrator name too long"));
+    }
+    let mut buf = vec![0u8; len];
+    src.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid_data("integrator name is not UTF-8"))
+}
+
+fn read_f64<R: Read>(src: &mut R) -> io::Result<f64> {
+    let mut buf = [0u8; 8];
+    src.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
@@ -6,6 +6,13 @@ use std::fmt::Debug;
 use std::rc::Rc;
 use std::{cell::RefCell, ops};
 
+use serde::{Deserialize, Serialize};
+
+use super::bsdf::{Bsdf, BsdfKind};
+use super::density::Density;
+use super::medium::Medium;
+use super::spectrum::Dispersion;
+use super::subsurface::Subsurface;
 use crate::{
     math::{utils, Color, Matrix, TUnit, Transformation, Vector},
     transform,
@@ -17,13 +24,32 @@ use crate::{
 /// specular: Specular lighting coefficient
 /// shininess: Represents the shininess of the Light's reflection on the surface
 /// color: Reflected Spectrum of light form object's surface (aka Color)
-#[derive(Debug, Clone, PartialEq)]
+/// emission: Light emitted by the surface, which makes the object a light source
+/// bsdf: Reflection model (Phong by default, or a physically based one)
+/// medium: Medium filling the (closed) object, whose surface then only bounds the volume
+/// and is not drawn
+/// density: Density (in object space) scaling the medium, which makes the volume heterogeneous
+/// subsurface: Light scattering under the surface, which replaces the diffuse reflection
+/// dispersion: Wavelength-dependent index of refraction of a dielectric in the spectral mode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Material {
     pub color: Color,
     pub ambient: f64,
     pub diffuse: f64,
     pub specular: f64,
     pub shininess: f64, 
+    #[serde(default)]
+    pub emission: Color,
+    #[serde(default)]
+    pub bsdf: BsdfKind,
+    #[serde(default)]
+    pub medium: Option<Medium>,
+    #[serde(default)]
+    pub density: Option<Density>,
+    #[serde(default)]
+    pub subsurface: Option<Subsurface>,
+    #[serde(default)]
+    pub dispersion: Option<Dispersion>,
     pattern: Pattern,
 }
 
@@ -35,6 +61,12 @@ impl Material {
             diffuse,
             specular, 
             shininess,
+            emission: Color::black(),
+            bsdf: BsdfKind::Phong,
+            medium: None,
+            density: None,
+            subsurface: None,
+            dispersion: None,
             pattern: Pattern::None,
         }
     }
@@ -51,6 +83,19 @@ impl Material {
     pub fn get_pattern(&mut self) -> &mut Pattern {
         return &mut self.pattern;
     }
+
+    /// Returns the base color of the Material at the point p (its pattern, or its color)
+    pub fn albedo(&self, p: &Vector) -> Color {
+        match &self.pattern {
+            Pattern::None => self.color,
+            _ => self.pattern.get(p).expect("Pattern is None"),
+        }
+    }
+
+    /// Returns true if the surface emits light
+    pub fn is_emissive(&self) -> bool {
+        self.emission.r > 0.0 || self.emission.g > 0.0 || self.emission.b > 0.0
+    }
 }
 
 impl Default for Material {
@@ -61,6 +106,12 @@ impl Default for Material {
             diffuse: 0.9,
             specular: 0.9,
             shininess: 200.0,
+            emission: Color::black(),
+            bsdf: BsdfKind::Phong,
+            medium: None,
+            density: None,
+            subsurface: None,
+            dispersion: None,
             pattern: Pattern::None
         }
     }
@@ -70,10 +121,12 @@ impl Default for Material {
 /// which is a working horse of the Ray Tracing Algorithm
 /// origin: world-coordinate position of the ray
 /// direction: direction of the ray
+/// wavelength: wavelength (in nm) carried by the ray in the spectral mode
 #[derive(Debug, PartialEq, Clone)]
 pub struct Ray {
     pub origin: Vector,
     pub direction: Vector,
+    pub wavelength: Option<f64>,
 }
 
 impl Ray {
@@ -82,9 +135,16 @@ impl Ray {
         Self {
             origin,
             direction,
+            wavelength: None,
         }
     }
 
+    /// Returns the ray carrying a given wavelength
+    pub fn with_wavelength(mut self, wavelength: Option<f64>) -> Self {
+        self.wavelength = wavelength;
+        self
+    }
+
     /// Get position along the Ray's direction at the given t-value
     pub fn pos(&self, t: f64) -> Vector {
         return self.origin + self.direction * t;
@@ -105,18 +165,22 @@ impl ops::Mul<&Ray> for &Matrix {
 /// obj: the object of interest (which was intersected),
 /// p: the point of intersection on the object,
 /// over_p: moved p in the dir of normal to solve the acne problem
+/// under_p: moved p against the normal, the origin of refracted rays
 /// e: eye vector at the point,
 /// n: normal at the point,
 /// inside: indicates whether the intersection took place inside the object,
+/// wavelength: wavelength (in nm) of the ray in the spectral mode,
 /// ALERT: Computations takes ownership over Intersection's data
 pub struct Computations {
     pub t: f64,
     pub obj: RAIIDrawable,
     pub p: Vector,
     pub over_p: Vector,
+    pub under_p: Vector,
     pub e: Vector,
     pub n: Vector,
     pub inside: bool,
+    pub wavelength: Option<f64>,
 }
 
 impl Computations {
@@ -136,15 +200,18 @@ impl Computations {
 
         // calculate overpoint
         let over_p = p + crate::math::utils::EPSILON * n;
+        let under_p = p - crate::math::utils::EPSILON * n;
 
         Self {
             t: i.t,
             obj: i.obj,
             p,
             over_p,
+            under_p,
             e,
             n,
             inside,
+            wavelength: r.wavelength,
         }
     }
 }
@@ -354,12 +421,47 @@ pub trait Drawable: Debug {
     /// obj_r: reference to an object_coordinates Ray which Is are seeked (&Ray)
     fn local_intersect(&self, _obj_r: &Ray) -> Tvalues;
 
+    /// (World Space) Returns a point sampled on the surface of the Drawable object (Shape),
+    /// or None if the surface cannot be sampled (see local_sample)
+    /// u: uniformly distributed numbers in [0, 1)
+    fn sample_surface(&self, u: (f64, f64)) -> Option<SurfaceSample> {
+        let s = self.local_sample(u)?;
+        let tm = self.get_transform().matrix();
+        let itm = self
+            .get_transform()
+            .inverse()
+            .expect("Could not invert Transformation matrix in Shape::Drawable");
+
+        // transform the normal like in normal(), the area scales by det(M) * |M^-T n| (Nanson's formula)
+        let mut world_n = itm.transpose() * s.n;
+        world_n.w = 0.0;
+        let scale = tm.fixed_view::<3, 3>(0, 0).determinant().abs() * world_n.magnitude();
+
+        Some(SurfaceSample {
+            p: tm * s.p,
+            n: world_n.normalize(),
+            pdf: s.pdf / scale,
+        })
+    }
+
+    /// (Object Space) Returns a point sampled on the surface of the Drawable object (Shape),
+    /// or None (the default) if the surface cannot be sampled, e.g. it is infinite.
+    /// Whether the result is None must not depend on u
+    fn local_sample(&self, _u: (f64, f64)) -> Option<SurfaceSample> {
+        None
+    }
+
     /// Gets a mutable reference to the Shape field of the object
     fn get_shape_mut(&mut self) -> &mut Shape;
     
     /// Gets a reference to the Shape field of the object
     fn get_shape(&self) -> &Shape;
 
+    /// Returns a serializable copy of the object, or None if the object cannot be serialized
+    fn to_kind(&self) -> Option<super::shapes::ShapeKind> {
+        None
+    }
+
     /// Wraps Drawable object into RAIIDrawable
     fn wrap(self) -> RAIIDrawable
     where
@@ -369,11 +471,22 @@ pub trait Drawable: Debug {
     }
 }
 
+/// Point sampled on the surface of a Drawable:
+/// p: position of the point
+/// n: unit normal at the point
+/// pdf: probability density of the point per unit of surface area
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceSample {
+    pub p: Vector,
+    pub n: Vector,
+    pub pdf: f64,
+}
+
 /// RAII Drawable objects
 pub type RAIIDrawable = Rc<RefCell<dyn Drawable>>;
 
 /// An abstract data structure that represents a shape drawable onto a Canvas
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Shape {
     /// Transformation object
     pub t: Transformation,
@@ -427,6 +540,7 @@ impl Drawable for Shape {
 /// Point Light Source
 /// pos: world-coordinates position of the point light source
 /// int: intensity of the light source (measured in [Color])
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PointLight {
     pub pos: Vector,
     int: Color,
@@ -437,6 +551,11 @@ impl PointLight {
     pub fn new(pos: Vector, int: Color) -> Self {
         Self { pos, int }
     }
+
+    /// Returns the intensity of the light source
+    pub fn intensity(&self) -> Color {
+        self.int
+    }
 }
 
 impl Default for PointLight {
@@ -456,71 +575,103 @@ impl PointLight {
     /// n - Normal to the object at the world pixel;
     /// shadowed - Switch whether the point is shadowed
     pub fn shade(&self, m: &Material, p: &Vector, e: &Vector, n: &Vector, shadowed: bool) -> Color {
-        // combine the surface color or pattern with the light's intensity
-        let eff_col: Color = match &m.pattern {  
-           Pattern::None => self.int * m.color,
-            _ => {
-                // println!("pattern option");
-                self.int * m.pattern.get(p).expect("Pattern is None")
-            },
-        };  
-
-
         // find the direction to the light source
         let l = (self.pos - p).normalize();
+        phong(&self.int, &l, m, p, e, n, shadowed)
+    }
+
+    /// Wraps PointLight in Box<PointLight>
+    pub fn wrap_box(self) -> Box<Self> {
+        Box::new(self)
+    }
+}
 
-        // compute the ambient contribution
-        let ambient = eff_col * m.ambient;
+/// Directional Light Source, infinitely far away (e.g. the sun)
+/// dir: unit world-coordinates direction towards the light source
+/// int: intensity of the light source (measured in [Color]), the same at every point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectionalLight {
+    pub dir: Vector,
+    int: Color,
+}
 
-        // if point is shadowed, only the ambient component is visible
-        if shadowed {
-            return ambient;
+impl DirectionalLight {
+    /// Creates a new DirectionalLight shining from the direction dir
+    pub fn new(dir: Vector, int: Color) -> Self {
+        Self {
+            dir: dir.normalize(),
+            int,
         }
+    }
+
+    /// Returns the intensity of the light source
+    pub fn intensity(&self) -> Color {
+        self.int
+    }
+
+    /// Shades a point like PointLight::shade, with the light arriving from the direction of the light source
+    pub fn shade(&self, m: &Material, p: &Vector, e: &Vector, n: &Vector, shadowed: bool) -> Color {
+        phong(&self.int, &self.dir, m, p, e, n, shadowed)
+    }
+}
+
+/// Shades a point lit with the intensity int from the direction l (see PointLight::shade)
+fn phong(int: &Color, l: &Vector, m: &Material, p: &Vector, e: &Vector, n: &Vector, shadowed: bool) -> Color {
+    // combine the surface color or pattern with the light's intensity
+    let eff_col: Color = *int * m.albedo(p);
+
+    // compute the ambient contribution
+    let ambient = eff_col * m.ambient;
+
+    // if point is shadowed, only the ambient component is visible
+    if shadowed {
+        return ambient;
+    }
+
+    // physically based materials are lit with the irradiance PI * intensity * cos
+    if m.bsdf != BsdfKind::Phong {
+        let ldn = utils::dot(l, n).max(0.0);
+        let f = Bsdf::new(m, p).eval(e, l, n);
+        return ambient + f * *int * (std::f64::consts::PI * ldn);
+    }
+
+    let diffuse: Color;
+    let specular: Color;
 
-        let diffuse: Color;
-        let specular: Color;
+    // ldn represents the cosine of the angle between the
+    // light vector and the normal vector. A negative number means
+    // the light is on the other side of the surface
+    let ldn = utils::dot(l, n); // light_dot_normal
+    if ldn < 0.0 {
+        diffuse = Color::black();
+        specular = Color::black();
+    } else {
+        // compute the diffusion contribution
+        diffuse = eff_col * m.diffuse * ldn;
 
-        // ldn represents the cosine of the angle between the
-        // light vector and the normal vector. A negative number means
-        // the light is on the other side of the surface
-        let ldn = utils::dot(&l, n); // light_dot_normal
-        if ldn < 0.0 {
-            diffuse = Color::black();
+        // reflected light vector
+        let r = utils::reflect(&(-l), n);
+
+        // rde represents the cosine of the angle between the
+        // reflection vector and the eye vector. A negative number means the
+        // light reflects away from the eye
+        let rde = utils::dot(&r, e); // reflect_dot_eye
+        if rde <= 0.0 {
             specular = Color::black();
         } else {
-            // compute the diffusion contribution
-            diffuse = eff_col * m.diffuse * ldn;
-
-            // reflected light vector
-            let r = utils::reflect(&(-l), n);
-
-            // rde represents the cosine of the angle between the
-            // reflection vector and the eye vector. A negative number means the
-            // light reflects away from the eye
-            let rde = utils::dot(&r, e); // reflect_dot_eye
-            if rde <= 0.0 {
-                specular = Color::black();
-            } else {
-                // compute the specular contribution
-                let factor = rde.powf(m.shininess);
-                specular = self.int * m.specular * factor;
-            }
+            // compute the specular contribution
+            let factor = rde.powf(m.shininess);
+            specular = *int * m.specular * factor;
         }
-
-        let res = ambient + specular + diffuse;
-        return res;
     }
 
-    /// Wraps PointLight in Box<PointLight>
-    pub fn wrap_box(self) -> Box<Self> {
-        Box::new(self)
-    }
+    ambient + specular + diffuse
 }
 
 // transformation set for pattern 
 type PatternTransform = (Transformation, Rc<Matrix>);
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Pattern {
     StripePattern(Color, Color, PatternTransform),
     TestPattern(PatternTransform),
@@ -0,0 +1,331 @@
+//! Contains the densities of heterogeneous volumes: 3D grids of values sampled trilinearly,
+//! and procedural noise. Densities are defined in the object space of the volume, where a grid
+//! spans the cube [-1, 1]^3 (the bounds of the unit Sphere)
+//!
+//! Grids are stored in a raw binary format: the dimensions nx, ny and nz as little-endian u32,
+//! followed by nx * ny * nz little-endian f32 values, x varying fastest and z slowest.
+//! A grid loaded from a file is serialized as the path of the file, other grids as their values
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::math::Vector;
+
+/// Largest number of values of a grid read from a file
+pub const MAX_GRID_VALUES: usize = 1 << 28;
+
+/// Density of a heterogeneous volume, which scales the coefficients of its Medium
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Density {
+    Grid(Rc<DensityGrid>),
+    Noise(Noise),
+}
+
+impl Density {
+    /// Returns the density at the point p (in object space)
+    pub fn at(&self, p: &Vector) -> f64 {
+        match self {
+            Density::Grid(g) => g.at(p),
+            Density::Noise(n) => n.at(p),
+        }
+    }
+
+    /// Returns the largest density
+    pub fn max(&self) -> f64 {
+        match self {
+            Density::Grid(g) => g.max(),
+            Density::Noise(_) => 1.0,
+        }
+    }
+}
+
+/// Density given by values at the points of a regular grid spanning the cube [-1, 1]^3
+/// (the values lie at the centers of the cells), zero outside of the cube.
+/// Grids are equal when their values are, wherever they were loaded from
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    values: Vec<f64>,
+
+    /// file the grid was loaded from
+    path: Option<PathBuf>,
+}
+
+impl DensityGrid {
+    /// Creates a grid with the dimensions (nx, ny, nz) from its values, x varying fastest.
+    /// Returns None if the number of values does not match the dimensions,
+    /// or a value is negative or not finite
+    pub fn new(nx: usize, ny: usize, nz: usize, values: Vec<f64>) -> Option<Self> {
+        let valid = values.iter().all(|v| v.is_finite() && *v >= 0.0);
+        if nx == 0 || ny == 0 || nz == 0 || !valid {
+            return None;
+        }
+        if nx.checked_mul(ny)?.checked_mul(nz)? != values.len() {
+            return None;
+        }
+        Some(Self {
+            nx,
+            ny,
+            nz,
+            values,
+            path: None,
+        })
+    }
+
+    /// Returns the dimensions of the grid
+    pub fn dims(&self) -> (usize, usize, usize) {
+        (self.nx, self.ny, self.nz)
+    }
+
+    /// Returns the file the grid was loaded from
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the value at the grid point (x, y, z)
+    pub fn value(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.values[(z * self.ny + y) * self.nx + x]
+    }
+
+    /// Returns the largest value of the grid
+    pub fn max(&self) -> f64 {
+        self.values.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// Returns the density at the point p (in object space), interpolated trilinearly
+    pub fn at(&self, p: &Vector) -> f64 {
+        if p.x.abs() > 1.0 || p.y.abs() > 1.0 || p.z.abs() > 1.0 {
+            return 0.0;
+        }
+
+        // continuous grid coordinates, with the grid points at the centers of the cells
+        let coord = |v: f64, n: usize| -> (usize, usize, f64) {
+            let c = ((v + 1.0) / 2.0 * n as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+            let i = (c.floor() as usize).min(n - 1);
+            (i, (i + 1).min(n - 1), c - i as f64)
+        };
+        let (x0, x1, fx) = coord(p.x, self.nx);
+        let (y0, y1, fy) = coord(p.y, self.ny);
+        let (z0, z1, fz) = coord(p.z, self.nz);
+
+        let lerp = |a: f64, b: f64, f: f64| a + (b - a) * f;
+        let plane = |z: usize| {
+            lerp(
+                lerp(self.value(x0, y0, z), self.value(x1, y0, z), fx),
+                lerp(self.value(x0, y1, z), self.value(x1, y1, z), fx),
+                fy,
+            )
+        };
+        lerp(plane(z0), plane(z1), fz)
+    }
+
+    /// Reads a grid in the raw binary format
+    pub fn read<R: Read>(mut src: R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut header = [0u8; 12];
+        src.read_exact(&mut header)?;
+        let dim = |i: usize| {
+            let bytes = [header[i], header[i + 1], header[i + 2], header[i + 3]];
+            u32::from_le_bytes(bytes) as usize
+        };
+        let (nx, ny, nz) = (dim(0), dim(4), dim(8));
+        let len = nx
+            .checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .filter(|n| *n > 0 && *n <= MAX_GRID_VALUES)
+            .ok_or_else(|| invalid("invalid density grid dimensions"))?;
+
+        let mut data = vec![0u8; len * 4];
+        src.read_exact(&mut data)?;
+        let values = data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+            .collect();
+
+        Self::new(nx, ny, nz, values)
+            .ok_or_else(|| invalid("density grid values must be finite and not negative"))
+    }
+
+    /// Writes the grid in the raw binary format
+    pub fn write<W: Write>(&self, mut sink: W) -> io::Result<()> {
+        for n in [self.nx, self.ny, self.nz] {
+            let n = u32::try_from(n).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "density grid is too large")
+            })?;
+            sink.write_all(&n.to_le_bytes())?;
+        }
+        for v in self.values.iter() {
+            sink.write_all(&(*v as f32).to_le_bytes())?;
+        }
+        sink.flush()
+    }
+
+    /// Loads a grid from a file in the raw binary format
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut grid = Self::read(BufReader::new(File::open(path)?))?;
+        grid.path = Some(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+        Ok(grid)
+    }
+
+    /// Saves the grid into a file in the raw binary format
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl PartialEq for DensityGrid {
+    fn eq(&self, other: &Self) -> bool {
+        self.dims() == other.dims() && self.values == other.values
+    }
+}
+
+/// Serializable form of a DensityGrid that is written
+#[derive(Serialize)]
+#[serde(untagged)]
+enum GridRef<'a> {
+    File {
+        path: &'a Path,
+    },
+    Values {
+        nx: usize,
+        ny: usize,
+        nz: usize,
+        values: &'a [f64],
+    },
+}
+
+/// Serializable form of a DensityGrid that is read
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GridDesc {
+    File {
+        path: PathBuf,
+    },
+    Values {
+        nx: usize,
+        ny: usize,
+        nz: usize,
+        values: Vec<f64>,
+    },
+}
+
+impl Serialize for DensityGrid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.path {
+            Some(path) => GridRef::File { path },
+            None => GridRef::Values {
+                nx: self.nx,
+                ny: self.ny,
+                nz: self.nz,
+                values: &self.values,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DensityGrid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match GridDesc::deserialize(deserializer)? {
+            GridDesc::File { path } => DensityGrid::load(&path)
+                .map_err(|e| de::Error::custom(format!("{}: {}", path.display(), e))),
+            GridDesc::Values { nx, ny, nz, values } => DensityGrid::new(nx, ny, nz, values)
+                .ok_or_else(|| de::Error::custom("invalid density grid")),
+        }
+    }
+}
+
+/// Density given by fractal Perlin noise, in [0, 1]:
+/// frequency: number of noise features per unit of object space
+/// octaves: number of summed layers of noise, each with twice the frequency and half the
+/// amplitude of the previous one
+/// seed: selects a different noise
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Noise {
+    pub frequency: f64,
+    pub octaves: usize,
+    pub seed: u32,
+}
+
+impl Noise {
+    /// Creates a Noise
+    pub fn new(frequency: f64, octaves: usize, seed: u32) -> Self {
+        Self {
+            frequency,
+            octaves,
+            seed,
+        }
+    }
+
+    /// Returns the density at the point p (in object space)
+    pub fn at(&self, p: &Vector) -> f64 {
+        let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+        let mut f = self.frequency;
+        for octave in 0..self.octaves.max(1) {
+            let seed = self.seed.wrapping_add(octave as u32);
+            sum += amplitude * perlin(p.x * f, p.y * f, p.z * f, seed);
+            total += amplitude;
+            amplitude *= 0.5;
+            f *= 2.0;
+        }
+        (0.5 + sum / total).clamp(0.0, 1.0)
+    }
+}
+
+/// Returns 3D Perlin gradient noise (in about [-1, 1]), zero at the points of the integer lattice
+pub fn perlin(x: f64, y: f64, z: f64, seed: u32) -> f64 {
+    let (xf, yf, zf) = (x.floor(), y.floor(), z.floor());
+    let (dx, dy, dz) = (x - xf, y - yf, z - zf);
+    let (xi, yi, zi) = (xf as i64, yf as i64, zf as i64);
+
+    // gradient at a corner of the cell, dotted with the offset from the corner
+    let corner = |cx: i64, cy: i64, cz: i64| {
+        let h = hash(xi + cx, yi + cy, zi + cz, seed);
+        let (ox, oy, oz) = (dx - cx as f64, dy - cy as f64, dz - cz as f64);
+        // one of the 12 directions towards the edges of a cube
+        match h % 12 {
+            0 => ox + oy,
+            1 => -ox + oy,
+            2 => ox - oy,
+            3 => -ox - oy,
+            4 => ox + oz,
+            5 => -ox + oz,
+            6 => ox - oz,
+            7 => -ox - oz,
+            8 => oy + oz,
+            9 => -oy + oz,
+            10 => oy - oz,
+            _ => -oy - oz,
+        }
+    };
+
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), u);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), u);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), u);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), u);
+    lerp(lerp(x00, x10, v), lerp(x01, x11, v), w)
+}
+
+/// Hashes a point of the integer lattice
+fn hash(x: i64, y: i64, z: i64, seed: u32) -> u32 {
+    let mut h = (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+        ^ (z as u64).wrapping_mul(0x1656_67b1_9e37_79f9)
+        ^ (seed as u64).wrapping_mul(0x27d4_eb2f_1656_67c5);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h as u32
+}
@@ -0,0 +1,274 @@
+//! Contains the environment light of a World: the radiance arriving from infinitely far away
+//! along the rays that hit nothing, here given by an equirectangular HDR image
+//!
+//! Directions are mapped to the image like the equirectangular Camera projection with the
+//! identity view: the center of the image looks along -z, the left edge along +x,
+//! and the top row along +y
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::image::ImageError;
+use super::sky::Sky;
+use super::tonemap::luminance;
+use super::Canvas;
+use crate::math::{utils, Color, Vector};
+
+/// Light arriving from infinitely far away in every direction
+pub trait Environment: fmt::Debug {
+    /// Returns the radiance arriving from the direction dir (the direction of a ray leaving
+    /// the scene)
+    fn radiance(&self, dir: &Vector) -> Color;
+
+    /// Samples a direction from the uniform random numbers u. Returns the direction,
+    /// its radiance divided by the density, and the density (per solid angle)
+    fn sample(&self, u: (f64, f64)) -> (Vector, Color, f64);
+
+    /// Returns the density (per solid angle) with which sample() chooses dir
+    fn pdf(&self, dir: &Vector) -> f64;
+
+    /// Returns the description the environment is saved as, None if it cannot be saved
+    fn desc(&self) -> Option<EnvironmentDesc>;
+}
+
+/// Serializable form of an Environment:
+/// Map: an EnvironmentMap loaded from the image at path
+/// Sky: a Sky with the sun in the direction sun
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EnvironmentDesc {
+    Map {
+        path: PathBuf,
+        intensity: f64,
+    },
+    Sky {
+        sun: Vector,
+        turbidity: f64,
+        intensity: f64,
+    },
+}
+
+impl EnvironmentDesc {
+    /// Creates the Environment of the description, loading the image of a map
+    pub fn build(&self) -> Result<Box<dyn Environment>, ImageError> {
+        match self {
+            EnvironmentDesc::Map { path, intensity } => {
+                let mut env = EnvironmentMap::load(path)?;
+                env.intensity = *intensity;
+                Ok(Box::new(env))
+            }
+            EnvironmentDesc::Sky {
+                sun,
+                turbidity,
+                intensity,
+            } => {
+                let mut sky = Sky::new(*sun, *turbidity);
+                sky.intensity = *intensity;
+                Ok(Box::new(sky))
+            }
+        }
+    }
+}
+
+/// Environment given by an equirectangular image (longitude along the width, latitude
+/// along the height). Directions are importance-sampled in proportion to the luminance
+/// of the pixels, weighted by the solid angle they cover:
+/// intensity: scale of the radiance of the image
+#[derive(Debug, Clone)]
+pub struct EnvironmentMap {
+    path: Option<PathBuf>,
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    pub intensity: f64,
+
+    // cumulative distribution of the rows, and of the pixels in every row
+    marginal: Vec<f64>,
+    conditional: Vec<Vec<f64>>,
+    total: f64,
+}
+
+impl EnvironmentMap {
+    /// Creates an environment map from the pixels of a Canvas
+    pub fn new(cv: &Canvas) -> Self {
+        let (width, height) = (cv.width.max(1), cv.height.max(1));
+        let pixels: Vec<Color> = if cv.width == 0 || cv.height == 0 {
+            vec![Color::black()]
+        } else {
+            (0..height)
+                .flat_map(|y| cv.row(y).iter().copied())
+                .collect()
+        };
+
+        let weights: Vec<f64> = (0..width * height)
+            .map(|i| {
+                let sin = row_sin(i / width, height);
+                luminance(&pixels[i]).max(0.0) * sin
+            })
+            .collect();
+
+        // a black image is sampled by solid angle
+        let sum: f64 = weights.iter().sum();
+        let weights = if sum > 0.0 {
+            weights
+        } else {
+            (0..width * height)
+                .map(|i| row_sin(i / width, height))
+                .collect()
+        };
+
+        let mut conditional = Vec::with_capacity(height);
+        let mut rows = Vec::with_capacity(height);
+        for y in 0..height {
+            let (cdf, row) = cdf(&weights[y * width..(y + 1) * width]);
+            conditional.push(cdf);
+            rows.push(row);
+        }
+        let (marginal, total) = cdf(&rows);
+
+        Self {
+            path: None,
+            width,
+            height,
+            pixels,
+            intensity: 1.0,
+            marginal,
+            conditional,
+            total,
+        }
+    }
+
+    /// Loads an environment map from a linear float image (.pfm or .hdr)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        let path = path.as_ref();
+        let mut env = Self::new(&Canvas::read_image(path)?);
+        env.path = Some(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+        Ok(env)
+    }
+
+    /// Returns the image file the map was loaded from (None if it was created from a Canvas)
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the pixel covering the point (u, v) of the image in [0, 1]^2
+    fn pixel(&self, u: f64, v: f64) -> (usize, usize) {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    /// Returns the density of the point (u, v) of the image in [0, 1]^2
+    fn pdf_uv(&self, u: f64, v: f64) -> f64 {
+        if self.total <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = self.pixel(u, v);
+        let row = &self.conditional[y];
+        let w = if x == 0 { row[0] } else { row[x] - row[x - 1] };
+        w * (self.width * self.height) as f64 / self.total
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, dir: &Vector) -> Color {
+        let (u, v) = direction_to_uv(dir);
+        let (x, y) = self.pixel(u, v);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    fn sample(&self, u: (f64, f64)) -> (Vector, Color, f64) {
+        let (y, dv) = sample_cdf(&self.marginal, u.1);
+        let (x, du) = sample_cdf(&self.conditional[y], u.0);
+        let (u, v) = (
+            (x as f64 + du) / self.width as f64,
+            (y as f64 + dv) / self.height as f64,
+        );
+
+        let dir = uv_to_direction(u, v);
+        let sin = (PI * v).sin();
+        if sin <= 0.0 {
+            return (dir, Color::black(), 0.0);
+        }
+
+        // the image covers 2 PI^2 sin(theta) of solid angle per unit area
+        let pdf = self.pdf_uv(u, v) / (2.0 * PI * PI * sin);
+        if pdf <= 0.0 {
+            return (dir, Color::black(), 0.0);
+        }
+        let li = self.pixels[y * self.width + x] * self.intensity;
+        (dir, li * (1.0 / pdf), pdf)
+    }
+
+    fn pdf(&self, dir: &Vector) -> f64 {
+        let (u, v) = direction_to_uv(dir);
+        let sin = (PI * v).sin();
+        if sin <= 0.0 {
+            return 0.0;
+        }
+        self.pdf_uv(u, v) / (2.0 * PI * PI * sin)
+    }
+
+    fn desc(&self) -> Option<EnvironmentDesc> {
+        self.path.as_ref().map(|path| EnvironmentDesc::Map {
+            path: path.clone(),
+            intensity: self.intensity,
+        })
+    }
+}
+
+/// Returns the point of an equirectangular image in [0, 1]^2 that a unit direction maps to
+pub fn direction_to_uv(dir: &Vector) -> (f64, f64) {
+    let lon = dir.x.atan2(-dir.z);
+    let u = (PI - lon) / (2.0 * PI);
+    let v = dir.y.clamp(-1.0, 1.0).acos() / PI;
+    (u.clamp(0.0, 1.0), v)
+}
+
+/// Returns the unit direction of a point of an equirectangular image in [0, 1]^2
+pub fn uv_to_direction(u: f64, v: f64) -> Vector {
+    let lon = PI - 2.0 * PI * u;
+    let theta = PI * v;
+    utils::vector(
+        theta.sin() * lon.sin(),
+        theta.cos(),
+        -theta.sin() * lon.cos(),
+    )
+}
+
+/// Returns sin(theta) at the center of a row of an image with a given height
+fn row_sin(y: usize, height: usize) -> f64 {
+    (PI * (y as f64 + 0.5) / height as f64).sin()
+}
+
+/// Returns the running sums of the weights and their total
+fn cdf(weights: &[f64]) -> (Vec<f64>, f64) {
+    let mut sum = 0.0;
+    let cdf = weights
+        .iter()
+        .map(|w| {
+            sum += w;
+            sum
+        })
+        .collect();
+    (cdf, sum)
+}
+
+/// Picks an entry of a cumulative distribution with the uniform random number u.
+/// Returns its index and the position of u inside the entry in [0, 1)
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let total = *cdf.last().unwrap_or(&0.0);
+    if total <= 0.0 {
+        let i = ((u * cdf.len() as f64) as usize).min(cdf.len().saturating_sub(1));
+        return (i, 0.5);
+    }
+
+    let target = u * total;
+    let i = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+    let lo = if i == 0 { 0.0 } else { cdf[i - 1] };
+    let w = cdf[i] - lo;
+    let d = if w > 0.0 { (target - lo) / w } else { 0.5 };
+    (i, d.clamp(0.0, 1.0 - f64::EPSILON))
+}
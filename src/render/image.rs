@@ -0,0 +1,1132 @@
+//! Contains encoders that move the pixels of a Canvas into image files.
+//! Every encoder works row by row on top of any std::io::Write sink,
+//! so the whole image never has to be kept in memory as text
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use super::Canvas;
+use crate::math::Color;
+
+/// Error returned by Canvas writers and readers
+#[derive(Debug)]
+pub enum ImageError {
+    /// The sink (or the source) failed
+    Io(io::Error),
+
+    /// The file at a given path could not be created, written or read
+    File { path: PathBuf, source: io::Error },
+
+    /// The image format cannot be chosen from the extension of a given path
+    UnsupportedFormat(PathBuf),
+}
+
+impl ImageError {
+    /// Attaches a path to an I/O error, so that it is known which file failed
+    pub fn at(self, path: &Path) -> Self {
+        match self {
+            ImageError::Io(source) => ImageError::File {
+                path: path.to_path_buf(),
+                source,
+            },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "image I/O failed: {}", e),
+            ImageError::File { path, source } => write!(f, "{}: {}", path.display(), source),
+            ImageError::UnsupportedFormat(path) => {
+                write!(f, "{}: unsupported image format", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Io(e) => Some(e),
+            ImageError::File { source, .. } => Some(source),
+            ImageError::UnsupportedFormat(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+/// Maximum length of a line in a plain (P3) PPM file, as recommended by the PPM specification
+pub const PPM_LINE_LIMIT: usize = 70;
+
+/// Flavor of the PPM file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpmFormat {
+    /// P3: samples are written as ASCII numbers, one image row per line.
+    /// wrap: break lines that would exceed PPM_LINE_LIMIT characters
+    Plain { wrap: bool },
+
+    /// P6: samples are written as raw bytes (8 bits per channel)
+    Raw,
+}
+
+impl Default for PpmFormat {
+    /// P3 with lines wrapped at PPM_LINE_LIMIT characters
+    fn default() -> Self {
+        PpmFormat::Plain { wrap: true }
+    }
+}
+
+/// Streaming PPM encoder: the header is written on creation, and then rows are
+/// written one at a time (top to bottom) directly into the sink.
+pub struct PpmWriter<W: Write> {
+    sink: W,
+    format: PpmFormat,
+    width: usize,
+    rows_left: usize,
+
+    /// reusable buffer for a single encoded row
+    buf: Vec<u8>,
+}
+
+impl<W: Write> PpmWriter<W> {
+    /// Creates a new PpmWriter and writes the PPM header into the sink
+    pub fn new(mut sink: W, width: usize, height: usize, format: PpmFormat) -> io::Result<Self> {
+        let magic = match format {
+            PpmFormat::Plain { .. } => "P3",
+            PpmFormat::Raw => "P6",
+        };
+        write!(sink, "{}\n{} {}\n255\n", magic, width, height)?;
+
+        Ok(Self {
+            sink,
+            format,
+            width,
+            rows_left: height,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Encodes a single row of pixels and writes it into the sink
+    pub fn write_row(&mut self, row: &[Color]) -> io::Result<()> {
+        check_row("PpmWriter", row.len(), self.width, self.rows_left)?;
+
+        self.buf.clear();
+        match self.format {
+            PpmFormat::Raw => {
+                for px in row {
+                    self.buf.extend_from_slice(&px.to_rgb8());
+                }
+            }
+            PpmFormat::Plain { wrap } => {
+                let mut line_len = 0;
+                for sample in row.iter().flat_map(|px| px.to_rgb8()) {
+                    let token = sample.to_string();
+
+                    // start a new line if the token does not fit into the current one
+                    if line_len > 0 {
+                        if wrap && line_len + 1 + token.len() > PPM_LINE_LIMIT {
+                            self.buf.push(b'\n');
+                            line_len = 0;
+                        } else {
+                            self.buf.push(b' ');
+                            line_len += 1;
+                        }
+                    }
+
+                    self.buf.extend_from_slice(token.as_bytes());
+                    line_len += token.len();
+                }
+                self.buf.push(b'\n');
+            }
+        }
+
+        self.rows_left -= 1;
+        self.sink.write_all(&self.buf)
+    }
+
+    /// Flushes the sink and returns it back. Fails if some rows were not written
+    pub fn finish(mut self) -> io::Result<W> {
+        check_finished("PpmWriter", self.rows_left)?;
+
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+/// Image file formats that a Canvas can be written to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Ppm(PpmFormat),
+    Png(PngDepth),
+
+    /// Portable Float Map: linear 32-bit float samples
+    Pfm,
+
+    /// Radiance HDR: linear samples in the shared-exponent RGBE form
+    Hdr,
+}
+
+impl ImageFormat {
+    /// Picks the format from the extension of the output path:
+    /// .ppm gives P3 PPM, .png gives 8-bit PNG, .pfm and .hdr give linear float images
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "ppm" => Some(ImageFormat::Ppm(PpmFormat::default())),
+            "png" => Some(ImageFormat::Png(PngDepth::Eight)),
+            "pfm" => Some(ImageFormat::Pfm),
+            "hdr" => Some(ImageFormat::Hdr),
+            _ => None,
+        }
+    }
+
+    /// Same as ImageFormat::from_path(), but reports an unsupported extension as ImageError
+    pub fn detect(path: &Path) -> Result<Self, ImageError> {
+        Self::from_path(path).ok_or_else(|| ImageError::UnsupportedFormat(path.to_path_buf()))
+    }
+}
+
+/// Streaming writer of any supported ImageFormat
+pub enum ImageWriter<W: Write> {
+    Ppm(PpmWriter<W>),
+    Png(Box<PngWriter<W>>),
+    Pfm(PfmWriter<W>),
+    Hdr(HdrWriter<W>),
+}
+
+impl<W: Write> ImageWriter<W> {
+    /// Creates a writer for a given format and writes the image header into the sink
+    pub fn new(sink: W, width: usize, height: usize, format: ImageFormat) -> io::Result<Self> {
+        Ok(match format {
+            ImageFormat::Ppm(format) => {
+                ImageWriter::Ppm(PpmWriter::new(sink, width, height, format)?)
+            }
+            ImageFormat::Png(depth) => {
+                ImageWriter::Png(Box::new(PngWriter::new(sink, width, height, depth)?))
+            }
+            ImageFormat::Pfm => ImageWriter::Pfm(PfmWriter::new(sink, width, height)?),
+            ImageFormat::Hdr => ImageWriter::Hdr(HdrWriter::new(sink, width, height)?),
+        })
+    }
+
+    /// Whether the rows must be written bottom to top (PFM), instead of top to bottom
+    pub fn bottom_up(&self) -> bool {
+        matches!(self, ImageWriter::Pfm(_))
+    }
+
+    /// Encodes a single row of pixels and writes it into the sink
+    pub fn write_row(&mut self, row: &[Color]) -> io::Result<()> {
+        match self {
+            ImageWriter::Ppm(w) => w.write_row(row),
+            ImageWriter::Png(w) => w.write_row(row),
+            ImageWriter::Pfm(w) => w.write_row(row),
+            ImageWriter::Hdr(w) => w.write_row(row),
+        }
+    }
+
+    /// Finishes the image, flushes the sink and returns it back
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            ImageWriter::Ppm(w) => w.finish(),
+            ImageWriter::Png(w) => w.finish(),
+            ImageWriter::Pfm(w) => w.finish(),
+            ImageWriter::Hdr(w) => w.finish(),
+        }
+    }
+}
+
+/// Sample depth of a PNG file (RGB color type)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PngDepth {
+    /// 8 bits per channel
+    Eight,
+
+    /// 16 bits per channel
+    Sixteen,
+}
+
+/// The first eight bytes of every PNG file
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// Amount of compressed data gathered before it is emitted as an IDAT chunk
+const IDAT_SIZE: usize = 1 << 16;
+
+/// Streaming PNG encoder: rows are filtered, compressed with the built-in zlib encoder,
+/// and emitted as IDAT chunks as soon as enough compressed data is gathered.
+pub struct PngWriter<W: Write> {
+    sink: W,
+    depth: PngDepth,
+    width: usize,
+    rows_left: usize,
+
+    /// previous and current unfiltered rows (PNG filters reference the row above)
+    prev: Vec<u8>,
+    cur: Vec<u8>,
+
+    /// buffers for choosing the best filter for the current row
+    candidate: Vec<u8>,
+    best: Vec<u8>,
+
+    zlib: ZlibEncoder,
+}
+
+impl<W: Write> PngWriter<W> {
+    /// Creates a new PngWriter and writes the PNG signature and header into the sink
+    pub fn new(mut sink: W, width: usize, height: usize, depth: PngDepth) -> io::Result<Self> {
+        if width == 0 || height == 0 || width > u32::MAX as usize || height > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PngWriter.new(): image dimensions are not supported by PNG",
+            ));
+        }
+
+        let bit_depth = match depth {
+            PngDepth::Eight => 8,
+            PngDepth::Sixteen => 16,
+        };
+
+        // width, height, bit depth, color type (RGB), compression, filter, interlace
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, 2, 0, 0, 0]);
+
+        sink.write_all(&PNG_SIGNATURE)?;
+        write_png_chunk(&mut sink, b"IHDR", &ihdr)?;
+
+        let row_len = width * 3 * Self::bytes_per_sample(depth);
+        Ok(Self {
+            sink,
+            depth,
+            width,
+            rows_left: height,
+            prev: vec![0; row_len],
+            cur: Vec::with_capacity(row_len),
+            candidate: Vec::with_capacity(row_len),
+            best: Vec::with_capacity(row_len),
+            zlib: ZlibEncoder::new(),
+        })
+    }
+
+    fn bytes_per_sample(depth: PngDepth) -> usize {
+        match depth {
+            PngDepth::Eight => 1,
+            PngDepth::Sixteen => 2,
+        }
+    }
+
+    /// Encodes a single row of pixels, emitting IDAT chunks whenever enough data is compressed
+    pub fn write_row(&mut self, row: &[Color]) -> io::Result<()> {
+        check_row("PngWriter", row.len(), self.width, self.rows_left)?;
+
+        // serialize samples (16-bit samples are big-endian)
+        self.cur.clear();
+        for px in row {
+            match self.depth {
+                PngDepth::Eight => self.cur.extend_from_slice(&px.to_rgb8()),
+                PngDepth::Sixteen => {
+                    for sample in px.to_rgb16() {
+                        self.cur.extend_from_slice(&sample.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        // choose the filter with the smallest sum of absolute differences
+        let bpp = 3 * Self::bytes_per_sample(self.depth);
+        let mut best_kind = 0;
+        let mut best_score = u64::MAX;
+        for kind in 0..5 {
+            png_filter(kind, bpp, &self.cur, &self.prev, &mut self.candidate);
+            let score = self
+                .candidate
+                .iter()
+                .map(|&b| (b as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                best_score = score;
+                best_kind = kind;
+                std::mem::swap(&mut self.best, &mut self.candidate);
+            }
+        }
+
+        self.zlib.write(&[best_kind]);
+        self.zlib.write(&self.best);
+        std::mem::swap(&mut self.prev, &mut self.cur);
+        self.rows_left -= 1;
+
+        if self.zlib.pending_output() >= IDAT_SIZE {
+            let data = self.zlib.take_output();
+            write_png_chunk(&mut self.sink, b"IDAT", &data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining compressed data and the PNG trailer, flushes the sink and returns it back.
+    /// Fails if some rows were not written
+    pub fn finish(mut self) -> io::Result<W> {
+        check_finished("PngWriter", self.rows_left)?;
+
+        let data = self.zlib.finish();
+        write_png_chunk(&mut self.sink, b"IDAT", &data)?;
+        write_png_chunk(&mut self.sink, b"IEND", &[])?;
+
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+/// Writes a single PNG chunk: length, type, data and CRC of type and data
+fn write_png_chunk<W: Write>(sink: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    sink.write_all(&(data.len() as u32).to_be_bytes())?;
+    sink.write_all(kind)?;
+    sink.write_all(data)?;
+
+    let crc = crc32_update(crc32_update(0xFFFF_FFFF, kind), data) ^ 0xFFFF_FFFF;
+    sink.write_all(&crc.to_be_bytes())
+}
+
+/// Applies PNG filter of a given kind (0: None, 1: Sub, 2: Up, 3: Average, 4: Paeth) to the row
+/// bpp: number of bytes per complete pixel
+fn png_filter(kind: u8, bpp: usize, row: &[u8], prev: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+
+        let predictor = match kind {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+        out.push(row[i].wrapping_sub(predictor));
+    }
+}
+
+/// Paeth predictor as defined by the PNG specification
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Streaming PFM encoder (linear 32-bit float samples, little-endian).
+/// ALERT: PFM stores rows bottom to top, so rows must be written starting from the last one
+pub struct PfmWriter<W: Write> {
+    sink: W,
+    width: usize,
+    rows_left: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> PfmWriter<W> {
+    /// Creates a new PfmWriter and writes the PFM header into the sink
+    pub fn new(mut sink: W, width: usize, height: usize) -> io::Result<Self> {
+        // negative scale marks little-endian data
+        write!(sink, "PF\n{} {}\n-1.0\n", width, height)?;
+
+        Ok(Self {
+            sink,
+            width,
+            rows_left: height,
+            buf: Vec::with_capacity(width * 12),
+        })
+    }
+
+    /// Writes a single row of pixels into the sink (bottom to top)
+    pub fn write_row(&mut self, row: &[Color]) -> io::Result<()> {
+        check_row("PfmWriter", row.len(), self.width, self.rows_left)?;
+
+        self.buf.clear();
+        for px in row {
+            for sample in [px.r, px.g, px.b] {
+                self.buf.extend_from_slice(&(sample as f32).to_le_bytes());
+            }
+        }
+
+        self.rows_left -= 1;
+        self.sink.write_all(&self.buf)
+    }
+
+    /// Flushes the sink and returns it back. Fails if some rows were not written
+    pub fn finish(mut self) -> io::Result<W> {
+        check_finished("PfmWriter", self.rows_left)?;
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+/// Streaming Radiance HDR encoder: linear samples are stored in the shared-exponent RGBE form,
+/// and rows (top to bottom) are run-length encoded
+pub struct HdrWriter<W: Write> {
+    sink: W,
+    width: usize,
+    rows_left: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> HdrWriter<W> {
+    /// Creates a new HdrWriter and writes the Radiance header into the sink
+    pub fn new(mut sink: W, width: usize, height: usize) -> io::Result<Self> {
+        write!(
+            sink,
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )?;
+
+        Ok(Self {
+            sink,
+            width,
+            rows_left: height,
+            buf: Vec::with_capacity(width * 4),
+        })
+    }
+
+    /// Encodes a single row of pixels and writes it into the sink
+    pub fn write_row(&mut self, row: &[Color]) -> io::Result<()> {
+        check_row("HdrWriter", row.len(), self.width, self.rows_left)?;
+
+        let rgbe: Vec<[u8; 4]> = row.iter().map(rgbe_encode).collect();
+        self.buf.clear();
+
+        // run-length encoding is only defined for these widths, otherwise pixels are stored flat
+        if !(8..=0x7FFF).contains(&self.width) {
+            for px in rgbe {
+                self.buf.extend_from_slice(&px);
+            }
+        } else {
+            self.buf
+                .extend_from_slice(&[2, 2, (self.width >> 8) as u8, self.width as u8]);
+
+            // every component is encoded separately as runs and literal dumps
+            for c in 0..4 {
+                let comp: Vec<u8> = rgbe.iter().map(|px| px[c]).collect();
+                let mut i = 0;
+                while i < comp.len() {
+                    let run = comp[i..]
+                        .iter()
+                        .take(127)
+                        .take_while(|&&v| v == comp[i])
+                        .count();
+                    if run >= 3 {
+                        self.buf.extend_from_slice(&[128 + run as u8, comp[i]]);
+                        i += run;
+                        continue;
+                    }
+
+                    // gather a literal dump until the next run of at least 3 equal values
+                    let start = i;
+                    while i < comp.len() && i - start < 128 {
+                        if i + 2 < comp.len() && comp[i] == comp[i + 1] && comp[i] == comp[i + 2] {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    self.buf.push((i - start) as u8);
+                    self.buf.extend_from_slice(&comp[start..i]);
+                }
+            }
+        }
+
+        self.rows_left -= 1;
+        self.sink.write_all(&self.buf)
+    }
+
+    /// Flushes the sink and returns it back. Fails if some rows were not written
+    pub fn finish(mut self) -> io::Result<W> {
+        check_finished("HdrWriter", self.rows_left)?;
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+/// Largest value RGBE can store: the mantissa 255 with the exponent 127
+const RGBE_MAX: f64 = 255.0 * (1u128 << 119) as f64;
+
+/// Converts linear Color into the shared-exponent RGBE form. NaN channels are stored as 0,
+/// and channels beyond the range of RGBE (infinity included) as its largest value
+fn rgbe_encode(px: &Color) -> [u8; 4] {
+    let fit = |val: f64| {
+        if val.is_nan() {
+            0.0
+        } else {
+            val.clamp(0.0, RGBE_MAX)
+        }
+    };
+    let (r, g, b) = (fit(px.r), fit(px.g), fit(px.b));
+    let max = r.max(g).max(b);
+    if max < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    // max = m * 2^e, where m lies in [0.5, 1)
+    let mut e = max.log2().floor() as i32 + 1;
+    if max / 2f64.powi(e) >= 1.0 {
+        e += 1;
+    }
+    let e = e.clamp(-128, 127);
+    let scale = 256.0 / 2f64.powi(e);
+    let cvt = |val: f64| (val * scale).min(255.0) as u8;
+
+    [cvt(r), cvt(g), cvt(b), (e + 128) as u8]
+}
+
+/// Converts RGBE back into linear Color
+fn rgbe_decode(px: [u8; 4]) -> Color {
+    if px[3] == 0 {
+        return Color::black();
+    }
+
+    let f = 2f64.powi(px[3] as i32 - 136);
+    Color::new(
+        (px[0] as f64 + 0.5) * f,
+        (px[1] as f64 + 0.5) * f,
+        (px[2] as f64 + 0.5) * f,
+    )
+}
+
+/// Reads a PFM image (color "PF" or grayscale "Pf") into a new Canvas
+pub fn read_pfm<R: BufRead>(mut src: R) -> io::Result<Canvas> {
+    let magic = read_token(&mut src)?;
+    let channels = match magic.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("read_pfm(): not a PFM file")),
+    };
+
+    let width = parse_token::<usize, _>(&mut src)?;
+    let height = parse_token::<usize, _>(&mut src)?;
+    let scale = parse_token::<f64, _>(&mut src)?;
+    let little_endian = scale < 0.0;
+
+    let mut cv = Canvas::new(width, height, Color::black());
+    let mut buf = vec![0u8; width * channels * 4];
+    for y in (0..height).rev() {
+        src.read_exact(&mut buf)?;
+
+        let samples: Vec<f64> = buf
+            .chunks_exact(4)
+            .map(|b| {
+                let bytes = [b[0], b[1], b[2], b[3]];
+                if little_endian {
+                    f32::from_le_bytes(bytes) as f64
+                } else {
+                    f32::from_be_bytes(bytes) as f64
+                }
+            })
+            .collect();
+
+        for x in 0..width {
+            cv[[x, y]] = match channels {
+                3 => Color::new(samples[3 * x], samples[3 * x + 1], samples[3 * x + 2]),
+                _ => Color::new(samples[x], samples[x], samples[x]),
+            };
+        }
+    }
+
+    Ok(cv)
+}
+
+/// Reads a Radiance HDR image (RGBE, flat or run-length encoded rows) into a new Canvas
+pub fn read_hdr<R: BufRead>(mut src: R) -> io::Result<Canvas> {
+    // header lines are terminated by an empty line
+    let mut line = String::new();
+    src.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid_data("read_hdr(): not a Radiance HDR file"));
+    }
+    loop {
+        line.clear();
+        if src.read_line(&mut line)? == 0 {
+            return Err(invalid_data("read_hdr(): unexpected end of the header"));
+        }
+        let l = line.trim();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(format) = l.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid_data("read_hdr(): only RGBE data is supported"));
+            }
+        }
+    }
+
+    // resolution string; only the standard orientation is supported
+    line.clear();
+    src.read_line(&mut line)?;
+    let res: Vec<&str> = line.split_whitespace().collect();
+    if res.len() != 4 || res[0] != "-Y" || res[2] != "+X" {
+        return Err(invalid_data("read_hdr(): unsupported resolution string"));
+    }
+    let height = res[1]
+        .parse::<usize>()
+        .map_err(|_| invalid_data("read_hdr(): invalid height"))?;
+    let width = res[3]
+        .parse::<usize>()
+        .map_err(|_| invalid_data("read_hdr(): invalid width"))?;
+
+    let mut cv = Canvas::new(width, height, Color::black());
+    let mut comps = vec![0u8; width * 4];
+    for y in 0..height {
+        let mut head = [0u8; 4];
+        src.read_exact(&mut head)?;
+
+        let rle =
+            head[0] == 2 && head[1] == 2 && (head[2] as usize) << 8 | head[3] as usize == width;
+        if !rle {
+            // flat row: the first pixel was already read
+            comps[..4].copy_from_slice(&head);
+            src.read_exact(&mut comps[4..])?;
+            for x in 0..width {
+                let px = [
+                    comps[4 * x],
+                    comps[4 * x + 1],
+                    comps[4 * x + 2],
+                    comps[4 * x + 3],
+                ];
+                cv[[x, y]] = rgbe_decode(px);
+            }
+            continue;
+        }
+
+        // run-length encoded row: components are stored one after another
+        for c in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let mut count = [0u8; 1];
+                src.read_exact(&mut count)?;
+                let count = count[0] as usize;
+
+                if count > 128 {
+                    let count = count - 128;
+                    if x + count > width {
+                        return Err(invalid_data("read_hdr(): run exceeds the row"));
+                    }
+                    let mut val = [0u8; 1];
+                    src.read_exact(&mut val)?;
+                    for i in x..x + count {
+                        comps[4 * i + c] = val[0];
+                    }
+                    x += count;
+                } else {
+                    if count == 0 || x + count > width {
+                        return Err(invalid_data("read_hdr(): invalid dump length"));
+                    }
+                    let mut vals = vec![0u8; count];
+                    src.read_exact(&mut vals)?;
+                    for (i, val) in vals.into_iter().enumerate() {
+                        comps[4 * (x + i) + c] = val;
+                    }
+                    x += count;
+                }
+            }
+        }
+        for x in 0..width {
+            let px = [
+                comps[4 * x],
+                comps[4 * x + 1],
+                comps[4 * x + 2],
+                comps[4 * x + 3],
+            ];
+            cv[[x, y]] = rgbe_decode(px);
+        }
+    }
+
+    Ok(cv)
+}
+
+/// Reads a whitespace separated token of a header, consuming the single whitespace after it
+fn read_token<R: BufRead>(src: &mut R) -> io::Result<String> {
+    let mut token = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        src.read_exact(&mut byte)?;
+        if byte[0].is_ascii_whitespace() {
+            if token.is_empty() {
+                continue;
+            }
+            break;
+        }
+        token.push(byte[0]);
+    }
+
+    String::from_utf8(token).map_err(|_| invalid_data("read_token(): header is not ASCII"))
+}
+
+/// Reads a header token and parses it into a number
+fn parse_token<T: std::str::FromStr, R: BufRead>(src: &mut R) -> io::Result<T> {
+    let token = read_token(src)?;
+    token
+        .parse::<T>()
+        .map_err(|_| invalid_data("parse_token(): invalid number in the header"))
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Checks that a row may be written by a streaming writer
+fn check_row(writer: &str, len: usize, width: usize, rows_left: usize) -> io::Result<()> {
+    if len != width {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}.write_row(): row length does not match the image width",
+                writer
+            ),
+        ));
+    }
+    if rows_left == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}.write_row(): all rows have already been written", writer),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that a streaming writer received all rows
+fn check_finished(writer: &str, rows_left: usize) -> io::Result<()> {
+    if rows_left != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{}.finish(): not all rows were written", writer),
+        ));
+    }
+    Ok(())
+}
+
+/// Lookup table for CRC-32 (polynomial 0xEDB88320), computed at compile time
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// Updates a running CRC-32 with data. The running value starts at 0xFFFFFFFF and
+/// is inverted when all data is processed
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Returns CRC-32 checksum of data (as used in PNG chunks)
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xFFFF_FFFF, data) ^ 0xFFFF_FFFF
+}
+
+/// Size of the deflate sliding window (maximum match distance)
+const WINDOW: usize = 1 << 15;
+
+/// Amount of input compressed into a single deflate block
+const BLOCK: usize = 1 << 16;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_SIZE: usize = 1 << 15;
+
+/// How many earlier positions with the same hash are examined while looking for a match
+const MAX_CHAIN: usize = 64;
+
+/// Base lengths and number of extra bits of the deflate length codes 257..285
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and number of extra bits of the deflate distance codes 0..29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Writes bits into a byte buffer, least significant bit first (as required by deflate)
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    n: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            out: Vec::new(),
+            acc: 0,
+            n: 0,
+        }
+    }
+
+    /// Writes the lowest n bits of a value
+    fn put(&mut self, value: u32, n: u32) {
+        self.acc |= (value as u64) << self.n;
+        self.n += n;
+        while self.n >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n -= 8;
+        }
+    }
+
+    /// Writes a Huffman code of a given length (Huffman codes are stored most significant bit first)
+    fn put_code(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.put(reversed, len);
+    }
+
+    /// Pads the stream with zero bits up to the byte boundary
+    fn align(&mut self) {
+        if self.n > 0 {
+            self.put(0, 8 - self.n);
+        }
+    }
+}
+
+/// Zlib encoder (deflate with LZ77 matching and the fixed Huffman codes) that
+/// accepts input incrementally and keeps only the sliding window in memory
+struct ZlibEncoder {
+    /// sliding window followed by the input that is not compressed yet
+    data: Vec<u8>,
+
+    /// absolute stream position of data[0]
+    base: usize,
+
+    /// index in data of the first byte that is not compressed yet
+    pos: usize,
+
+    /// hash of three bytes -> (absolute position + 1) of its latest occurrence
+    head: Vec<usize>,
+
+    /// (absolute position % WINDOW) -> (absolute position + 1) of the previous occurrence of the same hash
+    prev: Vec<usize>,
+
+    bits: BitWriter,
+
+    /// Adler-32 checksum of the uncompressed data
+    adler: (u32, u32),
+}
+
+impl ZlibEncoder {
+    fn new() -> Self {
+        let mut bits = BitWriter::new();
+
+        // CMF: deflate with 32K window, FLG: no dictionary, fastest level, check bits
+        bits.out.extend_from_slice(&[0x78, 0x01]);
+
+        Self {
+            data: Vec::new(),
+            base: 0,
+            pos: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; WINDOW],
+            bits,
+            adler: (1, 0),
+        }
+    }
+
+    /// Feeds data into the encoder
+    fn write(&mut self, input: &[u8]) {
+        let (mut a, mut b) = self.adler;
+        for chunk in input.chunks(5552) {
+            for &byte in chunk {
+                a += byte as u32;
+                b += a;
+            }
+            a %= 65521;
+            b %= 65521;
+        }
+        self.adler = (a, b);
+
+        self.data.extend_from_slice(input);
+        while self.data.len() - self.pos >= BLOCK + MAX_MATCH {
+            self.compress_block(self.pos + BLOCK, false);
+        }
+    }
+
+    /// Number of compressed bytes that are ready to be taken
+    fn pending_output(&self) -> usize {
+        self.bits.out.len()
+    }
+
+    /// Takes the compressed bytes produced so far
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bits.out)
+    }
+
+    /// Compresses the remaining input and returns the rest of the zlib stream
+    fn finish(mut self) -> Vec<u8> {
+        self.compress_block(self.data.len(), true);
+        self.bits.align();
+
+        let (a, b) = self.adler;
+        let adler = (b << 16) | a;
+        self.bits.out.extend_from_slice(&adler.to_be_bytes());
+        self.bits.out
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let v = (self.data[i] as usize) << 16
+            | (self.data[i + 1] as usize) << 8
+            | self.data[i + 2] as usize;
+        (v.wrapping_mul(2654435761) >> 8) & (HASH_SIZE - 1)
+    }
+
+    /// Registers the position i in the hash chains
+    fn insert(&mut self, i: usize) {
+        if i + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let h = self.hash(i);
+        let abs = self.base + i;
+        self.prev[abs % WINDOW] = self.head[h];
+        self.head[h] = abs + 1;
+    }
+
+    /// Finds the longest earlier match for the position i, returns (length, distance)
+    fn find_match(&self, i: usize) -> (usize, usize) {
+        let max_len = MAX_MATCH.min(self.data.len() - i);
+        if max_len < MIN_MATCH {
+            return (0, 0);
+        }
+
+        let abs = self.base + i;
+        let mut best = (0, 0);
+        let mut cand = self.head[self.hash(i)];
+        for _ in 0..MAX_CHAIN {
+            if cand == 0 {
+                break;
+            }
+            let c = cand - 1;
+            if c < self.base || abs - c > WINDOW {
+                break;
+            }
+
+            let j = c - self.base;
+            let len = self.data[i..i + max_len]
+                .iter()
+                .zip(&self.data[j..])
+                .take_while(|(x, y)| x == y)
+                .count();
+            if len > best.0 {
+                best = (len, abs - c);
+                if len == max_len {
+                    break;
+                }
+            }
+
+            let next = self.prev[c % WINDOW];
+            if next >= cand {
+                break; // the chain entry was overwritten by a newer position
+            }
+            cand = next;
+        }
+
+        if best.0 >= MIN_MATCH {
+            best
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// Compresses input up to data[end] into a single block with fixed Huffman codes
+    fn compress_block(&mut self, end: usize, last: bool) {
+        // BFINAL, BTYPE = 01 (fixed Huffman codes)
+        self.bits.put(last as u32, 1);
+        self.bits.put(1, 2);
+
+        let mut i = self.pos;
+        while i < end {
+            let (len, dist) = self.find_match(i);
+            if len >= MIN_MATCH {
+                self.put_match(len, dist);
+                for k in i..i + len {
+                    self.insert(k);
+                }
+                i += len;
+            } else {
+                self.put_symbol(self.data[i] as u32);
+                self.insert(i);
+                i += 1;
+            }
+        }
+        self.put_symbol(256); // end of block
+        self.pos = i;
+
+        // keep only the sliding window of already compressed data
+        if self.pos > WINDOW {
+            let drop = self.pos - WINDOW;
+            self.data.drain(..drop);
+            self.base += drop;
+            self.pos -= drop;
+        }
+    }
+
+    /// Writes a literal/length symbol with the fixed Huffman code
+    fn put_symbol(&mut self, sym: u32) {
+        match sym {
+            0..=143 => self.bits.put_code(0x30 + sym, 8),
+            144..=255 => self.bits.put_code(0x190 + sym - 144, 9),
+            256..=279 => self.bits.put_code(sym - 256, 7),
+            _ => self.bits.put_code(0xC0 + sym - 280, 8),
+        }
+    }
+
+    /// Writes a <length, distance> pair
+    fn put_match(&mut self, len: usize, dist: usize) {
+        let li = LEN_BASE.iter().rposition(|&b| b as usize <= len).unwrap();
+        self.put_symbol(257 + li as u32);
+        self.bits
+            .put((len - LEN_BASE[li] as usize) as u32, LEN_EXTRA[li] as u32);
+
+        let di = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+        self.bits.put_code(di as u32, 5);
+        self.bits.put(
+            (dist - DIST_BASE[di] as usize) as u32,
+            DIST_EXTRA[di] as u32,
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Contains the integrators, which compute the light arriving along a camera ray:
+//! the Whitted-style Phong shading of World::calc, and a Monte Carlo path tracer
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use super::bsdf::{Bsdf, BsdfKind};
+use super::core::{Computations, RAIIDrawable, Ray};
+use super::World;
+use crate::math::sampler::Sampler;
+use crate::math::{utils, Color};
+
+/// Number of bounces after which the path tracer starts Russian roulette
+const ROULETTE_DEPTH: usize = 3;
+
+/// Computes the radiance carried by camera rays
+pub trait Integrator: fmt::Debug {
+    /// Returns the name of the integrator
+    fn name(&self) -> &'static str;
+
+    /// Returns the light arriving along the ray r:
+    /// bg: color of the rays that hit nothing
+    /// sampler: random numbers of the current pixel sample
+    /// max_depth: maximum number of bounces of a light path
+    fn li(
+        &self,
+        world: &World,
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        max_depth: usize,
+    ) -> Color {
+        let emitters = world.emitters();
+        self.li_with(world, &emitters, r, bg, sampler, max_depth)
+    }
+
+    /// Same as li(), with the emitters of the world (World::emitters) found by the caller,
+    /// so that a render finds them once instead of for every camera ray
+    fn li_with(
+        &self,
+        world: &World,
+        emitters: &[RAIIDrawable],
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        max_depth: usize,
+    ) -> Color;
+}
+
+/// Integrator shared between the Renderers of the render threads
+pub type SharedIntegrator = Arc<dyn Integrator + Send + Sync>;
+
+/// Returns the integrator with a given name ("whitted" or "path")
+pub fn from_name(name: &str) -> Result<SharedIntegrator, String> {
+    match name {
+        "whitted" => Ok(Arc::new(Whitted)),
+        "path" => Ok(Arc::new(PathTracer::default())),
+        _ => Err(format!("unknown integrator '{}'", name)),
+    }
+}
+
+/// Direct Phong shading of the first hit with hard shadows (World::calc), the default integrator.
+/// Perfectly smooth dielectrics and conductors also reflect and refract the rays recursively.
+/// Emissive objects (World::emitters) also light the hit through its BSDF, from one point sampled
+/// on them per pixel sample, so their shadows become soft with several samples per pixel.
+/// The fog and the volumes dim the light and scatter the light of the point and directional
+/// lights (World::media). Subsurface scattering gathers the light of the emitters at one probe
+/// per pixel sample
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Whitted;
+
+impl Integrator for Whitted {
+    fn name(&self) -> &'static str {
+        "whitted"
+    }
+
+    fn li_with(
+        &self,
+        world: &World,
+        emitters: &[RAIIDrawable],
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        max_depth: usize,
+    ) -> Color {
+        self.trace(world, emitters, r, bg, sampler, max_depth.max(1))
+    }
+}
+
+impl Whitted {
+    /// Shades the first hit of the ray r, and follows the mirror and refracted rays of perfectly
+    /// smooth surfaces while depth (the number of hits left) allows it
+    fn trace(
+        &self,
+        world: &World,
+        emitters: &[RAIIDrawable],
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        depth: usize,
+    ) -> Color {
+        let xs = world.intersect(r);
+        let Some(hit) = world.surface_hit(&xs) else {
+            let (scattered, tr) = world.media(r, f64::INFINITY);
+            return scattered + tr * world.background(&r.direction, bg);
+        };
+        let info = Computations::new(hit.clone(), r);
+        let bsdf = Bsdf::from_hit(&info);
+        let subsurface = info.obj.borrow().get_material().subsurface;
+
+        let mut res = Color::black();
+        match subsurface.filter(|_| !info.inside) {
+            // the light of the emitters enters the surface at a probe
+            Some(ss) => {
+                if let Some((q, weight)) = ss.probe(&info, sampler) {
+                    if let Some((l, li)) = world.sample_emitter(emitters, &q.over_p, sampler) {
+                        let cos = utils::dot(&l, &q.n);
+                        if cos > 0.0 {
+                            res = weight * li * (cos / PI);
+                        }
+                    }
+                }
+            }
+            None => {
+                if let Some((l, li)) = world.sample_emitter(emitters, &info.over_p, sampler) {
+                    let cos = utils::dot(&l, &info.n);
+                    if cos > 0.0 {
+                        res = bsdf.eval(&info.e, &l, &info.n) * li * cos;
+                    }
+                }
+            }
+        }
+
+        if depth > 1 {
+            for (dir, weight) in bsdf.specular_lobes(&info.e, &info.n) {
+                let origin = if utils::dot(&dir, &info.n) > 0.0 {
+                    info.over_p
+                } else {
+                    info.under_p
+                };
+                let c = self.trace(
+                    world,
+                    emitters,
+                    &Ray::new(origin, dir).with_wavelength(r.wavelength),
+                    bg,
+                    sampler,
+                    depth - 1,
+                );
+                res = res + weight * c;
+            }
+        }
+        let (scattered, tr) = world.media(r, hit.t);
+        scattered + tr * (res + world.shade_hit(info))
+    }
+}
+
+/// Unidirectional path tracer. Surfaces scatter light with the BSDF of their material, Phong
+/// materials reflect diffusely with their color scaled by the diffuse coefficient (the ambient
+/// and specular terms are not used). Every bounce adds the direct light of the point and
+/// directional lights and of one point sampled on an emitter (next-event estimation), then
+/// continues in a direction sampled from the BSDF.
+/// The emission of a hit surface is added only when it is not sampled by next-event estimation:
+/// for camera rays, after perfectly smooth (delta) bounces and transmissions (next-event
+/// estimation samples only the side of the surface the ray came from), and for emitters that
+/// cannot be sampled (e.g. planes).
+/// Rays that hit nothing return the background color, which acts as a uniform sky, or the
+/// radiance of the World's environment. The environment is also sampled by next-event estimation,
+/// and both of its estimates are combined by multiple importance sampling (power heuristic).
+/// The fog and the volumes dim the light along every segment of a path, and add the light
+/// they scatter from the point and directional lights (single scattering, World::media).
+/// Phong materials with subsurface scattering move the path to a probe where the light enters
+/// the surface (weighted by the diffusion profile), which then reflects like a white diffuse
+/// surface.
+///
+/// Point and directional lights keep the convention of the Phong model: they have no distance
+/// falloff, and a white diffuse surface facing a light of intensity I reflects I
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracer {
+    /// number of bounces after which paths are terminated by Russian roulette
+    pub roulette_depth: usize,
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        Self {
+            roulette_depth: ROULETTE_DEPTH,
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn name(&self) -> &'static str {
+        "path"
+    }
+
+    fn li_with(
+        &self,
+        world: &World,
+        emitters: &[RAIIDrawable],
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        max_depth: usize,
+    ) -> Color {
+        let mut res = Color::black();
+        let mut throughput = utils::color(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        // true when the last bounce is not covered by next-event estimation
+        let mut unsampled = false;
+        let mut prev_pdf = 0.0;
+
+        for depth in 0..max_depth.max(1) {
+            let xs = world.intersect(&ray);
+            let hit = world.surface_hit(&xs);
+
+            // single scattering of the media on the way to the hit
+            let (scattered, tr) = world.media(&ray, hit.map_or(f64::INFINITY, |i| i.t));
+            res = res + throughput * scattered;
+            throughput = throughput * tr;
+
+            let info = match hit {
+                Some(i) => Computations::new(i.clone(), &ray),
+                None => {
+                    let li = world.background(&ray.direction, bg);
+                    let w = match &world.environment {
+                        Some(env) if depth > 0 && !unsampled => {
+                            power_heuristic(prev_pdf, env.pdf(&ray.direction))
+                        }
+                        _ => 1.0,
+                    };
+                    return res + throughput * li * w;
+                }
+            };
+
+            let (emission, subsurface) = {
+                let obj = info.obj.borrow();
+                let m = obj.get_material();
+                let diffuse = m.bsdf == BsdfKind::Phong && !info.inside;
+                (m.emission, m.subsurface.filter(|_| diffuse))
+            };
+            if depth == 0 || unsampled || !is_sampled(emitters, &info.obj) {
+                res = res + throughput * emission;
+            }
+
+            let (info, bsdf) = match subsurface {
+                Some(ss) => {
+                    let Some((q, weight)) = ss.probe(&info, sampler) else {
+                        break;
+                    };
+                    throughput = throughput * weight;
+                    (
+                        q,
+                        Bsdf::Lambert {
+                            albedo: Color::white(),
+                        },
+                    )
+                }
+                None => {
+                    let bsdf = Bsdf::from_hit(&info);
+                    (info, bsdf)
+                }
+            };
+
+            // next-event estimation (delta BSDFs reflect nothing from sampled directions)
+            if !bsdf.is_delta() {
+                res = res + throughput * direct_light(world, emitters, &info, &bsdf, sampler);
+            }
+
+            let Some(s) = bsdf.sample(&info.e, &info.n, sampler) else {
+                break;
+            };
+            throughput = throughput * s.weight;
+            unsampled = s.delta || utils::dot(&s.wi, &info.n) < 0.0;
+            prev_pdf = s.pdf;
+            if depth + 1 >= self.roulette_depth {
+                let q = max_component(&throughput).min(0.95);
+                if q <= 0.0 || sampler.next_f64() >= q {
+                    break;
+                }
+                throughput = throughput * (1.0 / q);
+            }
+
+            let origin = if utils::dot(&s.wi, &info.n) > 0.0 {
+                info.over_p
+            } else {
+                info.under_p
+            };
+            ray = Ray::new(origin, s.wi).with_wavelength(ray.wavelength);
+        }
+        res
+    }
+}
+
+/// Returns the light reflected at a hit from the point and directional lights, from one point sampled
+/// on an emitter and from one direction sampled from the environment
+fn direct_light(
+    world: &World,
+    emitters: &[RAIIDrawable],
+    info: &Computations,
+    bsdf: &Bsdf,
+    sampler: &mut Sampler,
+) -> Color {
+    let mut res = Color::black();
+
+    // a light of intensity I delivers the irradiance PI * I * cos
+    for light in world.sources.iter() {
+        let l = (light.pos - info.over_p).normalize();
+        let cos = utils::dot(&l, &info.n);
+        if cos > 0.0 {
+            let f = bsdf.eval(&info.e, &l, &info.n);
+            let tr = world.transmittance(&info.over_p, &light.pos);
+            res = res + f * light.intensity() * tr * (PI * cos);
+        }
+    }
+    for light in world.directional.iter() {
+        let cos = utils::dot(&light.dir, &info.n);
+        if cos > 0.0 {
+            let f = bsdf.eval(&info.e, &light.dir, &info.n);
+            let tr = world.transmittance_to(&info.over_p, &light.dir);
+            res = res + f * light.intensity() * tr * (PI * cos);
+        }
+    }
+
+    if let Some((l, li)) = world.sample_emitter(emitters, &info.over_p, sampler) {
+        let cos = utils::dot(&l, &info.n);
+        if cos > 0.0 {
+            res = res + bsdf.eval(&info.e, &l, &info.n) * li * cos;
+        }
+    }
+
+    if let Some((l, li, pdf)) = world.sample_environment(&info.over_p, sampler) {
+        let cos = utils::dot(&l, &info.n);
+        if cos > 0.0 {
+            let w = power_heuristic(pdf, bsdf.pdf(&info.e, &l, &info.n));
+            res = res + bsdf.eval(&info.e, &l, &info.n) * li * (cos * w);
+        }
+    }
+    res
+}
+
+/// Returns the weight of a sample with the density f when another technique could have
+/// produced it with the density g (power heuristic with exponent 2)
+fn power_heuristic(f: f64, g: f64) -> f64 {
+    let (f2, g2) = (f * f, g * g);
+    if f2 + g2 <= 0.0 {
+        0.0
+    } else {
+        f2 / (f2 + g2)
+    }
+}
+
+/// Returns true if an object is one of the emitters sampled by next-event estimation
+fn is_sampled(emitters: &[RAIIDrawable], obj: &RAIIDrawable) -> bool {
+    emitters.iter().any(|e| Rc::ptr_eq(e, obj))
+}
+
+/// Returns the largest channel of a color
+fn max_component(c: &Color) -> f64 {
+    c.r.max(c.g).max(c.b)
+}
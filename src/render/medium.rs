@@ -0,0 +1,246 @@
+//! Contains participating media, which absorb and scatter the light passing through them:
+//! the fog that fills a World, and the volumes bounded by closed objects (Material::medium),
+//! which are heterogeneous when their Material has a density.
+//! The transmittance of heterogeneous volumes is estimated by ratio tracking, whose random
+//! numbers are derived from the ray, so the renders stay reproducible
+
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use super::core::Ray;
+use super::density::Density;
+use crate::math::sampler::Sampler;
+use crate::math::{utils, Color, Matrix, Vector};
+
+/// Number of points per ray segment at which the light scattered by a medium is gathered
+pub const VOLUME_SAMPLES: usize = 16;
+
+/// Homogeneous medium:
+/// absorption: fraction of the light absorbed per unit of distance (per channel)
+/// scattering: fraction of the light scattered per unit of distance (per channel)
+/// g: anisotropy of the Henyey-Greenstein phase function, from -1 (backward scattering)
+/// through 0 (isotropic) to 1 (forward scattering)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Medium {
+    pub absorption: Color,
+    pub scattering: Color,
+    #[serde(default)]
+    pub g: f64,
+}
+
+impl Medium {
+    /// Creates an isotropic Medium
+    pub fn new(absorption: Color, scattering: Color) -> Self {
+        Self {
+            absorption,
+            scattering,
+            g: 0.0,
+        }
+    }
+
+    /// Returns the fraction of the light absorbed or scattered per unit of distance
+    pub fn extinction(&self) -> Color {
+        self.absorption + self.scattering
+    }
+
+    /// Returns the fraction of the light that passes through a given distance of the Medium
+    /// (Beer-Lambert law)
+    pub fn transmittance(&self, dist: f64) -> Color {
+        let e = self.extinction();
+        let tr = |s: f64| if s <= 0.0 { 1.0 } else { (-s * dist).exp() };
+        utils::color(tr(e.r), tr(e.g), tr(e.b))
+    }
+
+    /// Returns the density (per solid angle) of light scattered by an angle whose cosine is cos
+    /// (between the directions of travel before and after scattering)
+    pub fn phase(&self, cos: f64) -> f64 {
+        let g = self.g.clamp(-0.99, 0.99);
+        let denom = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    /// Returns the points of the segment [t0, t1) of a ray (t1 may be infinite) at which the
+    /// scattered light is gathered, and their weights: the sum of weight * transmittance from t0
+    /// to t * light scattered at t estimates the light the Medium scatters along the segment.
+    /// The points are stratified in proportion to the transmittance, so they crowd near t0
+    /// in dense media
+    pub fn scatter_points(&self, t0: f64, t1: f64) -> Vec<(f64, Color)> {
+        let e = self.extinction();
+        let mean = (e.r + e.g + e.b) / 3.0;
+        if mean <= 0.0 || t1 <= t0 || self.scattering == Color::black() {
+            return vec![];
+        }
+
+        // fraction of the points' distribution that fits into the segment
+        let covered = -(-mean * (t1 - t0)).exp_m1();
+        if covered <= 0.0 {
+            return vec![];
+        }
+        (0..VOLUME_SAMPLES)
+            .map(|i| {
+                let u = (i as f64 + 0.5) / VOLUME_SAMPLES as f64;
+                let s = -(-u * covered).ln_1p() / mean;
+                let pdf = mean * (-mean * s).exp() / covered;
+                (
+                    t0 + s,
+                    self.scattering * (1.0 / (pdf * VOLUME_SAMPLES as f64)),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Fog that fills the World below a given height:
+/// medium: the Medium of the fog
+/// height: the top of the fog (None when the fog fills all of the space)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Fog {
+    pub medium: Medium,
+    #[serde(default)]
+    pub height: Option<f64>,
+}
+
+impl Fog {
+    /// Creates a Fog that fills all of the space
+    pub fn new(medium: Medium) -> Self {
+        Self {
+            medium,
+            height: None,
+        }
+    }
+
+    /// Returns the part of the segment [0, t_max] of the ray r that lies inside of the fog
+    pub fn segment(&self, r: &Ray, t_max: f64) -> Option<(f64, f64)> {
+        let (y, dy) = (r.origin.y, r.direction.y);
+        let (t0, t1) = match self.height {
+            None => (0.0, t_max),
+            Some(h) if dy == 0.0 => {
+                if y >= h {
+                    return None;
+                }
+                (0.0, t_max)
+            }
+            Some(h) => {
+                // the ray crosses the top of the fog at tc
+                let tc = (h - y) / dy;
+                if dy > 0.0 {
+                    (0.0, t_max.min(tc))
+                } else {
+                    (tc.max(0.0), t_max)
+                }
+            }
+        };
+
+        if t1 > t0 {
+            Some((t0, t1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Part [t0, t1] of a ray that lies inside of a medium. Heterogeneous volumes carry their density
+/// and the transformation from the World into their object space
+#[derive(Debug, Clone)]
+pub struct MediumSegment {
+    pub medium: Medium,
+    pub t0: f64,
+    pub t1: f64,
+    pub density: Option<(Density, Matrix)>,
+}
+
+impl MediumSegment {
+    /// Creates the segment of a homogeneous medium
+    pub fn new(medium: Medium, t0: f64, t1: f64) -> Self {
+        Self {
+            medium,
+            t0,
+            t1,
+            density: None,
+        }
+    }
+
+    /// Returns the density at the point p (1 in homogeneous media)
+    pub fn density_at(&self, p: &Vector) -> f64 {
+        match &self.density {
+            Some((d, itm)) => d.at(&(itm * p)),
+            None => 1.0,
+        }
+    }
+
+    /// Returns the majorant of the extinction: the largest extinction over the channels
+    /// and the points of the segment
+    fn majorant(&self) -> f64 {
+        let e = self.medium.extinction();
+        let density = self.density.as_ref().map_or(1.0, |(d, _)| d.max());
+        e.r.max(e.g).max(e.b) * density
+    }
+
+    /// Walks along the segment of the ray r through the tentative collisions of ratio tracking
+    /// (distributed with the majorant of the extinction), and returns their distances with the
+    /// density there. Homogeneous media need no collisions
+    pub fn track(&self, r: &Ray, sampler: &mut Sampler) -> Vec<(f64, f64)> {
+        let majorant = self.majorant();
+        if self.density.is_none() || majorant <= 0.0 {
+            return vec![];
+        }
+
+        let mut res = vec![];
+        let mut t = self.t0;
+        loop {
+            t -= (1.0 - sampler.next_f64()).ln() / majorant;
+            if t >= self.t1 {
+                return res;
+            }
+            res.push((t, self.density_at(&r.pos(t))));
+        }
+    }
+
+    /// Returns the transmittance from the start of the segment to the distance t (clamped to the
+    /// segment), estimated from the collisions returned by track() in heterogeneous media
+    pub fn transmittance(&self, collisions: &[(f64, f64)], t: f64) -> Color {
+        if self.density.is_none() {
+            return self
+                .medium
+                .transmittance(t.clamp(self.t0, self.t1) - self.t0);
+        }
+
+        // every collision keeps the fraction of the majorant that is not real extinction
+        let (e, majorant) = (self.medium.extinction(), self.majorant());
+        collisions
+            .iter()
+            .take_while(|(tc, _)| *tc < t)
+            .fold(Color::white(), |tr, (_, d)| {
+                tr * (Color::white() - e * (d / majorant))
+            })
+    }
+
+    /// Returns the points of the segment at which the scattered light is gathered and their
+    /// weights (see Medium::scatter_points), the collisions of ratio tracking in heterogeneous media
+    pub fn scatter_points(&self, collisions: &[(f64, f64)]) -> Vec<(f64, Color)> {
+        if self.density.is_none() {
+            return self.medium.scatter_points(self.t0, self.t1);
+        }
+
+        // the collisions are a Poisson process with the rate of the majorant
+        let majorant = self.majorant();
+        collisions
+            .iter()
+            .map(|(t, d)| (*t, self.medium.scattering * (d / majorant)))
+            .collect()
+    }
+}
+
+/// Returns the Sampler of the media along a ray, seeded by the ray
+pub fn ray_sampler(r: &Ray) -> Sampler {
+    let (o, d) = (&r.origin, &r.direction);
+    Sampler::hashed(&[
+        o.x.to_bits(),
+        o.y.to_bits(),
+        o.z.to_bits(),
+        d.x.to_bits(),
+        d.y.to_bits(),
+        d.z.to_bits(),
+    ])
+}
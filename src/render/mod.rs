@@ -1,30 +1,110 @@
-use shapes::{Point, Sphere};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use shapes::{Point, ShapeKind, Sphere};
 
-use crate::math::{utils, Color, Matrix, TUnit, Vector};
+use crate::math::{sampler::Sampler, utils, Color, Matrix, TUnit, Vector};
 
 use self::core::Drawable;
 
-use core::{Computations, Is, Material, PointLight, RAIIDrawable, Ray, II};
-use std::fs::File;
-use std::io::Write;
+use core::{Computations, DirectionalLight, Is, Material, PointLight, RAIIDrawable, Ray, I, II};
+use std::fs::{self, File};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::ops;
-use std::path::PathBuf;
-
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::cell::OnceCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use aov::{Aov, AovBuffers};
+use checkpoint::{Checkpoint, Tile};
+use environment::{Environment, EnvironmentDesc};
+use medium::{Fog, Medium, MediumSegment};
+use image::{ImageError, ImageFormat, ImageWriter, PpmFormat};
+use integrator::{SharedIntegrator, Whitted};
+use progress::{CancelToken, NoProgress, PassObserver, Progress, RenderObserver, RenderStatus};
+use progressive::{Accumulator, Budget};
+use stereo::{Eye, Stereo, StereoImage};
+use subsurface::{Subsurface, SUBSURFACE_SAMPLES};
+use tonemap::PostProcess;
+
+pub mod aov;
+pub mod bsdf;
+pub mod checkpoint;
 pub mod core;
+pub mod density;
+pub mod environment;
+pub mod image;
+pub mod integrator;
+pub mod medium;
+pub mod progress;
+pub mod progressive;
 pub mod shapes;
+pub mod sky;
+pub mod spectrum;
+pub mod stereo;
+pub mod subsurface;
+pub mod tonemap;
+
+/// Projection of the Camera
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    /// Rays start at the eye and spread by the field of view
+    #[default]
+    Perspective,
+
+    /// Parallel rays leave a view rectangle of the given width in world units
+    /// (its height follows the aspect ratio of the image)
+    Orthographic { width: f64 },
+
+    /// 360 degree panorama: the columns span the longitude (the center of the image looks
+    /// forward, the left and right edges look backward) and the rows span the latitude.
+    /// Pixels are square, so an image twice as wide as tall covers the whole sphere
+    Equirectangular,
+
+    /// Equidistant fisheye: the angle from the view direction grows linearly with the distance
+    /// from the center of the image, reaching fov / 2 at the edge of the circle inscribed in it
+    /// (pixels outside of the circle look further than fov / 2)
+    Fisheye,
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    /// Parses perspective, equirectangular, fisheye or orthographic:WIDTH
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perspective" => Ok(Projection::Perspective),
+            "equirectangular" => Ok(Projection::Equirectangular),
+            "fisheye" => Ok(Projection::Fisheye),
+            _ => match s.strip_prefix("orthographic:").map(|w| w.parse::<f64>()) {
+                Some(Ok(width)) if width > 0.0 => Ok(Projection::Orthographic { width }),
+                _ => Err(format!("unknown projection '{}'", s)),
+            },
+        }
+    }
+}
 
 /// Structure that implements Camera
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Camera {
     pub hsize: usize, // in px
     pub vsize: usize, // in px
     pub fov: f64,
 
+    // for panoramic projections the following are angles in radians
     pub px_size: f64, // pixel size in canvas untis
     pub hw: f64,      // half width of CV
     pub hh: f64,      // half height of CV
 
     /// view transformation matrix
     pub vtm: Matrix,
+
+    #[serde(default)]
+    pub projection: Projection,
 }
 
 impl Camera {
@@ -39,17 +119,44 @@ impl Camera {
             hw,
             hh,
             vtm: Matrix::identity(),
+            projection: Projection::Perspective,
         }
     }
 
+    /// Creates a Camera with a panoramic (or any other) projection, fov is used by fisheye cameras
+    pub fn with_projection(hsize: usize, vsize: usize, fov: f64, projection: Projection) -> Self {
+        let mut res = Camera::new(hsize, vsize, fov);
+        res.set_projection(projection);
+        res
+    }
+
+    /// Creates an orthographic Camera whose view is width world units wide
+    pub fn orthographic(hsize: usize, vsize: usize, width: f64) -> Self {
+        let mut res = Camera::new(hsize, vsize, std::f64::consts::FRAC_PI_2);
+        res.set_projection(Projection::Orthographic { width });
+        res
+    }
+
+    /// Changes the projection of the Camera, keeping its resolution and view transformation
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.resize(self.hsize, self.vsize);
+    }
+
     /// Returns a Ray from the Camera to the provided pixel position of the Canvas
     pub fn ray_for_pixel(&self, x: usize, y: usize) -> Ray {
+        self.ray_for_subpixel(x, y, 0.5, 0.5)
+    }
+
+    /// Returns a Ray from the Camera through the point (dx, dy) inside the pixel,
+    /// where dx and dy are in [0, 1) and (0.5, 0.5) is the pixel's center
+    pub fn ray_for_subpixel(&self, x: usize, y: usize, dx: f64, dy: f64) -> Ray {
         let x = x as f64;
         let y = y as f64;
 
-        // the offset from the edge of the canvas to the pixel's cente
-        let xoffset = (x + 0.5) * self.px_size;
-        let yoffset = (y + 0.5) * self.px_size;
+        // the offset from the edge of the canvas to the sample point
+        let xoffset = (x + dx) * self.px_size;
+        let yoffset = (y + dy) * self.px_size;
 
         // the untransformed coordinates of the pixel in world space.
         let world_x = self.hw - xoffset;
@@ -59,14 +166,42 @@ impl Camera {
         let inv_view = self
             .vtm
             .try_inverse()
-            .expect("Cannot invert view transformation matrix in Camera.ray_for_pixel()");
+            .expect("Cannot invert view transformation matrix in Camera.ray_for_subpixel()");
 
         // find the ray's origin and direction, and apply the view transformation
-        let pixel = &inv_view * utils::point(world_x, world_y, -1.0);
-        let origin = &inv_view * utils::point(0.0, 0.0, 0.0);
-        let direction = (pixel - origin).normalize();
+        let (origin, direction) = match self.projection {
+            Projection::Perspective => {
+                let pixel = inv_view * utils::point(world_x, world_y, -1.0);
+                let origin = inv_view * utils::point(0.0, 0.0, 0.0);
+                (origin, (pixel - origin).normalize())
+            }
+            Projection::Orthographic { .. } => {
+                let origin = inv_view * utils::point(world_x, world_y, 0.0);
+                let direction = inv_view * utils::vector(0.0, 0.0, -1.0);
+                (origin, direction.normalize())
+            }
+            Projection::Equirectangular => {
+                // world_x is the longitude (positive to the left), world_y the latitude
+                let (lon, lat) = (world_x, world_y);
+                let dir = utils::vector(lat.cos() * lon.sin(), lat.sin(), -lat.cos() * lon.cos());
+                let origin = inv_view * utils::point(0.0, 0.0, 0.0);
+                (origin, (inv_view * dir).normalize())
+            }
+            Projection::Fisheye => {
+                // the angle from the view direction is the distance from the center
+                let theta = world_x.hypot(world_y);
+                let dir = if theta == 0.0 {
+                    utils::vector(0.0, 0.0, -1.0)
+                } else {
+                    let s = theta.sin() / theta;
+                    utils::vector(world_x * s, world_y * s, -theta.cos())
+                };
+                let origin = inv_view * utils::point(0.0, 0.0, 0.0);
+                (origin, (inv_view * dir).normalize())
+            }
+        };
 
-        Ray { origin, direction }
+        Ray::new(origin, direction)
     }
 
     /// Calculates pixel size, half_width, and half_height of the Canvas
@@ -90,6 +225,38 @@ impl Camera {
         return (hw * 2.0 / hsize, hw, hh);
     }
 
+    /// Changes the resolution of the Camera, keeping its field of view (or view width)
+    /// and view transformation
+    pub fn resize(&mut self, hsize: usize, vsize: usize) {
+        let (px_size, hw, hh) = match self.projection {
+            Projection::Perspective => {
+                Camera::calculate_parameters(hsize as f64, vsize as f64, self.fov)
+            }
+            Projection::Orthographic { width } => {
+                let px_size = width / hsize as f64;
+                (px_size, width / 2.0, px_size * vsize as f64 / 2.0)
+            }
+            Projection::Equirectangular => {
+                let px_size = 2.0 * PI / hsize as f64;
+                (px_size, PI, px_size * vsize as f64 / 2.0)
+            }
+            Projection::Fisheye => {
+                let px_size = self.fov / hsize.min(vsize) as f64;
+                (
+                    px_size,
+                    px_size * hsize as f64 / 2.0,
+                    px_size * vsize as f64 / 2.0,
+                )
+            }
+        };
+
+        self.hsize = hsize;
+        self.vsize = vsize;
+        self.px_size = px_size;
+        self.hw = hw;
+        self.hh = hh;
+    }
+
     /// Sets a camera's view transformation
     pub fn set_view(&mut self, from: Vector, to: Vector, up: Vector) {
         // normalize up vector
@@ -114,10 +281,27 @@ impl Camera {
 }
 
 /// Structure that is used to generate images on Canvas and PPM, configure the World and Camera
+/// spp: number of samples per pixel (1 shoots a single ray through the pixel's center)
+/// max_depth: maximum recursion depth of a light path
+/// tile_size: side of the square tiles the image is rendered in (in px)
+/// integrator: computes the color of the camera rays (Whitted by default)
 pub struct Renderer {
     pub world: World,
+    pub spp: usize,
+    pub max_depth: usize,
+    pub tile_size: usize,
+    pub integrator: SharedIntegrator,
     cv: Canvas,
     c: Camera,
+
+    /// canvases of the enabled output variables
+    aovs: AovBuffers,
+
+    /// directory where the generated images are written
+    out_dir: PathBuf,
+
+    /// emitters of the world, found on the first camera ray of a render
+    emitters: OnceCell<Vec<RAIIDrawable>>,
 }
 
 impl Renderer {
@@ -133,42 +317,651 @@ impl Renderer {
     ) -> Self {
         let mut res = Self {
             world: World::new(),
+            spp: 1,
+            max_depth: 5,
+            tile_size: 32,
+            integrator: Arc::new(Whitted),
             cv: Canvas::new(hsize, vsize, bg),
+            aovs: AovBuffers::default(),
             c: Camera::new(hsize, vsize, fov),
+            out_dir: PathBuf::from("img"),
+            emitters: OnceCell::new(),
         };
         res.c.set_view(from, to, up);
         res
     }
 
-    /// Render objects from the world onto the canvas
-    pub fn render(&mut self) {
+    /// Creates a Renderer from an already configured World and Camera
+    pub fn with_camera(world: World, c: Camera, bg: Color) -> Self {
+        Self {
+            world,
+            spp: 1,
+            max_depth: 5,
+            tile_size: 32,
+            integrator: Arc::new(Whitted),
+            cv: Canvas::new(c.hsize, c.vsize, bg),
+            aovs: AovBuffers::default(),
+            c,
+            out_dir: PathBuf::from("img"),
+            emitters: OnceCell::new(),
+        }
+    }
+
+    /// Returns a reference to the Camera
+    pub fn camera(&self) -> &Camera {
+        &self.c
+    }
+
+    /// Returns a mutable reference to the Camera (resize through Renderer.resize())
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.c
+    }
+
+    /// Changes the resolution of the Camera and the Canvas (the Canvas is cleared)
+    pub fn resize(&mut self, hsize: usize, vsize: usize) {
+        self.c.resize(hsize, vsize);
+        self.cv = Canvas::new(hsize, vsize, self.cv.bg);
+        self.aovs.resize(hsize, vsize);
+    }
+
+    /// Enables an output variable, which is rendered into its own Canvas next to the image
+    pub fn enable_aov(&mut self, aov: Aov) {
+        self.aovs.enable(aov, self.cv.width, self.cv.height);
+    }
+
+    /// Returns the Canvas of an enabled output variable
+    pub fn aov(&self, aov: Aov) -> Option<&Canvas> {
+        self.aovs.get(aov)
+    }
+
+    /// Renders the enabled output variables with one ray through the center of every pixel.
+    /// Finished renders call it on their own
+    pub fn render_aovs(&mut self) {
+        if self.aovs.is_empty() {
+            return;
+        }
+
         for y in 0..self.cv.height {
             for x in 0..self.cv.width {
                 let ray = self.c.ray_for_pixel(x, y);
-                let color = self.world.calc(&ray, &self.cv.bg);
+                self.aovs.write(x, y, &self.world, &ray);
+            }
+        }
+    }
+
+    /// Render objects from the world onto the canvas
+    pub fn render(&mut self) {
+        self.render_with(&mut NoProgress, &CancelToken::new());
+    }
+
+    /// Renders both eyes of a stereo rig placed around the Camera.
+    /// The Camera is restored afterwards, and the Canvas is cleared
+    pub fn render_stereo(&mut self, stereo: &Stereo) -> StereoImage {
+        let center = self.c.clone();
+        let blank = Canvas::new(self.cv.width, self.cv.height, self.cv.bg);
+
+        let mut render_eye = |eye| {
+            self.c = stereo.eye(&center, eye);
+            self.render();
+            std::mem::replace(&mut self.cv, blank.clone())
+        };
+        let left = render_eye(Eye::Left);
+        let right = render_eye(Eye::Right);
+
+        self.c = center;
+        StereoImage { left, right }
+    }
+
+    /// Renders objects from the world onto the canvas tile by tile, reporting the progress to
+    /// observer after every tile and checking cancel between tiles.
+    /// A cancelled render keeps the tiles finished so far on the canvas
+    pub fn render_with(
+        &mut self,
+        observer: &mut dyn RenderObserver,
+        cancel: &CancelToken,
+    ) -> RenderStatus {
+        let ck = Checkpoint::new(self.cv.width, self.cv.height, self.tile_size, self.spp);
+        let mut tiles = TileCollector::new(self, ck, None);
+        self.render_tiles(&mut tiles, observer, cancel)
+            .expect("Tiles cannot fail without a checkpoint file")
+    }
+
+    /// Renders like render_with(), saving the finished tiles into the checkpoint file at path
+    /// every interval (and when the render ends or is cancelled).
+    /// If the file already exists, the render resumes from it and skips the tiles it holds,
+    /// so the final image is the same as the one of an uninterrupted render
+    pub fn render_resumable<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        interval: Duration,
+        observer: &mut dyn RenderObserver,
+        cancel: &CancelToken,
+    ) -> Result<RenderStatus, ImageError> {
+        let path = path.as_ref();
+        let ck = self.checkpoint_at(path)?;
+        let mut tiles = TileCollector::new(self, ck, Some((path, interval)));
+        self.render_tiles(&mut tiles, observer, cancel)
+    }
+
+    /// Returns the checkpoint stored at path, or an empty one if there is no such file.
+    /// A checkpoint is only resumed by a render of the same scene with the same settings,
+    /// so the scene has to be serializable
+    fn checkpoint_at(&self, path: &Path) -> Result<Checkpoint, ImageError> {
+        // the debug form of the integrator holds its parameters and the wrapped integrators
+        let integrator = format!("{:?}", self.integrator);
+        let scene = (&self.c, &self.cv.bg, &self.world, &integrator);
+        let hash = checkpoint::hash_serialized(&scene).map_err(|e| {
+            let msg = format!("the scene cannot be saved with a checkpoint: {}", e);
+            ImageError::from(io::Error::new(io::ErrorKind::InvalidInput, msg)).at(path)
+        })?;
+        let res = Checkpoint::new(self.cv.width, self.cv.height, self.tile_size, self.spp)
+            .with_settings(self.integrator.name(), self.max_depth, hash);
+        if !path.exists() {
+            return Ok(res);
+        }
+
+        let loaded = Checkpoint::load(path)?;
+        if !loaded.matches(&res) {
+            let settings = |c: &Checkpoint| {
+                (c.width, c.height, c.tile_size, c.spp, c.integrator.clone(), c.max_depth)
+            };
+            let msg = if settings(&loaded) == settings(&res) {
+                "checkpoint was made for another scene or integrator parameters".to_string()
+            } else {
+                format!(
+                    "checkpoint was made for a {}x{} render with {} px tiles, {} spp, \
+                     the {} integrator and max depth {}",
+                    loaded.width,
+                    loaded.height,
+                    loaded.tile_size,
+                    loaded.spp,
+                    loaded.integrator,
+                    loaded.max_depth
+                )
+            };
+            let err = io::Error::new(io::ErrorKind::InvalidData, msg);
+            return Err(ImageError::from(err).at(path));
+        }
+        Ok(loaded)
+    }
+
+    /// Renders the pending tiles of the collector on this thread
+    fn render_tiles(
+        &mut self,
+        tiles: &mut TileCollector,
+        observer: &mut dyn RenderObserver,
+        cancel: &CancelToken,
+    ) -> Result<RenderStatus, ImageError> {
+        self.emitters.take();
+        for index in tiles.ck.pending() {
+            if cancel.is_cancelled() {
+                break;
+            }
+
+            let sums = self.sample_tile(&tiles.grid[index]);
+            tiles.add(&mut self.cv, index, sums, observer)?;
+        }
+
+        let status = tiles.finish()?;
+        if status == RenderStatus::Completed {
+            self.draw_points();
+            self.render_aovs();
+        }
+        Ok(status)
+    }
+
+    /// Renders rows y0..y1 of the canvas (points are not drawn)
+    pub fn render_rows(&mut self, y0: usize, y1: usize) {
+        self.emitters.take();
+        for y in y0..y1.min(self.cv.height) {
+            for x in 0..self.cv.width {
+                let color = self.render_pixel(x, y);
                 self.cv
                     .write(x, y, color)
-                    .expect("Could not write to Canvas at Renderer.render()");
+                    .expect("Could not write to Canvas at Renderer.render_rows()");
             }
         }
+    }
+
+    /// Returns the color of the pixel (x, y) averaged over all of its samples
+    pub fn render_pixel(&self, x: usize, y: usize) -> Color {
+        self.sample_pixel(x, y) * (1.0 / self.spp.max(1) as f64)
+    }
 
-        // Draw points
+    /// Returns the sum of all samples of the pixel (x, y)
+    pub fn sample_pixel(&self, x: usize, y: usize) -> Color {
+        if self.spp <= 1 {
+            let ray = self.c.ray_for_pixel(x, y);
+            return self.trace(&ray, &mut Sampler::for_pixel(x, y, 0));
+        }
+
+        let mut sum = Color::black();
+        for s in 0..self.spp {
+            sum = sum + self.sample(x, y, s);
+        }
+        sum
+    }
+
+    /// Returns the s-th sample of the pixel (x, y), shot through a jittered point of the pixel
+    pub fn sample(&self, x: usize, y: usize, s: usize) -> Color {
+        let mut sampler = Sampler::for_pixel(x, y, s);
+        let (dx, dy) = sampler.next_2d();
+        let ray = self.c.ray_for_subpixel(x, y, dx, dy);
+        self.trace(&ray, &mut sampler)
+    }
+
+    /// Returns the color of a camera ray computed by the integrator
+    fn trace(&self, r: &Ray, sampler: &mut Sampler) -> Color {
+        let emitters = self.emitters.get_or_init(|| self.world.emitters());
+        self.integrator
+            .li_with(&self.world, emitters, r, &self.cv.bg, sampler, self.max_depth)
+    }
+
+    /// Renders progressively: adds whole-image passes of one sample per pixel into acc until
+    /// budget is reached, writing the current average onto the canvas after every pass.
+    /// observer receives the canvas and the progress (in samples per pixel) after every pass.
+    /// cancel is checked between rows, and an unfinished pass is discarded.
+    /// acc may already hold passes of an earlier call, which are continued
+    pub fn render_progressive(
+        &mut self,
+        acc: &mut Accumulator,
+        budget: Budget,
+        observer: &mut dyn PassObserver,
+        cancel: &CancelToken,
+    ) -> RenderStatus {
+        assert!(
+            acc.width == self.cv.width && acc.height == self.cv.height,
+            "Renderer.render_progressive(): the accumulator does not match the canvas"
+        );
+
+        // the output variables do not change between passes
+        self.render_aovs();
+        self.emitters.take();
+
+        let start = Instant::now();
+        let first = acc.samples();
+        let mut pass = Vec::with_capacity(acc.width * acc.height);
+
+        while !budget.reached(acc.samples(), start.elapsed()) {
+            let s = acc.samples();
+            pass.clear();
+            for y in 0..self.cv.height {
+                if cancel.is_cancelled() {
+                    return RenderStatus::Cancelled;
+                }
+                for x in 0..self.cv.width {
+                    pass.push(self.sample(x, y, s));
+                }
+            }
+
+            acc.add_pass(&pass);
+            acc.resolve(&mut self.cv);
+            self.draw_points();
+
+            let elapsed = start.elapsed();
+            let total = budget.samples.unwrap_or(0);
+            let mut p = Progress::new(acc.samples(), total, elapsed);
+
+            // the remaining time is estimated from the passes of this call, bounded by the time budget
+            let by_samples = budget
+                .samples
+                .and_then(|_| Progress::new(acc.samples() - first, total - first, elapsed).eta);
+            let by_time = budget.time.map(|t| t.saturating_sub(elapsed));
+            p.eta = match (by_samples, by_time) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            observer.pass(&self.cv, &p);
+        }
+        RenderStatus::Completed
+    }
+
+    /// Returns the sample sums of the pixels of a tile in row-major order
+    pub fn sample_tile(&self, t: &Tile) -> Vec<Color> {
+        let mut res = Vec::with_capacity(t.len());
+        for y in t.y0..t.y1 {
+            for x in t.x0..t.x1 {
+                res.push(self.sample_pixel(x, y));
+            }
+        }
+        res
+    }
+
+    /// Draws the points of the world on top of the rendered canvas
+    fn draw_points(&mut self) {
         for p in self.world.points.iter() {
             p.draw(&mut self.cv);
         }
     }
 
-    /// Generates the PPM file
-    pub fn generate_ppm(&self, filename: &str) {
-        self.cv.to_ppm(filename);
+    /// Returns a reference to the Canvas the objects are rendered onto
+    pub fn canvas(&self) -> &Canvas {
+        &self.cv
+    }
+
+    /// Sets the directory where the generated images are written (img by default)
+    pub fn set_output_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.out_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// Returns the directory where the generated images are written
+    pub fn output_dir(&self) -> &Path {
+        &self.out_dir
+    }
+
+    /// Resolves filename inside the output directory, creating the directory if necessary
+    fn output_path(&self, filename: &str) -> Result<PathBuf, ImageError> {
+        fs::create_dir_all(&self.out_dir).map_err(|e| ImageError::from(e).at(&self.out_dir))?;
+        Ok(self.out_dir.join(filename))
+    }
+
+    /// Generates the image of an enabled output variable in the output directory
+    /// (the format is chosen by the extension, PFM keeps the values unchanged)
+    pub fn generate_aov(&self, aov: Aov, filename: &str) -> Result<(), ImageError> {
+        let cv = self
+            .aovs
+            .get(aov)
+            .expect("Output variable is not enabled in Renderer.generate_aov()");
+        cv.save(self.output_path(filename)?)
+    }
+
+    /// Generates the PPM file in the output directory
+    pub fn generate_ppm(&self, filename: &str) -> Result<(), ImageError> {
+        self.cv.to_ppm(self.output_path(filename)?)
+    }
+
+    /// Generates the PPM file in a given PPM flavor in the output directory
+    pub fn generate_ppm_as(&self, filename: &str, format: PpmFormat) -> Result<(), ImageError> {
+        self.cv.to_ppm_as(self.output_path(filename)?, format)
+    }
+
+    /// Generates the image file in the output directory, the format is chosen by the file extension (.ppm, .png, .pfm or .hdr)
+    pub fn generate_image(&self, filename: &str) -> Result<(), ImageError> {
+        self.generate_image_with(filename, &PostProcess::default())
+    }
+
+    /// Generates the image file in the output directory (the format is chosen by the file extension), using the given post-processing stage
+    pub fn generate_image_with(&self, filename: &str, post: &PostProcess) -> Result<(), ImageError> {
+        let format = ImageFormat::detect(filename.as_ref())?;
+        self.cv.save_with(self.output_path(filename)?, format, post)
+    }
+}
+
+/// Collects finished tiles on the rendering thread: writes them onto the canvas,
+/// reports the progress and saves the checkpoint file
+struct TileCollector<'a> {
+    ck: Checkpoint,
+    grid: Vec<Tile>,
+
+    /// checkpoint file and the interval between saves
+    autosave: Option<(&'a Path, Duration)>,
+    last_save: Instant,
+
+    start: Instant,
+    /// number of tiles finished before this run (resumed from the checkpoint)
+    resumed: usize,
+}
+
+impl<'a> TileCollector<'a> {
+    /// Creates a collector, drawing the tiles already in the checkpoint onto the canvas
+    fn new(r: &mut Renderer, ck: Checkpoint, autosave: Option<(&'a Path, Duration)>) -> Self {
+        let grid = ck.grid();
+        for (index, tile) in grid.iter().enumerate() {
+            if let Some(sums) = ck.get(index) {
+                TileCollector::draw(&mut r.cv, tile, sums, ck.spp);
+            }
+        }
+
+        Self {
+            resumed: ck.finished(),
+            ck,
+            grid,
+            autosave,
+            last_save: Instant::now(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Draws the averaged samples of a tile onto the canvas
+    fn draw(cv: &mut Canvas, t: &Tile, sums: &[Color], spp: usize) {
+        let scale = 1.0 / spp.max(1) as f64;
+        let width = t.x1 - t.x0;
+        for y in t.y0..t.y1 {
+            let row = &sums[(y - t.y0) * width..(y - t.y0 + 1) * width];
+            for (dst, sum) in cv.row_mut(y)[t.x0..t.x1].iter_mut().zip(row) {
+                *dst = sum * scale;
+            }
+        }
+    }
+
+    /// Adds a finished tile
+    fn add(
+        &mut self,
+        cv: &mut Canvas,
+        index: usize,
+        sums: Vec<Color>,
+        observer: &mut dyn RenderObserver,
+    ) -> Result<(), ImageError> {
+        TileCollector::draw(cv, &self.grid[index], &sums, self.ck.spp);
+        self.ck.insert(index, sums);
+
+        // the remaining time is estimated from the tiles of this run only
+        let done = self.ck.finished();
+        let mut p = Progress::new(done, self.grid.len(), self.start.elapsed());
+        p.eta = Progress::new(done - self.resumed, self.grid.len() - self.resumed, p.elapsed).eta;
+        observer.progress(&p);
+
+        if let Some((path, interval)) = self.autosave {
+            if self.last_save.elapsed() >= interval {
+                self.ck.save(path)?;
+                self.last_save = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the final checkpoint and returns the status of the render
+    fn finish(&mut self) -> Result<RenderStatus, ImageError> {
+        if let Some((path, _)) = self.autosave {
+            self.ck.save(path)?;
+        }
+
+        if self.ck.finished() == self.grid.len() {
+            Ok(RenderStatus::Completed)
+        } else {
+            Ok(RenderStatus::Cancelled)
+        }
+    }
+}
+
+/// Error of a resumable parallel render
+#[derive(Debug)]
+pub enum RenderError<E> {
+    /// The scene could not be built
+    Scene(E),
+
+    /// The checkpoint file could not be read or written
+    Checkpoint(ImageError),
+}
+
+impl<E: fmt::Display> fmt::Display for RenderError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Scene(e) => write!(f, "{}", e),
+            RenderError::Checkpoint(e) => write!(f, "checkpoint: {}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for RenderError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RenderError::Scene(e) => Some(e),
+            RenderError::Checkpoint(e) => Some(e),
+        }
+    }
+}
+
+/// Renders a scene on several threads.
+/// World holds shared references to its objects and cannot cross threads, so every
+/// thread builds its own copy of the scene with build and renders tiles from a shared queue.
+/// The tiles are merged into the Renderer built first, which is returned
+pub fn render_parallel<F, E>(build: F, threads: usize) -> Result<Renderer, E>
+where
+    F: Fn() -> Result<Renderer, E> + Sync,
+    E: Send,
+{
+    let (res, _) = render_parallel_with(build, threads, &mut NoProgress, &CancelToken::new())?;
+    Ok(res)
+}
+
+/// Renders a scene on several threads like render_parallel(), reporting the progress to observer
+/// (on the calling thread) after every tile and stopping all threads once cancel is set
+pub fn render_parallel_with<F, E>(
+    build: F,
+    threads: usize,
+    observer: &mut dyn RenderObserver,
+    cancel: &CancelToken,
+) -> Result<(Renderer, RenderStatus), E>
+where
+    F: Fn() -> Result<Renderer, E> + Sync,
+    E: Send,
+{
+    match parallel_tiles(build, threads, None, observer, cancel) {
+        Ok(res) => Ok(res),
+        Err(RenderError::Scene(e)) => Err(e),
+        Err(RenderError::Checkpoint(_)) => unreachable!("no checkpoint is saved"),
     }
 }
 
+/// Renders a scene on several threads like render_parallel_with(), saving and resuming
+/// the checkpoint file at path like Renderer.render_resumable()
+pub fn render_parallel_resumable<F, E, P>(
+    build: F,
+    threads: usize,
+    path: P,
+    interval: Duration,
+    observer: &mut dyn RenderObserver,
+    cancel: &CancelToken,
+) -> Result<(Renderer, RenderStatus), RenderError<E>>
+where
+    F: Fn() -> Result<Renderer, E> + Sync,
+    E: Send,
+    P: AsRef<Path>,
+{
+    parallel_tiles(
+        build,
+        threads,
+        Some((path.as_ref(), interval)),
+        observer,
+        cancel,
+    )
+}
+
+/// Renders the tiles on worker threads and collects them on the calling thread
+fn parallel_tiles<F, E>(
+    build: F,
+    threads: usize,
+    autosave: Option<(&Path, Duration)>,
+    observer: &mut dyn RenderObserver,
+    cancel: &CancelToken,
+) -> Result<(Renderer, RenderStatus), RenderError<E>>
+where
+    F: Fn() -> Result<Renderer, E> + Sync,
+    E: Send,
+{
+    let threads = threads.max(1);
+    let mut res = build().map_err(RenderError::Scene)?;
+
+    let ck = match autosave {
+        Some((path, _)) => res.checkpoint_at(path).map_err(RenderError::Checkpoint)?,
+        None => Checkpoint::new(res.cv.width, res.cv.height, res.tile_size, res.spp),
+    };
+    let mut tiles = TileCollector::new(&mut res, ck, autosave);
+    if threads == 1 {
+        let status = res
+            .render_tiles(&mut tiles, observer, cancel)
+            .map_err(RenderError::Checkpoint)?;
+        return Ok((res, status));
+    }
+
+    let (width, height) = (res.cv.width, res.cv.height);
+    let (spp, max_depth) = (res.spp, res.max_depth);
+    let integrator = &res.integrator;
+    let pending = tiles.ck.pending();
+    let grid = tiles.grid.clone();
+
+    // index of the next pending tile, and a flag that stops the threads when one of them fails
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let stopped = || stop.load(Ordering::Relaxed) || cancel.is_cancelled();
+
+    let mut failure = None;
+    std::thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel::<Result<(usize, Vec<Color>), E>>();
+        for _ in 0..threads.min(pending.len()) {
+            let (tx, build, stopped) = (tx.clone(), &build, &stopped);
+            let (next, pending, grid) = (&next, &pending, &grid);
+            scope.spawn(move || {
+                let mut r = match build() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+                r.resize(width, height);
+                r.spp = spp;
+                r.max_depth = max_depth;
+                r.integrator = integrator.clone();
+
+                while !stopped() {
+                    let Some(&index) = pending.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        return;
+                    };
+                    let sums = r.sample_tile(&grid[index]);
+                    if tx.send(Ok((index, sums))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        for msg in rx {
+            let added = match msg {
+                Ok((index, sums)) => tiles
+                    .add(&mut res.cv, index, sums, observer)
+                    .map_err(RenderError::Checkpoint),
+                Err(e) => Err(RenderError::Scene(e)),
+            };
+            if let Err(e) = added {
+                stop.store(true, Ordering::Relaxed);
+                failure.get_or_insert(e);
+            }
+        }
+    });
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    let status = tiles.finish().map_err(RenderError::Checkpoint)?;
+    if status == RenderStatus::Completed {
+        res.draw_points();
+        res.render_aovs();
+    }
+    Ok((res, status))
+}
+
 /// Structure that holds points, objects and lights, their inner data, and overall configurations of the virtual world
+/// World is serialized with its objects in the ShapeKind form
 pub struct World {
     pub points: Vec<Point>,
     pub objects: Vec<RAIIDrawable>,
     pub sources: Vec<Box<PointLight>>,
+    pub directional: Vec<DirectionalLight>,
+    pub fog: Option<Fog>,
+    pub environment: Option<Box<dyn Environment>>,
 }
 
 impl World {
@@ -178,6 +971,9 @@ impl World {
             points: vec![],
             objects: vec![],
             sources: vec![],
+            directional: vec![],
+            fog: None,
+            environment: None,
         }
     }
 
@@ -203,6 +999,30 @@ impl World {
         self.sources.push(src);
     }
 
+    /// Adds a directional light source
+    pub fn add_directional(&mut self, light: DirectionalLight) {
+        self.directional.push(light);
+    }
+
+    /// Fills the World with fog
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.fog = Some(fog);
+    }
+
+    /// Sets the environment that lights the World from infinitely far away
+    pub fn set_environment(&mut self, env: Box<dyn Environment>) {
+        self.environment = Some(env);
+    }
+
+    /// Returns the light arriving along a ray that hits nothing: the radiance of the environment
+    /// in its direction, or the background color bg when there is no environment
+    pub fn background(&self, dir: &Vector, bg: &Color) -> Color {
+        match &self.environment {
+            Some(env) => env.radiance(dir),
+            None => *bg,
+        }
+    }
+
     /// Interect the world's object with a given ray
     pub fn intersect(&self, r: &Ray) -> Is {
         let mut world_intersections: Is = Is::new();
@@ -218,6 +1038,14 @@ impl World {
         world_intersections
     }
 
+    /// Returns the first intersection in front of the ray origin with a surface that is drawn,
+    /// skipping the boundaries of volumes
+    pub fn surface_hit<'a>(&self, xs: &'a Is) -> Option<&'a I> {
+        xs.iter()
+            .filter(|i| i.t >= 0.0)
+            .find(|i| i.obj.borrow().get_material().medium.is_none())
+    }
+
     /// Checks whether a point is shadowed
     /// p: point that is being checked
     pub fn is_shadowed(&self, p: &Vector) -> bool {
@@ -226,17 +1054,27 @@ impl World {
             panic!("World does not support multiple sources, or no sources were provided");
         }
 
-        // calculate the distance from the point p to the light source
-        let mut v = self.sources[0].pos - p;
+        self.is_shadowed_by(p, &self.sources[0])
+    }
+
+    /// Checks whether a point is shadowed from a given light source
+    pub fn is_shadowed_by(&self, p: &Vector, light: &PointLight) -> bool {
+        self.is_occluded(p, &light.pos)
+    }
+
+    /// Checks whether an object lies between the points p and q
+    pub fn is_occluded(&self, p: &Vector, q: &Vector) -> bool {
+        // calculate the distance from the point p to the point q
+        let mut v = q - p;
         let dist = v.magnitude();
 
-        // get the ray from the point p to the light source
+        // get the ray from the point p to the point q
         v.normalize_mut();
         let r = Ray::new(p.clone(), v);
 
-        // intersect world with the ray, and identify hit
+        // intersect world with the ray, and identify hit (volumes only dim the light)
         let xs = self.intersect(&r);
-        let hit = xs.hit();
+        let hit = self.surface_hit(&xs);
 
         match hit {
             Some(i) => {
@@ -249,37 +1087,414 @@ impl World {
         }
     }
 
-    /// Shades a hit using given computations information
+    /// Checks whether an object lies in the direction dir from the point p
+    pub fn is_blocked(&self, p: &Vector, dir: &Vector) -> bool {
+        let xs = self.intersect(&Ray::new(*p, *dir));
+        self.surface_hit(&xs).is_some()
+    }
+
+    /// Returns the fraction of the light that travels from the point q to the point p:
+    /// black if an object lies between them, otherwise the transmittance of the media
+    pub fn transmittance(&self, p: &Vector, q: &Vector) -> Color {
+        if self.is_occluded(p, q) {
+            return Color::black();
+        }
+        let mut v = q - p;
+        let dist = v.magnitude();
+        v.normalize_mut();
+        self.media_transmittance(&Ray::new(*p, v), dist)
+    }
+
+    /// Returns the fraction of the light that arrives at the point p from infinitely far away
+    /// in the direction dir
+    pub fn transmittance_to(&self, p: &Vector, dir: &Vector) -> Color {
+        if self.is_blocked(p, dir) {
+            return Color::black();
+        }
+        self.media_transmittance(&Ray::new(*p, *dir), f64::INFINITY)
+    }
+
+    /// Returns the media (the fog and the volumes) along the segment [0, t_max] of the ray r,
+    /// with the part of the segment that each of them fills
+    fn media_segments(&self, r: &Ray, t_max: f64) -> Vec<MediumSegment> {
+        let mut res = vec![];
+        if let Some(fog) = &self.fog {
+            if let Some((t0, t1)) = fog.segment(r, t_max) {
+                res.push(MediumSegment::new(fog.medium, t0, t1));
+            }
+        }
+
+        for obj in self.objects.iter() {
+            let obj = obj.borrow();
+            let m = obj.get_material();
+            let Some(medium) = m.medium else {
+                continue;
+            };
+            let density = m.density.as_ref().map(|d| {
+                let itm = obj
+                    .get_transform()
+                    .inverse()
+                    .expect("Could not invert Transformation matrix in World::media_segments()");
+                (d.clone(), itm)
+            });
+
+            // the ray is inside of a closed object between pairs of its intersections
+            let mut ts = obj.intersect(r);
+            ts.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            for pair in ts.chunks_exact(2) {
+                let (t0, t1) = (pair[0].max(0.0), pair[1].min(t_max));
+                if t1 > t0 {
+                    res.push(MediumSegment {
+                        medium,
+                        t0,
+                        t1,
+                        density: density.clone(),
+                    });
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the transmittance of the media along the segment [0, t_max] of the ray r
+    fn media_transmittance(&self, r: &Ray, t_max: f64) -> Color {
+        let mut sampler = medium::ray_sampler(r);
+        self.media_segments(r, t_max).iter().fold(Color::white(), |tr, s| {
+            let collisions = s.track(r, &mut sampler);
+            tr * s.transmittance(&collisions, s.t1)
+        })
+    }
+
+    /// Returns the light that the media (the fog and the volumes) scatter from the light sources
+    /// towards the origin of the ray r along the segment [0, t_max] (single scattering),
+    /// and the transmittance of the media along the segment
+    pub fn media(&self, r: &Ray, t_max: f64) -> (Color, Color) {
+        let segments = self.media_segments(r, t_max);
+        if segments.is_empty() {
+            return (Color::black(), Color::white());
+        }
+
+        // collisions of ratio tracking in the heterogeneous volumes
+        let mut sampler = medium::ray_sampler(r);
+        let tracks: Vec<_> = segments.iter().map(|s| s.track(r, &mut sampler)).collect();
+
+        // transmittance from the origin of the ray to the distance t
+        let tr_to = |t: f64| {
+            segments
+                .iter()
+                .zip(tracks.iter())
+                .fold(Color::white(), |tr, (s, c)| tr * s.transmittance(c, t))
+        };
+
+        let mut scattered = Color::black();
+        for (s, collisions) in segments.iter().zip(tracks.iter()) {
+            for (t, weight) in s.scatter_points(collisions) {
+                let li = self.in_scattered(&s.medium, &r.pos(t), &r.direction);
+                scattered = scattered + weight * tr_to(t) * li;
+            }
+        }
+        (scattered, tr_to(t_max))
+    }
+
+    /// Returns the light of the point and directional lights arriving at the point p inside of
+    /// the medium m that it scatters into the direction -dir (per unit of scattering).
+    /// A light of intensity I delivers the irradiance PI * I, like on surfaces
+    fn in_scattered(&self, m: &Medium, p: &Vector, dir: &Vector) -> Color {
+        let mut res = Color::black();
+        for light in self.sources.iter() {
+            let l = (light.pos - p).normalize();
+            let tr = self.transmittance(p, &light.pos);
+            res = res + light.intensity() * tr * (PI * m.phase(utils::dot(&l, dir)));
+        }
+        for light in self.directional.iter() {
+            let tr = self.transmittance_to(p, &light.dir);
+            res = res + light.intensity() * tr * (PI * m.phase(utils::dot(&light.dir, dir)));
+        }
+        res
+    }
+
+    /// Returns the fraction of the light sources (point and directional) that are visible
+    /// from a point (0 with no sources)
+    pub fn visibility(&self, p: &Vector) -> f64 {
+        let total = self.sources.len() + self.directional.len();
+        if total == 0 {
+            return 0.0;
+        }
+
+        let visible = self
+            .sources
+            .iter()
+            .filter(|l| !self.is_shadowed_by(p, l))
+            .count()
+            + self
+                .directional
+                .iter()
+                .filter(|l| !self.is_blocked(p, &l.dir))
+                .count();
+        visible as f64 / total as f64
+    }
+
+    /// Returns the index of an object in the World
+    pub fn index_of(&self, obj: &RAIIDrawable) -> Option<usize> {
+        self.objects.iter().position(|o| Rc::ptr_eq(o, obj))
+    }
+
+    /// Returns the emissive objects whose surface can be sampled, which act as light sources
+    pub fn emitters(&self) -> Vec<RAIIDrawable> {
+        self.objects
+            .iter()
+            .filter(|o| {
+                let o = o.borrow();
+                o.get_material().is_emissive() && o.local_sample((0.5, 0.5)).is_some()
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Samples a point on one of the emitters as seen from the point p. Returns the direction
+    /// towards it and the radiance arriving from it divided by the density of the direction
+    /// (per solid angle) and dimmed by the media, or None if the point is occluded
+    pub fn sample_emitter(
+        &self,
+        emitters: &[RAIIDrawable],
+        p: &Vector,
+        sampler: &mut Sampler,
+    ) -> Option<(Vector, Color)> {
+        if emitters.is_empty() {
+            return None;
+        }
+
+        // pick an emitter uniformly, then a point on its surface
+        let i = ((sampler.next_f64() * emitters.len() as f64) as usize).min(emitters.len() - 1);
+        let emitter = emitters[i].borrow();
+        let s = emitter.sample_surface(sampler.next_2d())?;
+
+        let mut l = s.p - p;
+        let dist2 = l.magnitude_squared();
+        l.normalize_mut();
+
+        // the surface emits on both sides
+        let cos_light = utils::dot(&l, &s.n).abs();
+        if s.pdf <= 0.0 {
+            return None;
+        }
+        let tr = self.transmittance(p, &s.p);
+        if tr == Color::black() {
+            return None;
+        }
+
+        let weight = cos_light * emitters.len() as f64 / (dist2 * s.pdf);
+        Some((l, emitter.get_material().emission * tr * weight))
+    }
+
+    /// Samples a direction of the environment as seen from the point p. Returns the direction,
+    /// the radiance arriving from it (dimmed by the media) divided by its density (per solid
+    /// angle) and the density, or None if there is no environment or the direction is blocked
+    pub fn sample_environment(
+        &self,
+        p: &Vector,
+        sampler: &mut Sampler,
+    ) -> Option<(Vector, Color, f64)> {
+        let env = self.environment.as_ref()?;
+        let (l, li, pdf) = env.sample(sampler.next_2d());
+        if pdf <= 0.0 {
+            return None;
+        }
+        let tr = self.transmittance_to(p, &l);
+        if tr == Color::black() {
+            return None;
+        }
+        Some((l, li * tr, pdf))
+    }
+
+    /// Estimates the direct light of emitters at the point p with the normal n from one point
+    /// sampled on one of them. The estimate is measured like PointLight intensity: the irradiance
+    /// divided by PI, so a white diffuse surface reflects it unchanged
+    pub fn sample_emitters(
+        &self,
+        emitters: &[RAIIDrawable],
+        p: &Vector,
+        n: &Vector,
+        sampler: &mut Sampler,
+    ) -> Color {
+        match self.sample_emitter(emitters, p, sampler) {
+            Some((l, li)) if utils::dot(&l, n) > 0.0 => li * (utils::dot(&l, n) / PI),
+            _ => Color::black(),
+        }
+    }
+
+    /// Shades a hit using given computations information: the Phong shading of every light source
+    /// (point and directional, dimmed by the media between them and the hit)
+    /// and the emission of the surface. Subsurface scattering replaces the diffuse term
+    /// of the lights outside of the object
     pub fn shade_hit(&self, info: Computations) -> Color {
-        // todo!("Support multiple light sources");
-        if self.sources.len() != 1 {
-            panic!("World does not support multiple sources, or no sources were provided");
+        let obj = info.obj.borrow();
+        let subsurface = obj.get_material().subsurface.filter(|_| !info.inside);
+        let without_diffuse;
+        let m = if subsurface.is_some() {
+            let mut m = obj.get_material().clone();
+            m.diffuse = 0.0;
+            without_diffuse = m;
+            &without_diffuse
+        } else {
+            obj.get_material()
+        };
+
+        let mut res = m.emission;
+        if let Some(ss) = subsurface {
+            res = res + self.subsurface_light(&ss, &info);
+        }
+        for light in self.sources.iter() {
+            // determine how much of the light reaches the point
+            let tr = self.transmittance(&info.over_p, &light.pos);
+            res = res + dimmed(|shadowed| light.shade(m, &info.p, &info.e, &info.n, shadowed), tr);
+        }
+        for light in self.directional.iter() {
+            let tr = self.transmittance_to(&info.over_p, &light.dir);
+            res = res + dimmed(|shadowed| light.shade(m, &info.p, &info.e, &info.n, shadowed), tr);
         }
+        res
+    }
 
-        // determine whether the point is shadowed
-        let shadowed: bool = self.is_shadowed(&info.over_p);
+    /// Returns the light that leaves a hit through subsurface scattering, gathered from the
+    /// point and directional lights at SUBSURFACE_SAMPLES probes, whose random numbers are
+    /// derived from the hit point so the shading stays reproducible
+    fn subsurface_light(&self, ss: &Subsurface, info: &Computations) -> Color {
+        let p = &info.p;
+        let mut sampler = Sampler::hashed(&[p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]);
+
+        let mut res = Color::black();
+        for _ in 0..SUBSURFACE_SAMPLES {
+            if let Some((q, weight)) = ss.probe(info, &mut sampler) {
+                res = res + weight * self.irradiance(&q) * (1.0 / PI);
+            }
+        }
+        res * (1.0 / SUBSURFACE_SAMPLES as f64)
+    }
 
-        return self.sources[0].shade(
-            info.obj.borrow().get_material(),
-            &info.p,
-            &info.e,
-            &info.n,
-            shadowed,
-        );
+    /// Returns the irradiance of a hit from the point and directional lights (a light
+    /// of intensity I delivers PI * I * cos), dimmed by the media between them and the hit
+    pub fn irradiance(&self, info: &Computations) -> Color {
+        let mut res = Color::black();
+        for light in self.sources.iter() {
+            let cos = utils::dot(&(light.pos - info.over_p).normalize(), &info.n);
+            if cos > 0.0 {
+                let tr = self.transmittance(&info.over_p, &light.pos);
+                res = res + light.intensity() * tr * (PI * cos);
+            }
+        }
+        for light in self.directional.iter() {
+            let cos = utils::dot(&light.dir, &info.n);
+            if cos > 0.0 {
+                let tr = self.transmittance_to(&info.over_p, &light.dir);
+                res = res + light.intensity() * tr * (PI * cos);
+            }
+        }
+        res
     }
 
     /// Calculate color in the World when the Ray is travelling
     pub fn calc(&self, r: &Ray, bg: &Color) -> Color {
         // todo!("Hit returns &I, so for performance purposes it can take the ownership, so that clone is not necessary.")
         let xs = self.intersect(&r);
-        let hit = xs.hit();
+        let hit = self.surface_hit(&xs);
 
-        match hit {
-            Some(i) => {
-                return self.shade_hit(Computations::new(i.clone(), r));
+        let (t, res) = match hit {
+            Some(i) => (i.t, self.shade_hit(Computations::new(i.clone(), r))),
+            None => (f64::INFINITY, self.background(&r.direction, bg)), // ray hit nothing.
+        };
+
+        // the light scattered by the media on the way, and the light that passes through them
+        let (scattered, tr) = self.media(r, t);
+        scattered + tr * res
+    }
+}
+
+/// Returns the shading of a light source whose light is dimmed by the transmittance tr:
+/// shade(true) is the shading of a shadowed point (the ambient term), shade(false) of a lit one
+fn dimmed<F: Fn(bool) -> Color>(shade: F, tr: Color) -> Color {
+    if tr == Color::black() {
+        return shade(true);
+    }
+    if tr == Color::white() {
+        return shade(false);
+    }
+    let ambient = shade(true);
+    ambient + (shade(false) - ambient) * tr
+}
+
+/// Serializable form of the World
+#[derive(Serialize, Deserialize)]
+struct WorldDesc {
+    points: Vec<Point>,
+    objects: Vec<ShapeKind>,
+    sources: Vec<PointLight>,
+    #[serde(default)]
+    directional: Vec<DirectionalLight>,
+    #[serde(default)]
+    fog: Option<Fog>,
+    #[serde(default)]
+    environment: Option<EnvironmentDesc>,
+}
+
+impl Serialize for World {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut objects = Vec::with_capacity(self.objects.len());
+        for obj in self.objects.iter() {
+            let obj = obj.borrow();
+            match obj.to_kind() {
+                Some(kind) => objects.push(kind),
+                None => {
+                    return Err(ser::Error::custom(format!(
+                        "World object {:?} cannot be serialized",
+                        obj
+                    )))
+                }
             }
-            None => bg.clone(), // ray hit nothing.
         }
+
+        let environment = match &self.environment {
+            Some(env) => Some(env.desc().ok_or_else(|| {
+                ser::Error::custom("World environment was not loaded from a file and cannot be serialized")
+            })?),
+            None => None,
+        };
+
+        WorldDesc {
+            points: self.points.clone(),
+            objects,
+            sources: self.sources.iter().map(|s| (**s).clone()).collect(),
+            directional: self.directional.clone(),
+            fog: self.fog,
+            environment,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for World {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let desc = WorldDesc::deserialize(deserializer)?;
+
+        let mut world = World::new();
+        for p in desc.points {
+            world.add_point(p);
+        }
+        for obj in desc.objects {
+            world.add_obj(obj.wrap());
+        }
+        for src in desc.sources {
+            world.add_src(src.wrap_box());
+        }
+        for light in desc.directional {
+            world.add_directional(light);
+        }
+        world.fog = desc.fog;
+        if let Some(env) = desc.environment {
+            world.environment = Some(env.build().map_err(de::Error::custom)?);
+        }
+
+        Ok(world)
     }
 }
 
@@ -309,6 +1524,7 @@ impl Default for World {
 }
  
 /// Implements Canvas where objects are drawn. Canvas can be converted to PPM format to be visualized.
+#[derive(Debug, Clone)]
 pub struct Canvas {
     pub width: usize,
     pub height: usize,
@@ -342,51 +1558,102 @@ impl Canvas {
         self.width * y + x
     }
 
-    /// Converts Canvas to ppm format and writes it to the ppm file in img directory.
-    pub fn to_ppm(&self, filename: &str) {
-        // insert PPM flavor, width, and height
-        let mut ppm = String::new();
-        ppm.push_str(format!("P3\n{} {}\n255\n", self.width, self.height).as_str());
+    /// Returns a row of pixels at a given y-coordinate
+    pub fn row(&self, y: usize) -> &[Color] {
+        let start = self.cc(0, y);
+        &self.grid[start..start + self.width]
+    }
+
+    /// Returns a mutable row of pixels at a given y-coordinate
+    pub fn row_mut(&mut self, y: usize) -> &mut [Color] {
+        let start = self.cc(0, y);
+        &mut self.grid[start..start + self.width]
+    }
+
+    /// Streams Canvas in the given PPM flavor into any sink, row by row
+    pub fn write_ppm<W: Write>(&self, sink: W, format: PpmFormat) -> Result<(), ImageError> {
+        self.write_image(sink, ImageFormat::Ppm(format))
+    }
+
+    /// Streams Canvas in the given image format into any sink, row by row
+    pub fn write_image<W: Write>(&self, sink: W, format: ImageFormat) -> Result<(), ImageError> {
+        self.write_image_with(sink, format, &PostProcess::default())
+    }
+
+    /// Streams Canvas in the given image format into any sink, passing every row through the post-processing stage
+    pub fn write_image_with<W: Write>(
+        &self,
+        sink: W,
+        format: ImageFormat,
+        post: &PostProcess,
+    ) -> Result<(), ImageError> {
+        let mut writer = ImageWriter::new(sink, self.width, self.height, format)?;
+        let mut buf = Vec::with_capacity(self.width);
 
-        // write pixels to ppm
-        let mut buf = String::new();
         for i in 0..self.height {
-            buf.clear();
-            for j in 0..self.width {
-                buf.push_str(self[[j, i]].fmt().as_str());
-                buf.push(' ');
-            }
-            ppm.push_str(buf.trim());
-            ppm.push('\n');
+            let y = if writer.bottom_up() {
+                self.height - 1 - i
+            } else {
+                i
+            };
+
+            post.apply_row(self.row(y), &mut buf);
+            writer.write_row(&buf)?;
         }
-        ppm = ppm.trim().to_owned();
 
-        // load ppm string into a file on a given path (dir/filename)
-        // dir must be 'img'
-        // filename should have .ppm extension to be displayed correctly
-        match self.ppm_to_file("img", filename, ppm.as_bytes()) {
-            Ok(_) => (),
-            Err(e) => panic!("{}", e),
-        }
+        writer.finish()?;
+        Ok(())
     }
 
-    /// Writes buffer (PPM-format) to the dir/filename.ppm
-    fn ppm_to_file(&self, dir: &str, filename: &str, buf: &[u8]) -> Result<(), &'static str> {
-        // open file to read
-        let path = match PathBuf::from(dir).join(filename).to_str() {
-            Some(res) => String::from(res),
-            None => return Err("Canvas.ppm_to_file(): Unable to convert PathBuf to &str"),
+    /// Reads a linear float image (.pfm or .hdr, chosen by the file extension) into a new Canvas
+    pub fn read_image<P: AsRef<Path>>(path: P) -> Result<Canvas, ImageError> {
+        let path = path.as_ref();
+        let format = ImageFormat::detect(path)?;
+
+        let file = File::open(path).map_err(|e| ImageError::from(e).at(path))?;
+        let src = BufReader::new(file);
+        let res = match format {
+            ImageFormat::Pfm => image::read_pfm(src),
+            ImageFormat::Hdr => image::read_hdr(src),
+            _ => return Err(ImageError::UnsupportedFormat(path.to_path_buf())),
         };
 
-        let mut file = match File::create(path) {
-            Ok(file) => file,
-            Err(_) => return Err("Canvas.ppm_to_file(): Could not open the file"),
-        };
+        res.map_err(|e| ImageError::from(e).at(path))
+    }
 
-        match file.write(buf) {
-            Ok(_) => return Ok(()),
-            Err(_) => return Err("Canvas.ppm_to_file(): Could not write to the file"),
-        }
+    /// Writes Canvas to a file, the format is chosen by the file extension (.ppm, .png, .pfm or .hdr)
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let format = ImageFormat::detect(path.as_ref())?;
+        self.save_as(path, format)
+    }
+
+    /// Writes Canvas in a given image format to a file
+    pub fn save_as<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> Result<(), ImageError> {
+        self.save_with(path, format, &PostProcess::default())
+    }
+
+    /// Writes Canvas in a given image format to a file, using the given post-processing stage
+    pub fn save_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: ImageFormat,
+        post: &PostProcess,
+    ) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| ImageError::from(e).at(path))?;
+
+        self.write_image_with(BufWriter::new(file), format, post)
+            .map_err(|e| e.at(path))
+    }
+
+    /// Converts Canvas to plain ppm format (P3) and writes it to a file
+    pub fn to_ppm<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        self.to_ppm_as(path, PpmFormat::default())
+    }
+
+    /// Converts Canvas to a given ppm format and writes it to a file
+    pub fn to_ppm_as<P: AsRef<Path>>(&self, path: P, format: PpmFormat) -> Result<(), ImageError> {
+        self.save_as(path, ImageFormat::Ppm(format))
     }
 }
 
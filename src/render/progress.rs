@@ -0,0 +1,116 @@
+//! Contains the hooks of a running render: progress observers and the cancellation token
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Canvas;
+
+/// Snapshot of the progress of a render:
+/// done: number of finished work items (tiles, or passes of a progressive render)
+/// total: number of all work items
+/// elapsed: time since the render started
+/// eta: estimated remaining time (None until the first item is finished)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Creates a Progress, estimating the remaining time from the finished items
+    pub fn new(done: usize, total: usize, elapsed: Duration) -> Self {
+        let eta = if done == 0 {
+            None
+        } else {
+            let left = total.saturating_sub(done) as f64;
+            Some(elapsed.mul_f64(left / done as f64))
+        };
+
+        Self {
+            done,
+            total,
+            elapsed,
+            eta,
+        }
+    }
+
+    /// Returns the finished part of the render in 0..1
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+}
+
+/// Receives the progress of a render after every finished work item
+pub trait RenderObserver {
+    fn progress(&mut self, p: &Progress);
+}
+
+impl<F: FnMut(&Progress)> RenderObserver for F {
+    fn progress(&mut self, p: &Progress) {
+        self(p)
+    }
+}
+
+/// Receives the canvas with the current average and the progress after every progressive pass
+/// (the progress is counted in samples per pixel, with total 0 when there is no sample target)
+pub trait PassObserver {
+    fn pass(&mut self, cv: &Canvas, p: &Progress);
+}
+
+impl<F: FnMut(&Canvas, &Progress)> PassObserver for F {
+    fn pass(&mut self, cv: &Canvas, p: &Progress) {
+        self(cv, p)
+    }
+}
+
+/// Observer that ignores the progress
+pub struct NoProgress;
+
+impl RenderObserver for NoProgress {
+    fn progress(&mut self, _p: &Progress) {}
+}
+
+impl PassObserver for NoProgress {
+    fn pass(&mut self, _cv: &Canvas, _p: &Progress) {}
+}
+
+/// Shared flag used to abort a running render. Clones refer to the same flag,
+/// so one clone can be handed to a GUI or a signal handler while the render checks another
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the render to stop after the current work item
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the render was asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+/// Result of a render that can be cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderStatus {
+    /// Every pixel was rendered
+    Completed,
+
+    /// The render was cancelled, the Canvas holds the work items finished until then
+    Cancelled,
+}
@@ -0,0 +1,105 @@
+//! Contains the accumulation buffer of progressive rendering, where whole-image passes
+//! of one sample per pixel are added until a sample count or a time budget is reached
+
+use std::time::Duration;
+
+use super::Canvas;
+use crate::math::Color;
+
+/// Floating-point buffer of per-pixel sample sums:
+/// width, height: dimensions of the image in pixels
+/// samples: number of passes (samples per pixel) added so far
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accumulator {
+    pub width: usize,
+    pub height: usize,
+    samples: usize,
+    sums: Vec<Color>,
+}
+
+impl Accumulator {
+    /// Creates an empty Accumulator
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            samples: 0,
+            sums: vec![Color::black(); width * height],
+        }
+    }
+
+    /// Returns the number of samples per pixel accumulated so far
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Adds a whole pass of one sample per pixel (in row-major order)
+    pub fn add_pass(&mut self, pass: &[Color]) {
+        assert_eq!(
+            pass.len(),
+            self.sums.len(),
+            "Accumulator.add_pass(): the pass does not match the buffer"
+        );
+
+        for (sum, c) in self.sums.iter_mut().zip(pass) {
+            *sum = *sum + *c;
+        }
+        self.samples += 1;
+    }
+
+    /// Returns the average of the pixel (x, y)
+    pub fn average(&self, x: usize, y: usize) -> Color {
+        if self.samples == 0 {
+            return Color::black();
+        }
+        self.sums[self.width * y + x] * (1.0 / self.samples as f64)
+    }
+
+    /// Writes the current average into a canvas of the same dimensions
+    pub fn resolve(&self, cv: &mut Canvas) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                cv[[x, y]] = self.average(x, y);
+            }
+        }
+    }
+}
+
+/// Limits of a progressive render, which stops at whichever is reached first:
+/// samples: target number of samples per pixel
+/// time: time budget, checked between passes (so the last pass may end after it)
+/// Without any limit the render runs until it is cancelled
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    pub samples: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+impl Budget {
+    /// Stops at a target number of samples per pixel
+    pub fn samples(samples: usize) -> Self {
+        Self {
+            samples: Some(samples),
+            time: None,
+        }
+    }
+
+    /// Stops when the time budget is spent
+    pub fn time(time: Duration) -> Self {
+        Self {
+            samples: None,
+            time: Some(time),
+        }
+    }
+
+    /// Adds a time budget to the limits
+    pub fn with_time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// Returns true if a render with the given samples per pixel and elapsed time is done
+    pub fn reached(&self, samples: usize, elapsed: Duration) -> bool {
+        self.samples.is_some_and(|s| samples >= s) || self.time.is_some_and(|t| elapsed >= t)
+    }
+}
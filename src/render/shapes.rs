@@ -2,6 +2,8 @@
 //! Each specific shape has a "shape" field that contains general
 //! functionality of each Drawable object
 
+use serde::{Deserialize, Serialize};
+
 use super::core::*;
 
 use crate::math::{utils, Vector, utils::EPSILON};
@@ -9,7 +11,7 @@ use crate::{math, tvalues};
 
 // begin Sphere ===========================================================================================
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sphere {
     shape: Shape,
 
@@ -57,6 +59,21 @@ impl Drawable for Sphere {
         // otherwise return an empty vector
         tvalues!()
     }
+
+    /// Samples the sphere uniformly by area (its surface is |p - c|^2 = r, like in local_intersect)
+    fn local_sample(&self, u: (f64, f64)) -> Option<SurfaceSample> {
+        let radius = self.r.sqrt();
+        let z = 1.0 - 2.0 * u.0;
+        let rho = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * u.1;
+        let n = utils::vector(rho * phi.cos(), rho * phi.sin(), z);
+
+        Some(SurfaceSample {
+            p: self.c + n * radius,
+            n,
+            pdf: 1.0 / (4.0 * std::f64::consts::PI * self.r),
+        })
+    }
     
     fn get_shape(&self) -> &Shape {
         &self.shape
@@ -65,13 +82,17 @@ impl Drawable for Sphere {
     fn get_shape_mut(&mut self) -> &mut Shape {
         &mut self.shape
     }
+
+    fn to_kind(&self) -> Option<ShapeKind> {
+        Some(ShapeKind::Sphere(self.clone()))
+    }
 }
 
 // end Sphere ===========================================================================================
 
 // begin Point ===========================================================================================
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Point {
     shape: Shape,
 
@@ -125,7 +146,7 @@ impl Drawable for Point {
 // begin Plane ===========================================================================================
 
 /// Plane that (by default) extends in x- and z-directions.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plane {
     shape: Shape,
 }
@@ -161,6 +182,31 @@ impl Drawable for Plane {
         let t = -obj_r.origin.y / obj_r.direction.y;
         return vec![t] as Tvalues;
     }
+
+    fn to_kind(&self) -> Option<ShapeKind> {
+        Some(ShapeKind::Plane(self.clone()))
+    }
  }
 
 // end Plane ===========================================================================================
+
+// begin ShapeKind ===========================================================================================
+
+/// Serializable form of every Drawable shape that can be placed into the World
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ShapeKind {
+    Sphere(Sphere),
+    Plane(Plane),
+}
+
+impl ShapeKind {
+    /// Wraps the shape into RAIIDrawable
+    pub fn wrap(self) -> RAIIDrawable {
+        match self {
+            ShapeKind::Sphere(s) => s.wrap(),
+            ShapeKind::Plane(p) => p.wrap(),
+        }
+    }
+}
+
+// end ShapeKind ===========================================================================================
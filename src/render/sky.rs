@@ -0,0 +1,220 @@
+//! Contains the procedural daylight sky of A. J. Preetham, P. Shirley and B. Smits
+//! ("A Practical Analytic Model for Daylight", 1999), parameterized by the direction
+//! of the sun and the turbidity of the atmosphere, and the matching sun light
+
+use std::f64::consts::PI;
+
+use super::core::DirectionalLight;
+use super::environment::{uv_to_direction, Environment, EnvironmentDesc, EnvironmentMap};
+use super::spectrum::xyz_to_rgb;
+use super::Canvas;
+use crate::math::{utils, Color, Vector};
+
+/// Converts the luminance of the model (in kcd/m^2) into radiance, so a clear sky at noon
+/// has a radiance of about 1
+const SKY_SCALE: f64 = 0.1;
+
+/// Intensity of the sun at the zenith of a perfectly clear atmosphere
+const SUN_INTENSITY: f64 = 4.0;
+
+/// Resolution of the table used to importance-sample the sky (longitude x latitude)
+const TABLE_SIZE: (usize, usize) = (64, 32);
+
+/// Wavelengths (in micrometers) of the red, green and blue channels of the sun transmittance
+const WAVELENGTHS: [f64; 3] = [0.65, 0.57, 0.475];
+
+/// Lowest turbidity of the model (a very clear sky)
+pub const MIN_TURBIDITY: f64 = 1.7;
+
+/// Highest turbidity of the model (a hazy sky)
+pub const MAX_TURBIDITY: f64 = 10.0;
+
+/// Daylight sky lit by the sun. Below the horizon the sky is black, the ground is expected
+/// to be modeled by the scene:
+/// sun: unit direction towards the sun (+y is the zenith)
+/// turbidity: haziness of the atmosphere, from 1.7 (very clear) to 10 (hazy)
+/// intensity: scale of the radiance of the sky and of the sun
+#[derive(Debug, Clone)]
+pub struct Sky {
+    sun: Vector,
+    turbidity: f64,
+    pub intensity: f64,
+
+    // luminance and chromaticity (Y, x, y) at the zenith, their Perez coefficients
+    // and the value of the Perez function at the zenith
+    zenith: [f64; 3],
+    perez: [[f64; 5]; 3],
+    perez_zenith: [f64; 3],
+
+    // radiance of the sky tabulated for importance sampling
+    table: EnvironmentMap,
+}
+
+impl Sky {
+    /// Creates a Sky with the sun in the direction sun (towards the sun) and a given turbidity,
+    /// which is clamped to the range of the model. A sun below the horizon lights the sky
+    /// like a sun on the horizon
+    pub fn new(sun: Vector, turbidity: f64) -> Self {
+        let sun = sun.normalize();
+        let t = turbidity.clamp(MIN_TURBIDITY, MAX_TURBIDITY);
+
+        // the model is defined for the sun above the horizon
+        let theta_s = sun.y.clamp(0.0, 1.0).acos();
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let (t2, s, s2, s3) = (t * t, theta_s, theta_s * theta_s, theta_s.powi(3));
+        let x = t2 * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let y = t2 * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        let mut perez_zenith = [0.0; 3];
+        for (f, c) in perez_zenith.iter_mut().zip(perez.iter()) {
+            *f = perez_f(c, 0.0, theta_s);
+        }
+
+        let mut sky = Self {
+            sun,
+            turbidity: t,
+            intensity: 1.0,
+            zenith: [luminance, x, y],
+            perez,
+            perez_zenith,
+            table: EnvironmentMap::new(&Canvas::new(1, 1, Color::black())),
+        };
+
+        let (w, h) = TABLE_SIZE;
+        let mut cv = Canvas::new(w, h, Color::black());
+        for y in 0..h {
+            for x in 0..w {
+                let (u, v) = ((x as f64 + 0.5) / w as f64, (y as f64 + 0.5) / h as f64);
+                cv[[x, y]] = sky.sky_radiance(&uv_to_direction(u, v));
+            }
+        }
+        sky.table = EnvironmentMap::new(&cv);
+        sky
+    }
+
+    /// Returns the unit direction towards the sun
+    pub fn sun_direction(&self) -> Vector {
+        self.sun
+    }
+
+    /// Returns the turbidity of the atmosphere
+    pub fn turbidity(&self) -> f64 {
+        self.turbidity
+    }
+
+    /// Returns the sun matching the sky: a DirectionalLight whose color is the sunlight
+    /// transmitted through the atmosphere (black when the sun is below the horizon)
+    pub fn sun(&self) -> DirectionalLight {
+        DirectionalLight::new(
+            self.sun,
+            self.sun_transmittance() * (SUN_INTENSITY * self.intensity),
+        )
+    }
+
+    /// Returns the fraction of sunlight in the red, green and blue channels that passes through
+    /// the atmosphere (Rayleigh scattering by molecules and Angstrom's formula for aerosols)
+    fn sun_transmittance(&self) -> Color {
+        if self.sun.y <= 0.0 {
+            return Color::black();
+        }
+
+        // relative optical mass of the air along the path of the sunlight
+        let theta = self.sun.y.acos();
+        let degrees = theta.to_degrees();
+        let mass = 1.0 / (theta.cos() + 0.15 * (93.885 - degrees).powf(-1.253));
+
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let [r, g, b] = WAVELENGTHS.map(|l: f64| {
+            let rayleigh = (-0.008735 * l.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * l.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        });
+        utils::color(r, g, b)
+    }
+
+    /// Returns the radiance of the sky in the direction dir, without the intensity
+    fn sky_radiance(&self, dir: &Vector) -> Color {
+        let dir = dir.normalize();
+        if dir.y <= 0.0 {
+            return Color::black();
+        }
+
+        let theta = dir.y.acos();
+        let gamma = utils::dot(&dir, &self.sun).clamp(-1.0, 1.0).acos();
+        let [lum, x, y] = [0, 1, 2]
+            .map(|i| self.zenith[i] * perez_f(&self.perez[i], theta, gamma) / self.perez_zenith[i]);
+        xyy_to_rgb(lum, x, y) * SKY_SCALE
+    }
+}
+
+impl Environment for Sky {
+    fn radiance(&self, dir: &Vector) -> Color {
+        self.sky_radiance(dir) * self.intensity
+    }
+
+    fn sample(&self, u: (f64, f64)) -> (Vector, Color, f64) {
+        let (dir, _, pdf) = self.table.sample(u);
+        if pdf <= 0.0 {
+            return (dir, Color::black(), 0.0);
+        }
+        (dir, self.radiance(&dir) * (1.0 / pdf), pdf)
+    }
+
+    fn pdf(&self, dir: &Vector) -> f64 {
+        self.table.pdf(dir)
+    }
+
+    fn desc(&self) -> Option<EnvironmentDesc> {
+        Some(EnvironmentDesc::Sky {
+            sun: self.sun,
+            turbidity: self.turbidity,
+            intensity: self.intensity,
+        })
+    }
+}
+
+/// Returns the Perez sky function for the angle theta from the zenith and the angle gamma
+/// from the sun
+fn perez_f(c: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *c;
+    let cos_theta = theta.cos().max(1e-3);
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Converts a luminance Y with the chromaticity (x, y) into linear sRGB
+fn xyy_to_rgb(lum: f64, x: f64, y: f64) -> Color {
+    if y <= 0.0 {
+        return Color::black();
+    }
+    let c = xyz_to_rgb(x / y * lum, lum, (1.0 - x - y) / y * lum);
+    utils::color(c.r.max(0.0), c.g.max(0.0), c.b.max(0.0))
+}
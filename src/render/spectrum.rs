@@ -0,0 +1,275 @@
+//! Contains the spectral rendering mode: every camera sample carries one wavelength (in nm),
+//! at which dispersive dielectrics refract, and its RGB radiance is turned into the spectral
+//! radiance at that wavelength and back into RGB through the CIE color matching functions.
+//!
+//! Every sample is converted into RGB by the integrator, instead of accumulating XYZ and
+//! converting the pixels when the Canvas is written, so that tiles, checkpoints, progressive
+//! passes and output variables keep working with RGB. XYZ to RGB is linear and the samples
+//! are summed without clamping (a single sample may have negative channels), so the pixels
+//! are the same as with the conversion at write time.
+//!
+//! RGB colors are upsampled to spectra with the method of B. Smits ("An RGB-to-Spectrum
+//! Conversion for Reflectances", 1999), and the color matching functions are the multi-lobe
+//! Gaussian fit of C. Wyman, P.-P. Sloan and P. Shirley ("Simple Analytic Approximations to the
+//! CIE XYZ Color Matching Functions", 2013)
+
+use serde::{Deserialize, Serialize};
+
+use super::core::{RAIIDrawable, Ray};
+use super::integrator::{Integrator, SharedIntegrator};
+use super::World;
+use crate::math::sampler::Sampler;
+use crate::math::{utils, Color};
+
+/// Shortest wavelength sampled by the spectral mode (in nm)
+pub const MIN_WAVELENGTH: f64 = 360.0;
+
+/// Longest wavelength sampled by the spectral mode (in nm)
+pub const MAX_WAVELENGTH: f64 = 830.0;
+
+/// Wavelength at which the index of refraction of a dispersive material is given when no other
+/// is (the sodium D line, in nm)
+pub const REFERENCE_WAVELENGTH: f64 = 589.3;
+
+/// Largest index of refraction a Dispersion may reach between MIN_WAVELENGTH and MAX_WAVELENGTH
+const MAX_IOR: f64 = 10.0;
+
+/// Names of the glasses with built-in dispersion
+pub const GLASSES: [&str; 3] = ["bk7", "fused-silica", "sf11"];
+
+/// Range of the bins of the Smits spectra (in nm)
+const SMITS_RANGE: (f64, f64) = (380.0, 720.0);
+
+/// Spectra of Smits' method, in 10 bins between 380 and 720 nm
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Wavelength-dependent index of refraction of a dielectric (wavelengths in micrometers):
+/// Cauchy: n = a + b / l^2
+/// Sellmeier: n^2 = 1 + sum of b[i] l^2 / (l^2 - c[i]) (c in square micrometers)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Dispersion {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    /// Returns the Sellmeier dispersion of a glass in GLASSES
+    pub fn glass(name: &str) -> Option<Self> {
+        let (b, c) = match name {
+            "bk7" => (
+                [1.03961212, 0.231792344, 1.01046945],
+                [0.00600069867, 0.0200179144, 103.560653],
+            ),
+            "fused-silica" => (
+                [0.6961663, 0.4079426, 0.8974794],
+                [0.004679148, 0.01351206, 97.934],
+            ),
+            "sf11" => (
+                [1.73759695, 0.313747346, 1.89878101],
+                [0.013188707, 0.0623068142, 155.23629],
+            ),
+            _ => return None,
+        };
+        Some(Dispersion::Sellmeier { b, c })
+    }
+
+    /// Returns the index of refraction at a wavelength (in nm), at least 1
+    pub fn ior(&self, wavelength: f64) -> f64 {
+        self.unclamped_ior(wavelength).max(1.0)
+    }
+
+    /// Returns the index of refraction given by the formula at a wavelength (in nm),
+    /// NaN where the Sellmeier formula has no real root
+    fn unclamped_ior(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f64 = b.iter().zip(c.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    /// Checks that the index of refraction is finite and between 1 and MAX_IOR at every
+    /// sampled wavelength, and that no Sellmeier pole lies between them
+    pub fn validate(&self) -> Result<(), String> {
+        if let Dispersion::Sellmeier { c, .. } = self {
+            let l2 = |wavelength: f64| (wavelength / 1000.0).powi(2);
+            let range = l2(MIN_WAVELENGTH)..=l2(MAX_WAVELENGTH);
+            if let Some(c) = c.iter().find(|c| range.contains(c)) {
+                return Err(format!(
+                    "the Sellmeier term c = {} has a pole between {} and {} nm",
+                    c, MIN_WAVELENGTH, MAX_WAVELENGTH
+                ));
+            }
+        }
+
+        let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+        for i in 0..=steps {
+            let wavelength = MIN_WAVELENGTH + i as f64;
+            let ior = self.unclamped_ior(wavelength);
+            if !(1.0..=MAX_IOR).contains(&ior) {
+                return Err(format!(
+                    "the index of refraction at {} nm is {}, outside 1..{}",
+                    wavelength, ior, MAX_IOR
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Samples a wavelength from the uniform random number u, in proportion to the sensitivity
+/// of the eye. Returns the wavelength (in nm) and its density
+pub fn sample_wavelength(u: f64) -> (f64, f64) {
+    let wavelength = 538.0 - 138.888889 * (0.85691062 - 1.82750197 * u).atanh();
+    let wavelength = wavelength.clamp(MIN_WAVELENGTH, MAX_WAVELENGTH);
+    (wavelength, wavelength_pdf(wavelength))
+}
+
+/// Returns the density with which sample_wavelength() chooses a wavelength (in nm)
+pub fn wavelength_pdf(wavelength: f64) -> f64 {
+    if !(MIN_WAVELENGTH..=MAX_WAVELENGTH).contains(&wavelength) {
+        return 0.0;
+    }
+    0.0039398042 / (0.0072 * (wavelength - 538.0)).cosh().powi(2)
+}
+
+/// Returns the CIE 1931 color matching functions (x, y, z) at a wavelength (in nm)
+pub fn cie_xyz(wavelength: f64) -> (f64, f64, f64) {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let t = (wavelength - mu) / if wavelength < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    (
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// Converts CIE XYZ into linear sRGB (D65 white), without clamping
+pub fn xyz_to_rgb(x: f64, y: f64, z: f64) -> Color {
+    utils::color(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    )
+}
+
+/// Returns the value at a wavelength (in nm) of the spectrum that Smits' method
+/// upsamples a linear RGB color to
+pub fn rgb_to_spectrum(c: &Color, wavelength: f64) -> f64 {
+    let (lo, hi) = SMITS_RANGE;
+    let bin = ((wavelength - lo) / (hi - lo) * 10.0).clamp(0.0, 9.0) as usize;
+    let (r, g, b) = (c.r, c.g, c.b);
+
+    // the smallest channel is white, the middle one adds a secondary color,
+    // the largest one a primary color
+    let (min, mid, max, secondary, primary) = if r <= g && r <= b {
+        if g <= b {
+            (r, g, b, &SMITS_CYAN, &SMITS_BLUE)
+        } else {
+            (r, b, g, &SMITS_CYAN, &SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        if r <= b {
+            (g, r, b, &SMITS_MAGENTA, &SMITS_BLUE)
+        } else {
+            (g, b, r, &SMITS_MAGENTA, &SMITS_RED)
+        }
+    } else if r <= g {
+        (b, r, g, &SMITS_YELLOW, &SMITS_GREEN)
+    } else {
+        (b, g, r, &SMITS_YELLOW, &SMITS_RED)
+    };
+    min * SMITS_WHITE[bin] + (mid - min) * secondary[bin] + (max - mid) * primary[bin]
+}
+
+/// Returns the linear RGB color of a spectrum that is zero except at one wavelength (in nm),
+/// where it has the given value, before the white balance
+fn wavelength_to_rgb(value: f64, wavelength: f64) -> Color {
+    let (x, y, z) = cie_xyz(wavelength);
+    xyz_to_rgb(x, y, z) * value
+}
+
+/// Returns the linear RGB color of the constant spectrum 1 before the white balance, which
+/// scales the channels so that this spectrum becomes white
+pub fn white_point() -> Color {
+    let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+    (0..steps).fold(Color::black(), |sum, i| {
+        sum + wavelength_to_rgb(1.0, MIN_WAVELENGTH + i as f64 + 0.5)
+    })
+}
+
+/// Integrator of the spectral mode, which wraps another integrator. Every sample traces the
+/// camera ray at one wavelength, sampled in proportion to the sensitivity of the eye: dielectrics
+/// with a Dispersion refract it with their index of refraction at that wavelength, which splits
+/// white light into colors. The RGB radiance computed by the inner integrator is upsampled to
+/// its value at the wavelength and converted back into RGB through the color matching functions,
+/// so the samples of a pixel average to its color
+#[derive(Debug, Clone)]
+pub struct Spectral {
+    pub inner: SharedIntegrator,
+    white: Color,
+}
+
+impl Spectral {
+    /// Creates the spectral mode of an integrator
+    pub fn new(inner: SharedIntegrator) -> Self {
+        Self {
+            inner,
+            white: white_point(),
+        }
+    }
+}
+
+impl Integrator for Spectral {
+    fn name(&self) -> &'static str {
+        "spectral"
+    }
+
+    fn li_with(
+        &self,
+        world: &World,
+        emitters: &[RAIIDrawable],
+        r: &Ray,
+        bg: &Color,
+        sampler: &mut Sampler,
+        max_depth: usize,
+    ) -> Color {
+        let (wavelength, pdf) = sample_wavelength(sampler.next_f64());
+        if pdf <= 0.0 {
+            return Color::black();
+        }
+
+        let r = r.clone().with_wavelength(Some(wavelength));
+        let li = self
+            .inner
+            .li_with(world, emitters, &r, bg, sampler, max_depth);
+        let rgb = wavelength_to_rgb(rgb_to_spectrum(&li, wavelength) / pdf, wavelength);
+        let w = &self.white;
+        utils::color(rgb.r / w.r, rgb.g / w.g, rgb.b / w.b)
+    }
+}
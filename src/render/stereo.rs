@@ -0,0 +1,145 @@
+//! Contains stereoscopic rendering: the eye cameras derived from a single Camera,
+//! and composite images of both eyes
+
+use std::path::Path;
+
+use super::image::{ImageError, ImageFormat};
+use super::tonemap::PostProcess;
+use super::{Camera, Canvas};
+
+/// One of the two eyes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// How the eyes are aimed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoMode {
+    /// Both eyes look along the view direction of the Camera (zero parallax at infinity)
+    Parallel,
+
+    /// Both eyes are turned towards the point at the convergence distance in front of the Camera
+    ToeIn,
+}
+
+/// Arrangement of the two eyes in a composite image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Left eye on the left, right eye on the right
+    SideBySide,
+
+    /// Left eye on the top, right eye on the bottom
+    OverUnder,
+}
+
+/// Stereo rig placed around a Camera:
+/// iod: interocular distance in world units
+/// convergence: distance in front of the Camera where the eyes converge (used by ToeIn)
+/// mode: how the eyes are aimed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereo {
+    pub iod: f64,
+    pub convergence: f64,
+    pub mode: StereoMode,
+}
+
+impl Stereo {
+    /// Creates a Stereo rig
+    pub fn new(iod: f64, convergence: f64, mode: StereoMode) -> Self {
+        Self {
+            iod,
+            convergence,
+            mode,
+        }
+    }
+
+    /// Returns the Camera of an eye. The eyes are moved by half of the interocular distance
+    /// to the sides of the Camera, keeping its projection, resolution and up vector
+    pub fn eye(&self, c: &Camera, eye: Eye) -> Camera {
+        let inv_view = c
+            .vtm
+            .try_inverse()
+            .expect("Cannot invert view transformation matrix in Stereo.eye()");
+
+        // the columns of the inverse view transformation are the axes of the camera in the world
+        // (x points to the left of the image) and its position
+        let left = inv_view.column(0).into_owned();
+        let up = inv_view.column(1).into_owned();
+        let forward = -inv_view.column(2).into_owned();
+        let center = inv_view.column(3).into_owned();
+
+        let side = match eye {
+            Eye::Left => 1.0,
+            Eye::Right => -1.0,
+        };
+        let from = center + left * (side * self.iod / 2.0);
+        let to = match self.mode {
+            StereoMode::Parallel => from + forward,
+            StereoMode::ToeIn => center + forward * self.convergence,
+        };
+
+        let mut res = c.clone();
+        res.set_view(from, to, up);
+        res
+    }
+}
+
+/// Rendered images of both eyes
+#[derive(Debug, Clone)]
+pub struct StereoImage {
+    pub left: Canvas,
+    pub right: Canvas,
+}
+
+impl StereoImage {
+    /// Returns both eyes composed into a single Canvas
+    pub fn compose(&self, layout: StereoLayout) -> Canvas {
+        let (w, h) = (self.left.width, self.left.height);
+        let mut res = match layout {
+            StereoLayout::SideBySide => Canvas::new(2 * w, h, self.left.bg),
+            StereoLayout::OverUnder => Canvas::new(w, 2 * h, self.left.bg),
+        };
+
+        for y in 0..h {
+            match layout {
+                StereoLayout::SideBySide => {
+                    res.row_mut(y)[..w].copy_from_slice(self.left.row(y));
+                    res.row_mut(y)[w..].copy_from_slice(self.right.row(y));
+                }
+                StereoLayout::OverUnder => {
+                    res.row_mut(y).copy_from_slice(self.left.row(y));
+                    res.row_mut(h + y).copy_from_slice(self.right.row(y));
+                }
+            }
+        }
+        res
+    }
+
+    /// Saves the composite image into a file, the format is chosen by the extension
+    pub fn save<P: AsRef<Path>>(&self, path: P, layout: StereoLayout) -> Result<(), ImageError> {
+        self.compose(layout).save(path)
+    }
+
+    /// Saves the eyes into two files, the format is chosen by the extension
+    pub fn save_eyes<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        left: P,
+        right: Q,
+    ) -> Result<(), ImageError> {
+        self.left.save(left)?;
+        self.right.save(right)
+    }
+
+    /// Saves the composite image in a given format with a post-processing stage
+    pub fn save_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        layout: StereoLayout,
+        format: ImageFormat,
+        post: &PostProcess,
+    ) -> Result<(), ImageError> {
+        self.compose(layout).save_with(path, format, post)
+    }
+}
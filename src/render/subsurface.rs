@@ -0,0 +1,139 @@
+//! Contains the approximation of subsurface scattering used for translucent materials like skin,
+//! wax and rubber: light entering the surface at one point leaves it at nearby points, spread by
+//! the normalized diffusion profile of B. Burley ("Extending the Disney BRDF to a BSDF with
+//! Integrated Subsurface Scattering", 2015).
+//!
+//! The points where the light enters are found by probe rays cast along the normal of a hit
+//! towards the surface of the same object, at a distance from the hit sampled from the profile.
+//! The surface is treated as locally flat, so thin and strongly curved parts lose some light
+
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use super::bsdf::Frame;
+use super::core::{Computations, Ray, I};
+use crate::math::sampler::Sampler;
+use crate::math::{utils, Color};
+
+/// Number of probes per hit gathered by the Phong shading
+pub const SUBSURFACE_SAMPLES: usize = 16;
+
+/// Fraction of the profile beyond the largest probe distance
+const PROFILE_CUTOFF: f64 = 1e-3;
+
+/// Subsurface scattering under a surface:
+/// mean_free_path: average distance the light travels under the surface (per channel)
+/// albedo: fraction of the entering light that leaves the surface again (per channel, 0..1),
+/// which replaces the diffuse color of the material
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Subsurface {
+    pub mean_free_path: Color,
+    pub albedo: Color,
+}
+
+impl Subsurface {
+    /// Creates a Subsurface
+    pub fn new(mean_free_path: Color, albedo: Color) -> Self {
+        Self {
+            mean_free_path,
+            albedo,
+        }
+    }
+
+    /// Returns the scale of the profile of every channel (the mean free path divided by the
+    /// fitted scaling factor of Burley)
+    fn scales(&self) -> [f64; 3] {
+        let scale = |a: f64, l: f64| {
+            let s = 1.85 - a + 7.0 * (a - 0.8).abs().powi(3);
+            (l / s).max(1e-9)
+        };
+        let (a, l) = (&self.albedo, &self.mean_free_path);
+        [scale(a.r, l.r), scale(a.g, l.g), scale(a.b, l.b)]
+    }
+
+    /// Returns the diffusion profile at the distance r from the point where the light enters
+    /// (per unit area of the surface), which integrates to the albedo over the plane
+    pub fn profile(&self, r: f64) -> Color {
+        let [dr, dg, db] = self.scales();
+        let a = &self.albedo;
+        utils::color(
+            a.r * normalized(r, dr),
+            a.g * normalized(r, dg),
+            a.b * normalized(r, db),
+        )
+    }
+
+    /// Returns the density (per unit area of the plane) with which sample_radius() chooses
+    /// a point at the distance r, the average of the profiles of the channels without the albedo
+    pub fn pdf(&self, r: f64) -> f64 {
+        self.scales().iter().map(|d| normalized(r, *d)).sum::<f64>() / 3.0
+    }
+
+    /// Samples the distance of a probe from the hit: picks a channel, then one of the two
+    /// exponentials of its profile
+    pub fn sample_radius(&self, sampler: &mut Sampler) -> f64 {
+        let scales = self.scales();
+        let d = scales[((sampler.next_f64() * 3.0) as usize).min(2)];
+        let d = if sampler.next_f64() < 0.25 {
+            d
+        } else {
+            3.0 * d
+        };
+        -(1.0 - sampler.next_f64()).ln() * d
+    }
+
+    /// Returns the largest distance of a probe from the hit
+    pub fn max_radius(&self) -> f64 {
+        let d = self.scales().iter().copied().fold(0.0, f64::max);
+        3.0 * d * (0.75 / PROFILE_CUTOFF).ln()
+    }
+
+    /// Samples a point of the surface of the hit object where the light that leaves the surface
+    /// at the hit entered it. Returns the point, seen from the outside along the probe ray,
+    /// and the weight of the sample (the profile divided by the density); None if the probe
+    /// finds no surface
+    pub fn probe(
+        &self,
+        info: &Computations,
+        sampler: &mut Sampler,
+    ) -> Option<(Computations, Color)> {
+        let (r, phi) = (self.sample_radius(sampler), 2.0 * PI * sampler.next_f64());
+        let r_max = self.max_radius();
+        if r >= r_max {
+            return None;
+        }
+
+        // the probe starts above the disk of the profile around the hit and crosses it
+        let frame = Frame::new(&info.n);
+        let offset = frame.to_world(&utils::vector(r * phi.cos(), r * phi.sin(), 0.0));
+        let h = (r_max * r_max - r * r).sqrt();
+        let probe = Ray::new(info.p + offset + info.n * h, -info.n);
+
+        // the entry point closest to the disk
+        let ts = info.obj.borrow().intersect(&probe);
+        let q = ts
+            .iter()
+            .filter(|t| **t >= 0.0 && **t <= 2.0 * h)
+            .map(|t| Computations::new(I::new(*t, info.obj.clone()), &probe))
+            .filter(|q| !q.inside)
+            .min_by(|a, b| {
+                let (da, db) = ((a.t - h).abs(), (b.t - h).abs());
+                da.partial_cmp(&db).unwrap_or(std::cmp::Ordering::Equal)
+            })?;
+
+        let pdf = self.pdf(r);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let weight = self.profile((q.p - info.p).magnitude()) * (1.0 / pdf);
+        Some((q, weight))
+    }
+}
+
+/// Returns the normalized diffusion profile with the scale d at the distance r
+/// (per unit area, integrates to one over the plane)
+fn normalized(r: f64, d: f64) -> f64 {
+    let r = r.max(1e-9 * d);
+    ((-r / d).exp() + (-r / (3.0 * d)).exp()) / (8.0 * PI * d * r)
+}
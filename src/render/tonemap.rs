@@ -0,0 +1,160 @@
+//! Contains the post-processing stage that sits between a Canvas and the image writers:
+//! exposure, tone mapping operators, and the transfer encoding of the output
+
+use std::str::FromStr;
+
+use crate::math::{utils, Color};
+
+/// Tone mapping operator that compresses linear radiance into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    /// Values are passed unchanged (LDR writers still clamp them to 0..1)
+    None,
+
+    /// Every channel is clamped to 0..1
+    Clamp,
+
+    /// Reinhard operator L / (1 + L) applied to the luminance, keeping the hue
+    Reinhard,
+
+    /// ACES filmic curve (Narkowicz's fit) applied to every channel
+    Aces,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    /// Parses none, clamp, reinhard or aces
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ToneMap::None),
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            _ => Err(format!("unknown tone map '{}'", s)),
+        }
+    }
+}
+
+/// Transfer function used to encode the tone mapped values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+    /// Values are written as they are
+    Linear,
+
+    /// sRGB opto-electronic transfer function (piecewise gamma ~2.2)
+    Srgb,
+}
+
+impl FromStr for Transfer {
+    type Err = String;
+
+    /// Parses linear or srgb
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Transfer::Linear),
+            "srgb" => Ok(Transfer::Srgb),
+            _ => Err(format!("unknown transfer '{}'", s)),
+        }
+    }
+}
+
+/// Post-processing configuration of a single output:
+/// exposure: exposure compensation in stops (every stop doubles the radiance)
+/// tone_map: tone mapping operator applied after the exposure
+/// transfer: transfer encoding applied after the tone mapping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostProcess {
+    pub exposure: f64,
+    pub tone_map: ToneMap,
+    pub transfer: Transfer,
+}
+
+impl PostProcess {
+    /// Creates a new PostProcess stage
+    pub fn new(exposure: f64, tone_map: ToneMap, transfer: Transfer) -> Self {
+        Self {
+            exposure,
+            tone_map,
+            transfer,
+        }
+    }
+
+    /// Display-referred output: exposure, ACES filmic curve and sRGB encoding
+    pub fn filmic(exposure: f64) -> Self {
+        Self::new(exposure, ToneMap::Aces, Transfer::Srgb)
+    }
+
+    /// Applies the whole stage to a single Color
+    pub fn apply(&self, c: &Color) -> Color {
+        let c = c * 2f64.powf(self.exposure);
+
+        let c = match self.tone_map {
+            ToneMap::None => c,
+            ToneMap::Clamp => utils::color(
+                c.r.clamp(0.0, 1.0),
+                c.g.clamp(0.0, 1.0),
+                c.b.clamp(0.0, 1.0),
+            ),
+            ToneMap::Reinhard => {
+                let l = luminance(&c);
+                if l <= 0.0 {
+                    Color::black()
+                } else {
+                    c * (1.0 / (1.0 + l))
+                }
+            }
+            ToneMap::Aces => utils::color(aces(c.r), aces(c.g), aces(c.b)),
+        };
+
+        match self.transfer {
+            Transfer::Linear => c,
+            Transfer::Srgb => utils::color(srgb_encode(c.r), srgb_encode(c.g), srgb_encode(c.b)),
+        }
+    }
+
+    /// Applies the whole stage to a row of pixels, storing the result in out
+    pub fn apply_row(&self, row: &[Color], out: &mut Vec<Color>) {
+        out.clear();
+        out.extend(row.iter().map(|c| self.apply(c)));
+    }
+}
+
+impl Default for PostProcess {
+    /// Keeps the values linear and unchanged (the legacy output)
+    fn default() -> Self {
+        Self::new(0.0, ToneMap::None, Transfer::Linear)
+    }
+}
+
+/// Returns relative luminance of a linear Color (Rec. 709 primaries)
+pub fn luminance(c: &Color) -> f64 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+/// ACES filmic tone curve fitted by K. Narkowicz
+fn aces(x: f64) -> f64 {
+    let x = x.max(0.0);
+    let res = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    res.clamp(0.0, 1.0)
+}
+
+/// Encodes a linear value with the sRGB transfer function
+pub fn srgb_encode(x: f64) -> f64 {
+    let x = x.max(0.0);
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an sRGB encoded value back into the linear one
+pub fn srgb_decode(x: f64) -> f64 {
+    let x = x.max(0.0);
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,66 @@
+//! JSON scene description: the serde form of the Camera, the background color and the World.
+//! Unlike the YAML format, it is meant to save scenes that were built in code, and load them back unchanged
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::SceneError;
+use crate::math::Color;
+use crate::render::{Camera, Renderer, World};
+
+/// Scene document that is written to JSON
+#[derive(Serialize)]
+struct SceneRef<'a> {
+    camera: &'a Camera,
+    background: &'a Color,
+    world: &'a World,
+}
+
+/// Scene document that is read from JSON
+#[derive(Deserialize)]
+struct SceneDoc {
+    camera: Camera,
+    background: Color,
+    world: World,
+}
+
+/// Serializes the Camera, the background color and the World of a Renderer into JSON
+pub fn to_json(r: &Renderer) -> Result<String, SceneError> {
+    let doc = SceneRef {
+        camera: r.camera(),
+        background: &r.canvas().bg,
+        world: &r.world,
+    };
+
+    serde_json::to_string_pretty(&doc).map_err(|e| SceneError::Serialize(e.to_string()))
+}
+
+/// Serializes a Renderer into a JSON file
+pub fn save_json_file<P: AsRef<Path>>(r: &Renderer, path: P) -> Result<(), SceneError> {
+    let path = path.as_ref();
+    fs::write(path, to_json(r)?).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Builds a Renderer from a JSON scene
+pub fn load_json(src: &str) -> Result<Renderer, SceneError> {
+    let doc: SceneDoc = serde_json::from_str(src)
+        .map_err(|e| SceneError::located(e.to_string(), e.line(), e.column()))?;
+
+    Ok(Renderer::with_camera(doc.world, doc.camera, doc.background))
+}
+
+/// Reads a JSON scene file and builds a Renderer from it
+pub fn load_json_file<P: AsRef<Path>>(path: P) -> Result<Renderer, SceneError> {
+    let path = path.as_ref();
+    let src = fs::read_to_string(path).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    load_json(&src)
+}
@@ -0,0 +1,89 @@
+//! Contains scene description formats and their loaders, which build a Renderer
+//! (with its World and Camera) from a file instead of hand-written Rust code
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::render::Renderer;
+
+pub mod json;
+pub mod yaml;
+
+pub use json::{load_json, load_json_file, save_json_file, to_json};
+pub use yaml::{load_yaml, load_yaml_file};
+
+/// Loads a scene file, choosing the format by its extension (.yaml, .yml or .json)
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Renderer, SceneError> {
+    let path = path.as_ref();
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("yaml") | Some("yml") => load_yaml_file(path),
+        Some("json") => load_json_file(path),
+        _ => Err(SceneError::parse(
+            None,
+            "unknown scene format (expected .yaml, .yml or .json)",
+        )),
+    }
+}
+
+/// Error returned by scene loaders
+#[derive(Debug)]
+pub enum SceneError {
+    /// The scene file could not be read
+    Io { path: PathBuf, source: io::Error },
+
+    /// The scene description is invalid
+    /// line: 1-based line of the scene file where the problem was found (if known)
+    Parse { line: Option<usize>, msg: String },
+
+    /// The scene could not be serialized
+    Serialize(String),
+}
+
+impl SceneError {
+    /// Creates a Parse error
+    pub fn parse<S: Into<String>>(line: Option<usize>, msg: S) -> Self {
+        SceneError::Parse {
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    /// Creates a Parse error from a message of a parser that appends " at line L column C" to it
+    fn located(mut msg: String, line: usize, column: usize) -> Self {
+        let suffix = format!(" at line {} column {}", line, column);
+        if let Some(pos) = msg.find(&suffix) {
+            msg.replace_range(pos..pos + suffix.len(), "");
+        }
+
+        SceneError::parse(Some(line), msg)
+    }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            SceneError::Parse {
+                line: Some(line),
+                msg,
+            } => write!(f, "line {}: {}", line, msg),
+            SceneError::Parse { line: None, msg } => write!(f, "{}", msg),
+            SceneError::Serialize(msg) => write!(f, "could not serialize the scene: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneError::Io { source, .. } => Some(source),
+            SceneError::Parse { .. } | SceneError::Serialize(_) => None,
+        }
+    }
+}
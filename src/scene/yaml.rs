@@ -0,0 +1,1101 @@
+//! YAML scene description. A scene is a list of items, where every item either
+//! adds something to the scene, or defines a reusable value:
+//!
+//! ```yaml
+//! - add: camera
+//!   width: 400
+//!   height: 200
+//!   field-of-view: 1.047
+//!   from: [0, 1.5, -5]
+//!   to: [0, 1, 0]
+//!   up: [0, 1, 0]
+//!   background: [0, 0, 0]       # optional
+//!   projection: perspective     # optional: perspective (the default), fisheye, equirectangular
+//!                               # (which needs no field-of-view) or orthographic, which takes
+//!                               # view-width (in world units) instead of field-of-view
+//!
+//! - add: light
+//!   at: [-10, 10, -10]
+//!   intensity: [1, 1, 1]
+//!
+//! - add: light                  # directional light, infinitely far away
+//!   direction: [1, 2, -1]       # towards the light
+//!   intensity: [1, 1, 1]
+//!
+//! - add: environment            # light of the rays that hit nothing, replaces the background
+//!   file: sky.hdr               # equirectangular .hdr or .pfm image, relative to the scene file
+//!   intensity: 1                # optional
+//!
+//! - add: fog                    # homogeneous fog filling the scene
+//!   absorption: [0.01, 0.01, 0.01]  # optional, per unit of distance
+//!   scattering: [0.05, 0.05, 0.05]  # optional, per unit of distance
+//!   anisotropy: 0.3             # optional: -1 (backward) to 1 (forward scattering)
+//!   height: 2                   # optional: top of the fog
+//!
+//! - add: sky                    # procedural daylight sky, instead of an environment image
+//!   sun: [1, 1, -1]             # direction towards the sun
+//!   turbidity: 3                # optional: 1.7 (very clear) to 10 (hazy)
+//!   intensity: 1                # optional
+//!   sun-light: true             # optional: adds the sun as a directional light
+//!
+//! - define: base-material
+//!   value:
+//!     color: [1, 0.9, 0.9]
+//!     specular: 0
+//!
+//! - define: green-material
+//!   extend: base-material      # copies base-material and overrides its keys
+//!   value:
+//!     color: [0.1, 1, 0.5]
+//!
+//! - define: lift
+//!   value:
+//!     - [translate, 0, 1, 0]
+//!
+//! - add: sphere
+//!   material: green-material
+//!   transform:                  # applied top to bottom, like Transformation::add()
+//!     - [scale, 0.5, 0.5, 0.5]
+//!     - lift
+//!
+//! - add: plane
+//!   material:
+//!     pattern:
+//!       type: stripes           # stripes, gradient or test
+//!       colors: [[1, 1, 1], [0, 0, 0]]
+//!       transform:
+//!         - [rotate-y, 0.785]
+//! ```
+//!
+//! Transformation units: translate, scale (x, y, z), rotate-x, rotate-y, rotate-z (angle in radians)
+//! and shear (xy, xz, yx, yz, zx, zy). Material keys: color, ambient, diffuse, specular, shininess,
+//! emission (color of the emitted light, which makes the object a light source), pattern,
+//! and metallic and roughness (0..1), either of which switches to the GGX microfacet model.
+//! A material with `ior` (index of refraction) is a dielectric, and one with `conductor`
+//! (gold, copper, aluminium, or a mapping with `eta` and `k` colors) is a metal; both are
+//! perfectly smooth unless `roughness` is given. A material with `medium` (a mapping with
+//! `absorption`, `scattering` and `anisotropy`, like fog) turns a closed object into a volume
+//! whose surface is not drawn. The medium of a volume is heterogeneous with either `grid`
+//! (a raw density grid file spanning the unit cube of the object, relative to the scene file)
+//! or `noise` (a mapping with `frequency`, `octaves` and `seed`), whose density scales
+//! the coefficients. A material with `subsurface` (a mapping with `mean-free-path` and `albedo`
+//! colors) is translucent like wax or skin: its diffuse reflection is replaced by light that
+//! scatters under the surface. A material with `dispersion` (bk7, fused-silica, sf11, a mapping
+//! with the Cauchy coefficients `a` and `b`, or one with the Sellmeier triples `b` and `c`)
+//! is a dielectric whose index of refraction depends on the wavelength in the spectral mode;
+//! it replaces `ior` with its index at 589.3 nm.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde_yaml::{Mapping, Value};
+
+use super::SceneError;
+use crate::math::{utils, Color, TUnit, Transformation, Vector};
+use crate::render::bsdf::{BsdfKind, CONDUCTORS};
+use crate::render::core::{
+    DirectionalLight, Drawable, Material, Pattern, PatternList, PointLight, RAIIDrawable,
+};
+use crate::render::density::{Density, DensityGrid, Noise};
+use crate::render::environment::{Environment, EnvironmentMap};
+use crate::render::medium::{Fog, Medium};
+use crate::render::shapes::{Plane, Sphere};
+use crate::render::sky::{Sky, MAX_TURBIDITY, MIN_TURBIDITY};
+use crate::render::spectrum::{Dispersion, GLASSES, REFERENCE_WAVELENGTH};
+use crate::render::subsurface::Subsurface;
+use crate::render::{Projection, Renderer};
+
+/// Maximum depth of defines referencing other defines
+const MAX_NESTING: usize = 32;
+
+/// Builds a Renderer (Camera, Canvas and World) from a YAML scene description.
+/// Files referenced by the scene are relative to the current directory
+pub fn load_yaml(src: &str) -> Result<Renderer, SceneError> {
+    load_yaml_in(src, Path::new(""))
+}
+
+/// Reads a YAML scene file and builds a Renderer from it
+pub fn load_yaml_file<P: AsRef<Path>>(path: P) -> Result<Renderer, SceneError> {
+    let path = path.as_ref();
+    let src = fs::read_to_string(path).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    load_yaml_in(&src, path.parent().unwrap_or(Path::new("")))
+}
+
+/// Builds a Renderer from a YAML scene description whose files are relative to the directory dir
+fn load_yaml_in(src: &str, dir: &Path) -> Result<Renderer, SceneError> {
+    let doc: Value = serde_yaml::from_str(src).map_err(yaml_error)?;
+    let items = match doc {
+        Value::Sequence(items) => items,
+        Value::Null => vec![],
+        _ => {
+            return Err(SceneError::parse(
+                Some(1),
+                "a scene must be a list of items",
+            ))
+        }
+    };
+
+    Loader::new(src, dir).load(&items)
+}
+
+/// Converts an error of the YAML parser, keeping its line number
+fn yaml_error(e: serde_yaml::Error) -> SceneError {
+    match e.location() {
+        Some(l) => SceneError::located(e.to_string(), l.line(), l.column()),
+        None => SceneError::parse(None, e.to_string()),
+    }
+}
+
+/// Camera settings gathered from the `add: camera` item
+struct CameraDesc {
+    width: usize,
+    height: usize,
+    fov: f64,
+    projection: Projection,
+    from: Vector,
+    to: Vector,
+    up: Vector,
+    bg: Color,
+}
+
+/// Walks through the items of a scene, keeping track of their lines in the source
+struct Loader<'a> {
+    lines: Vec<&'a str>,
+
+    /// 0-based index of the first line of every top-level item
+    starts: Vec<usize>,
+
+    defines: HashMap<String, Value>,
+
+    /// directory of the files referenced by the scene
+    dir: PathBuf,
+}
+
+impl<'a> Loader<'a> {
+    fn new(src: &'a str, dir: &Path) -> Self {
+        let lines: Vec<&str> = src.lines().collect();
+        let starts = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.starts_with("- ") || l.trim_end() == "-")
+            .map(|(i, _)| i)
+            .collect();
+
+        Self {
+            lines,
+            starts,
+            defines: HashMap::new(),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Returns the 1-based line of an item, or of a key inside of it
+    fn line(&self, item: usize, key: Option<&str>) -> Option<usize> {
+        let start = *self.starts.get(item)?;
+        let end = self
+            .starts
+            .get(item + 1)
+            .copied()
+            .unwrap_or(self.lines.len());
+
+        if let Some(key) = key {
+            let pattern = format!("{}:", key);
+            for i in start..end {
+                let l = self.lines[i].trim_start();
+                let l = l.strip_prefix("- ").unwrap_or(l).trim_start();
+                if l.starts_with(&pattern) {
+                    return Some(i + 1);
+                }
+            }
+        }
+
+        Some(start + 1)
+    }
+
+    /// Creates an error pointing to an item (and a key inside of it)
+    fn error<S: Into<String>>(&self, item: usize, key: Option<&str>, msg: S) -> SceneError {
+        SceneError::parse(self.line(item, key), msg)
+    }
+
+    fn load(mut self, items: &[Value]) -> Result<Renderer, SceneError> {
+        let mut camera: Option<CameraDesc> = None;
+        let mut lights = vec![];
+        let mut directional = vec![];
+        let mut objects = vec![];
+        let mut environment = None;
+        let mut fog = None;
+
+        for (idx, item) in items.iter().enumerate() {
+            let map = item
+                .as_mapping()
+                .ok_or_else(|| self.error(idx, None, "a scene item must be a mapping"))?;
+
+            if let Some(name) = map.get("define") {
+                self.define(idx, map, name)?;
+                continue;
+            }
+
+            let kind = map
+                .get("add")
+                .ok_or_else(|| self.error(idx, None, "a scene item must have `add` or `define`"))?;
+            let kind = kind
+                .as_str()
+                .ok_or_else(|| self.error(idx, Some("add"), "`add` must be a string"))?;
+
+            match kind {
+                "camera" => {
+                    if camera.is_some() {
+                        return Err(self.error(idx, None, "the camera is added twice"));
+                    }
+                    camera = Some(self.camera(idx, map)?);
+                }
+                "light" if map.contains_key("direction") => {
+                    directional.push(self.directional_light(idx, map)?)
+                }
+                "light" => lights.push(self.light(idx, map)?),
+                "fog" => {
+                    if fog.is_some() {
+                        return Err(self.error(idx, None, "the fog is added twice"));
+                    }
+                    fog = Some(self.fog(idx, map)?);
+                }
+                "environment" | "sky" => {
+                    if environment.is_some() {
+                        return Err(self.error(idx, None, "the environment is added twice"));
+                    }
+                    let env: Box<dyn Environment> = if kind == "sky" {
+                        let (sky, sun) = self.sky(idx, map)?;
+                        directional.extend(sun);
+                        Box::new(sky)
+                    } else {
+                        Box::new(self.environment(idx, map)?)
+                    };
+                    environment = Some(env);
+                }
+                "sphere" => objects.push(self.shape(idx, map, Sphere::default())?),
+                "plane" => objects.push(self.shape(idx, map, Plane::default())?),
+                other => {
+                    return Err(self.error(idx, Some("add"), format!("unknown item `{}`", other)))
+                }
+            }
+        }
+
+        let c = camera.ok_or_else(|| SceneError::parse(None, "the scene has no camera"))?;
+        let mut renderer = Renderer::new(c.width, c.height, c.fov, c.from, c.to, c.up, c.bg);
+        renderer.camera_mut().set_projection(c.projection);
+        renderer.world.add_objs(objects);
+        for light in lights {
+            renderer.world.add_src(light.wrap_box());
+        }
+        for light in directional {
+            renderer.world.add_directional(light);
+        }
+        if let Some(env) = environment {
+            renderer.world.set_environment(env);
+        }
+        if let Some(fog) = fog {
+            renderer.world.set_fog(fog);
+        }
+
+        Ok(renderer)
+    }
+
+    /// Registers `define: name` with its value, merging it over the `extend`-ed define
+    fn define(&mut self, idx: usize, map: &Mapping, name: &Value) -> Result<(), SceneError> {
+        self.check_keys(idx, map, &["define", "extend", "value"])?;
+
+        let name = name
+            .as_str()
+            .ok_or_else(|| self.error(idx, Some("define"), "`define` must be a string"))?;
+        let mut value = self.get(idx, map, "value")?.clone();
+
+        if let Some(base) = map.get("extend") {
+            let base = base
+                .as_str()
+                .ok_or_else(|| self.error(idx, Some("extend"), "`extend` must be a string"))?;
+            let base = self.defines.get(base).ok_or_else(|| {
+                self.error(idx, Some("extend"), format!("`{}` is not defined", base))
+            })?;
+
+            match (base, &value) {
+                (Value::Mapping(base), Value::Mapping(over)) => {
+                    let mut merged = base.clone();
+                    for (k, v) in over {
+                        merged.insert(k.clone(), v.clone());
+                    }
+                    value = Value::Mapping(merged);
+                }
+                _ => {
+                    return Err(self.error(
+                        idx,
+                        Some("extend"),
+                        "only mappings (like materials) can be extended",
+                    ))
+                }
+            }
+        }
+
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn camera(&self, idx: usize, map: &Mapping) -> Result<CameraDesc, SceneError> {
+        self.check_keys(
+            idx,
+            map,
+            &[
+                "add",
+                "width",
+                "height",
+                "field-of-view",
+                "from",
+                "to",
+                "up",
+                "background",
+                "projection",
+                "view-width",
+            ],
+        )?;
+
+        let bg = match map.get("background") {
+            Some(v) => self.color(idx, "background", v)?,
+            None => Color::black(),
+        };
+
+        let projection =
+            match map.get("projection").map(|v| v.as_str()) {
+                None | Some(Some("perspective")) => Projection::Perspective,
+                Some(Some("fisheye")) => Projection::Fisheye,
+                Some(Some("equirectangular")) => Projection::Equirectangular,
+                Some(Some("orthographic")) => {
+                    let width = self.num(idx, "view-width", self.get(idx, map, "view-width")?)?;
+                    if width <= 0.0 {
+                        return Err(self.error(
+                            idx,
+                            Some("view-width"),
+                            "`view-width` must be positive",
+                        ));
+                    }
+                    Projection::Orthographic { width }
+                }
+                _ => return Err(self.error(
+                    idx,
+                    Some("projection"),
+                    "`projection` must be perspective, orthographic, fisheye or equirectangular",
+                )),
+            };
+
+        // the field of view is only used by perspective and fisheye cameras
+        let fov = match (projection, map.get("field-of-view")) {
+            (Projection::Perspective | Projection::Fisheye, _) => {
+                self.num(idx, "field-of-view", self.get(idx, map, "field-of-view")?)?
+            }
+            (_, Some(v)) => self.num(idx, "field-of-view", v)?,
+            (_, None) => std::f64::consts::FRAC_PI_2,
+        };
+
+        Ok(CameraDesc {
+            width: self.size(idx, "width", self.get(idx, map, "width")?)?,
+            height: self.size(idx, "height", self.get(idx, map, "height")?)?,
+            fov,
+            projection,
+            from: self.point(idx, "from", self.get(idx, map, "from")?)?,
+            to: self.point(idx, "to", self.get(idx, map, "to")?)?,
+            up: self.vector(idx, "up", self.get(idx, map, "up")?)?,
+            bg,
+        })
+    }
+
+    fn light(&self, idx: usize, map: &Mapping) -> Result<PointLight, SceneError> {
+        self.check_keys(idx, map, &["add", "at", "intensity"])?;
+
+        let pos = self.point(idx, "at", self.get(idx, map, "at")?)?;
+        let int = self.color(idx, "intensity", self.get(idx, map, "intensity")?)?;
+        Ok(PointLight::new(pos, int))
+    }
+
+    fn directional_light(&self, idx: usize, map: &Mapping) -> Result<DirectionalLight, SceneError> {
+        self.check_keys(idx, map, &["add", "direction", "intensity"])?;
+
+        let dir = self.direction(idx, "direction", self.get(idx, map, "direction")?)?;
+        let int = self.color(idx, "intensity", self.get(idx, map, "intensity")?)?;
+        Ok(DirectionalLight::new(dir, int))
+    }
+
+    /// Builds the sky and its sun light (unless `sun-light` is false)
+    fn sky(
+        &self,
+        idx: usize,
+        map: &Mapping,
+    ) -> Result<(Sky, Option<DirectionalLight>), SceneError> {
+        self.check_keys(
+            idx,
+            map,
+            &["add", "sun", "turbidity", "intensity", "sun-light"],
+        )?;
+
+        let sun = self.direction(idx, "sun", self.get(idx, map, "sun")?)?;
+        let turbidity = match map.get("turbidity") {
+            Some(v) => match v.as_f64() {
+                Some(t) if (MIN_TURBIDITY..=MAX_TURBIDITY).contains(&t) => t,
+                _ => {
+                    return Err(self.error(
+                        idx,
+                        Some("turbidity"),
+                        format!(
+                            "`turbidity` must be a number from {} to {}",
+                            MIN_TURBIDITY, MAX_TURBIDITY
+                        ),
+                    ))
+                }
+            },
+            None => 3.0,
+        };
+        let sun_light = match map.get("sun-light") {
+            Some(v) => v.as_bool().ok_or_else(|| {
+                self.error(idx, Some("sun-light"), "`sun-light` must be true or false")
+            })?,
+            None => true,
+        };
+
+        let mut sky = Sky::new(sun, turbidity);
+        if let Some(v) = map.get("intensity") {
+            sky.intensity = self.positive(idx, "intensity", v)?;
+        }
+        let sun = if sun_light { Some(sky.sun()) } else { None };
+        Ok((sky, sun))
+    }
+
+    fn fog(&self, idx: usize, map: &Mapping) -> Result<Fog, SceneError> {
+        self.check_keys(
+            idx,
+            map,
+            &["add", "absorption", "scattering", "anisotropy", "height"],
+        )?;
+
+        let mut fog = Fog::new(self.medium_keys(idx, map)?);
+        if let Some(v) = map.get("height") {
+            fog.height = Some(self.num(idx, "height", v)?);
+        }
+        Ok(fog)
+    }
+
+    /// Reads the medium of a volume material and its density
+    fn medium(&self, idx: usize, v: &Value) -> Result<(Medium, Option<Density>), SceneError> {
+        let v = self.resolve(idx, "medium", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("medium"),
+                "`medium` must be a mapping with `absorption`, `scattering` and `anisotropy`",
+            )
+        })?;
+        self.check_keys(
+            idx,
+            map,
+            &["absorption", "scattering", "anisotropy", "grid", "noise"],
+        )?;
+
+        let density = match (map.get("grid"), map.get("noise")) {
+            (Some(_), Some(_)) => {
+                return Err(self.error(
+                    idx,
+                    Some("medium"),
+                    "a medium has either a `grid` or a `noise`",
+                ))
+            }
+            (Some(v), None) => Some(Density::Grid(Rc::new(self.grid(idx, v)?))),
+            (None, Some(v)) => Some(Density::Noise(self.noise(idx, v)?)),
+            (None, None) => None,
+        };
+        Ok((self.medium_keys(idx, map)?, density))
+    }
+
+    /// Loads the density grid of a medium
+    fn grid(&self, idx: usize, v: &Value) -> Result<DensityGrid, SceneError> {
+        let file = v
+            .as_str()
+            .ok_or_else(|| self.error(idx, Some("grid"), "`grid` must be a string"))?;
+        DensityGrid::load(self.dir.join(file))
+            .map_err(|e| self.error(idx, Some("grid"), format!("{}: {}", file, e)))
+    }
+
+    /// Reads the noise density of a medium
+    fn noise(&self, idx: usize, v: &Value) -> Result<Noise, SceneError> {
+        let v = self.resolve(idx, "noise", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("noise"),
+                "`noise` must be a mapping with `frequency`, `octaves` and `seed`",
+            )
+        })?;
+        self.check_keys(idx, map, &["frequency", "octaves", "seed"])?;
+
+        let mut noise = Noise::new(1.0, 4, 0);
+        if let Some(v) = map.get("frequency") {
+            noise.frequency = self.positive(idx, "frequency", v)?;
+        }
+        if let Some(v) = map.get("octaves") {
+            noise.octaves = self.size(idx, "octaves", v)?;
+        }
+        if let Some(v) = map.get("seed") {
+            noise.seed = v
+                .as_u64()
+                .and_then(|s| u32::try_from(s).ok())
+                .ok_or_else(|| {
+                    self.error(idx, Some("seed"), "`seed` must be a non-negative integer")
+                })?;
+        }
+        Ok(noise)
+    }
+
+    /// Reads the optional `absorption`, `scattering` and `anisotropy` keys of a medium
+    fn medium_keys(&self, idx: usize, map: &Mapping) -> Result<Medium, SceneError> {
+        let mut m = Medium::new(Color::black(), Color::black());
+        for (key, coefficient) in [
+            ("absorption", &mut m.absorption),
+            ("scattering", &mut m.scattering),
+        ] {
+            if let Some(v) = map.get(key) {
+                let c = self.color(idx, key, v)?;
+                if c.r < 0.0 || c.g < 0.0 || c.b < 0.0 {
+                    return Err(self.error(
+                        idx,
+                        Some(key),
+                        format!("`{}` must not be negative", key),
+                    ));
+                }
+                *coefficient = c;
+            }
+        }
+        if let Some(v) = map.get("anisotropy") {
+            m.g = match v.as_f64() {
+                Some(g) if g > -1.0 && g < 1.0 => g,
+                _ => {
+                    return Err(self.error(
+                        idx,
+                        Some("anisotropy"),
+                        "`anisotropy` must be a number between -1 and 1",
+                    ))
+                }
+            };
+        }
+        Ok(m)
+    }
+
+    fn environment(&self, idx: usize, map: &Mapping) -> Result<EnvironmentMap, SceneError> {
+        self.check_keys(idx, map, &["add", "file", "intensity"])?;
+
+        let file = self
+            .get(idx, map, "file")?
+            .as_str()
+            .ok_or_else(|| self.error(idx, Some("file"), "`file` must be a string"))?;
+        let mut env = EnvironmentMap::load(self.dir.join(file))
+            .map_err(|e| self.error(idx, Some("file"), e.to_string()))?;
+        if let Some(v) = map.get("intensity") {
+            env.intensity = self.positive(idx, "intensity", v)?;
+        }
+        Ok(env)
+    }
+
+    /// Configures a shape with its transformation, material and pattern
+    fn shape<T: Drawable + 'static>(
+        &self,
+        idx: usize,
+        map: &Mapping,
+        mut shape: T,
+    ) -> Result<RAIIDrawable, SceneError> {
+        self.check_keys(idx, map, &["add", "transform", "material"])?;
+
+        if let Some(t) = map.get("transform") {
+            shape.set_transform(self.transformation(idx, "transform", t)?);
+        }
+
+        if let Some(m) = map.get("material") {
+            let (material, pattern) = self.material(idx, m)?;
+            shape.set_material(material);
+
+            // the pattern references the shape transformation, so it is set afterwards
+            if let Some(p) = pattern {
+                shape.set_pattern(p);
+            }
+        }
+
+        Ok(shape.wrap())
+    }
+
+    /// Builds a Material (and its Pattern) from a mapping or a name of a defined one
+    fn material(&self, idx: usize, v: &Value) -> Result<(Material, Option<Pattern>), SceneError> {
+        let v = self.resolve(idx, "material", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("material"),
+                "a material must be a mapping or a name",
+            )
+        })?;
+        self.check_keys(
+            idx,
+            map,
+            &[
+                "color",
+                "ambient",
+                "diffuse",
+                "specular",
+                "shininess",
+                "emission",
+                "metallic",
+                "roughness",
+                "ior",
+                "conductor",
+                "medium",
+                "subsurface",
+                "dispersion",
+                "pattern",
+            ],
+        )?;
+
+        let mut m = Material::default();
+        let mut pattern = None;
+        let (mut metallic, mut roughness) = (None, None);
+        let (mut ior, mut conductor) = (None, None);
+        for (k, v) in map {
+            let key = k.as_str().unwrap_or_default();
+            match key {
+                "color" => m.color = self.color(idx, key, v)?,
+                "ambient" => m.ambient = self.num(idx, key, v)?,
+                "diffuse" => m.diffuse = self.num(idx, key, v)?,
+                "specular" => m.specular = self.num(idx, key, v)?,
+                "shininess" => m.shininess = self.num(idx, key, v)?,
+                "emission" => m.emission = self.color(idx, key, v)?,
+                "metallic" => metallic = Some(self.unit(idx, key, v)?),
+                "roughness" => roughness = Some(self.unit(idx, key, v)?),
+                "ior" => ior = Some(self.positive(idx, key, v)?),
+                "conductor" => conductor = Some(self.conductor(idx, v)?),
+                "medium" => {
+                    let (medium, density) = self.medium(idx, v)?;
+                    m.medium = Some(medium);
+                    m.density = density;
+                }
+                "subsurface" => m.subsurface = Some(self.subsurface(idx, v)?),
+                "dispersion" => m.dispersion = Some(self.dispersion(idx, v)?),
+                _ => pattern = Some(self.pattern(idx, v)?),
+            }
+        }
+
+        if let Some(d) = m.dispersion {
+            if metallic.is_some() || conductor.is_some() || ior.is_some() {
+                return Err(self.error(
+                    idx,
+                    Some("dispersion"),
+                    "`dispersion` cannot be combined with `metallic`, `conductor` or `ior`",
+                ));
+            }
+            ior = Some(d.ior(REFERENCE_WAVELENGTH));
+        }
+
+        m.bsdf = match (metallic, ior, conductor) {
+            (None, None, None) if roughness.is_none() => BsdfKind::Phong,
+            (_, None, None) => BsdfKind::Microfacet {
+                metallic: metallic.unwrap_or(0.0),
+                roughness: roughness.unwrap_or(0.5),
+            },
+            (None, Some(ior), None) => BsdfKind::Dielectric {
+                ior,
+                roughness: roughness.unwrap_or(0.0),
+            },
+            (None, None, Some((eta, k))) => BsdfKind::Conductor {
+                eta,
+                k,
+                roughness: roughness.unwrap_or(0.0),
+            },
+            _ => {
+                return Err(self.error(
+                    idx,
+                    Some("material"),
+                    "only one of `metallic`, `ior` and `conductor` can be given",
+                ))
+            }
+        };
+        if m.subsurface.is_some() && (m.bsdf != BsdfKind::Phong || m.medium.is_some()) {
+            return Err(self.error(
+                idx,
+                Some("subsurface"),
+                "`subsurface` cannot be combined with `metallic`, `roughness`, `ior`, \
+                 `conductor` or `medium`",
+            ));
+        }
+
+        Ok((m, pattern))
+    }
+
+    /// Reads the subsurface scattering of a material
+    fn subsurface(&self, idx: usize, v: &Value) -> Result<Subsurface, SceneError> {
+        let v = self.resolve(idx, "subsurface", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("subsurface"),
+                "`subsurface` must be a mapping with `mean-free-path` and `albedo`",
+            )
+        })?;
+        self.check_keys(idx, map, &["mean-free-path", "albedo"])?;
+
+        let key = "mean-free-path";
+        let mfp = self.color(idx, key, self.get(idx, map, key)?)?;
+        if mfp.r <= 0.0 || mfp.g <= 0.0 || mfp.b <= 0.0 {
+            return Err(self.error(idx, Some(key), "`mean-free-path` must be positive"));
+        }
+        let albedo = self.color(idx, "albedo", self.get(idx, map, "albedo")?)?;
+        if [albedo.r, albedo.g, albedo.b]
+            .iter()
+            .any(|a| !(0.0..=1.0).contains(a))
+        {
+            return Err(self.error(idx, Some("albedo"), "`albedo` must be between 0 and 1"));
+        }
+        Ok(Subsurface::new(mfp, albedo))
+    }
+
+    /// Reads the dispersion of a named glass, or the Cauchy or Sellmeier coefficients of a custom one
+    fn dispersion(&self, idx: usize, v: &Value) -> Result<Dispersion, SceneError> {
+        if let Some(d) = v.as_str().and_then(Dispersion::glass) {
+            return Ok(d);
+        }
+
+        let v = self.resolve(idx, "dispersion", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("dispersion"),
+                format!(
+                    "`dispersion` must be one of {}, or a mapping with `a` and `b` (Cauchy) \
+                     or `b` and `c` (Sellmeier)",
+                    GLASSES.join(", ")
+                ),
+            )
+        })?;
+
+        if map.contains_key("a") {
+            self.check_keys(idx, map, &["a", "b"])?;
+            let a = self.positive(idx, "a", self.get(idx, map, "a")?)?;
+            let b = self.num(idx, "b", self.get(idx, map, "b")?)?;
+            let d = Dispersion::Cauchy { a, b };
+            return d
+                .validate()
+                .map(|_| d)
+                .map_err(|e| self.error(idx, Some("dispersion"), e));
+        }
+        self.check_keys(idx, map, &["b", "c"])?;
+        let (b1, b2, b3) = self.triple(idx, "b", self.get(idx, map, "b")?)?;
+        let (c1, c2, c3) = self.triple(idx, "c", self.get(idx, map, "c")?)?;
+        let d = Dispersion::Sellmeier {
+            b: [b1, b2, b3],
+            c: [c1, c2, c3],
+        };
+        d.validate()
+            .map(|_| d)
+            .map_err(|e| self.error(idx, Some("dispersion"), e))
+    }
+
+    /// Returns the complex index of refraction (eta, k) of a named or a custom conductor
+    fn conductor(&self, idx: usize, v: &Value) -> Result<(Color, Color), SceneError> {
+        if let Some(name) = v.as_str() {
+            if let Some(BsdfKind::Conductor { eta, k, .. }) = BsdfKind::conductor(name, 0.0) {
+                return Ok((eta, k));
+            }
+        }
+
+        let v = self.resolve(idx, "conductor", v, 0)?;
+        let map = v.as_mapping().ok_or_else(|| {
+            self.error(
+                idx,
+                Some("conductor"),
+                format!(
+                    "`conductor` must be one of {} or a mapping with `eta` and `k`",
+                    CONDUCTORS.join(", ")
+                ),
+            )
+        })?;
+        self.check_keys(idx, map, &["eta", "k"])?;
+
+        let eta = self.color(idx, "eta", self.get(idx, map, "eta")?)?;
+        let k = self.color(idx, "k", self.get(idx, map, "k")?)?;
+        Ok((eta, k))
+    }
+
+    fn pattern(&self, idx: usize, v: &Value) -> Result<Pattern, SceneError> {
+        let v = self.resolve(idx, "pattern", v, 0)?;
+        let map = v
+            .as_mapping()
+            .ok_or_else(|| self.error(idx, Some("pattern"), "a pattern must be a mapping"))?;
+        self.check_keys(idx, map, &["type", "colors", "transform"])?;
+
+        let kind = self.get(idx, map, "type")?;
+        let mut p = match kind.as_str() {
+            Some("stripes") => Pattern::default(PatternList::StripePattern),
+            Some("gradient") => Pattern::default(PatternList::GradientPattern),
+            Some("test") => Pattern::default(PatternList::TestPattern),
+            _ => {
+                return Err(self.error(
+                    idx,
+                    Some("type"),
+                    "pattern type must be one of stripes, gradient, test",
+                ))
+            }
+        };
+
+        if let Some(colors) = map.get("colors") {
+            let colors = match colors.as_sequence() {
+                Some(seq) if seq.len() == 2 => seq,
+                _ => {
+                    return Err(self.error(
+                        idx,
+                        Some("colors"),
+                        "`colors` must be a list of two colors",
+                    ))
+                }
+            };
+            if matches!(p, Pattern::TestPattern(_)) {
+                return Err(self.error(idx, Some("colors"), "test pattern has no colors"));
+            }
+            p.set_colors(
+                self.color(idx, "colors", &colors[0])?,
+                self.color(idx, "colors", &colors[1])?,
+            );
+        }
+
+        if let Some(t) = map.get("transform") {
+            let mut units = vec![];
+            self.tunits(idx, t, &mut units, 0)?;
+            for u in units {
+                p.add_tunit(u);
+            }
+        }
+
+        Ok(p)
+    }
+
+    /// Builds a Transformation from a list of units (or a name of a defined list)
+    fn transformation(
+        &self,
+        idx: usize,
+        key: &str,
+        v: &Value,
+    ) -> Result<Transformation, SceneError> {
+        let mut units = vec![];
+        self.tunits(idx, v, &mut units, 0).map_err(|e| match e {
+            SceneError::Parse { msg, .. } => self.error(idx, Some(key), msg),
+            other => other,
+        })?;
+
+        Ok(Transformation::new(&units))
+    }
+
+    /// Collects TUnits from a list, splicing lists of defined names in place
+    fn tunits(
+        &self,
+        idx: usize,
+        v: &Value,
+        out: &mut Vec<TUnit>,
+        depth: usize,
+    ) -> Result<(), SceneError> {
+        if depth > MAX_NESTING {
+            return Err(self.error(idx, Some("transform"), "defines are nested too deeply"));
+        }
+
+        match v {
+            Value::String(_) => {
+                let resolved = self.resolve(idx, "transform", v, depth)?;
+                self.tunits(idx, resolved, out, depth + 1)
+            }
+            Value::Sequence(seq) => {
+                for el in seq {
+                    match el {
+                        Value::String(_) => self.tunits(idx, el, out, depth + 1)?,
+                        _ => out.push(self.tunit(idx, el)?),
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(self.error(idx, Some("transform"), "a transformation must be a list")),
+        }
+    }
+
+    /// Parses a single unit like [translate, 1, 2, 3]
+    fn tunit(&self, idx: usize, v: &Value) -> Result<TUnit, SceneError> {
+        let seq = v.as_sequence().filter(|s| !s.is_empty()).ok_or_else(|| {
+            self.error(
+                idx,
+                Some("transform"),
+                "a transformation unit must be a list like [scale, 1, 2, 3]",
+            )
+        })?;
+
+        let name = seq[0].as_str().unwrap_or_default();
+        let args = seq[1..]
+            .iter()
+            .map(|a| self.num(idx, "transform", a))
+            .collect::<Result<Vec<f64>, SceneError>>()?;
+
+        let expected = match name {
+            "translate" | "scale" => 3,
+            "rotate-x" | "rotate-y" | "rotate-z" => 1,
+            "shear" => 6,
+            _ => {
+                return Err(self.error(
+                    idx,
+                    Some("transform"),
+                    format!("unknown transformation unit `{}`", name),
+                ))
+            }
+        };
+        if args.len() != expected {
+            return Err(self.error(
+                idx,
+                Some("transform"),
+                format!(
+                    "`{}` takes {} numbers, {} given",
+                    name,
+                    expected,
+                    args.len()
+                ),
+            ));
+        }
+
+        Ok(match name {
+            "translate" => TUnit::Translate(args[0], args[1], args[2]),
+            "scale" => TUnit::Scale(args[0], args[1], args[2]),
+            "rotate-x" => TUnit::RotateX(args[0]),
+            "rotate-y" => TUnit::RotateY(args[0]),
+            "rotate-z" => TUnit::RotateZ(args[0]),
+            _ => TUnit::Shear(args[0], args[1], args[2], args[3], args[4], args[5]),
+        })
+    }
+
+    /// Replaces a name of a define by its value (other values are returned as they are)
+    fn resolve<'v>(
+        &'v self,
+        idx: usize,
+        key: &str,
+        v: &'v Value,
+        depth: usize,
+    ) -> Result<&'v Value, SceneError> {
+        let mut v = v;
+        for _ in depth..MAX_NESTING {
+            match v {
+                Value::String(name) => {
+                    v = self.defines.get(name).ok_or_else(|| {
+                        self.error(idx, Some(key), format!("`{}` is not defined", name))
+                    })?;
+                }
+                _ => return Ok(v),
+            }
+        }
+
+        Err(self.error(idx, Some(key), "defines are nested too deeply"))
+    }
+
+    /// Rejects keys that are not expected in a mapping
+    fn check_keys(&self, idx: usize, map: &Mapping, allowed: &[&str]) -> Result<(), SceneError> {
+        for k in map.keys() {
+            match k.as_str() {
+                Some(key) if allowed.contains(&key) => (),
+                Some(key) => {
+                    return Err(self.error(idx, Some(key), format!("unexpected key `{}`", key)))
+                }
+                None => return Err(self.error(idx, None, "keys must be strings")),
+            }
+        }
+        Ok(())
+    }
+
+    fn get<'m>(&self, idx: usize, map: &'m Mapping, key: &str) -> Result<&'m Value, SceneError> {
+        map.get(key)
+            .ok_or_else(|| self.error(idx, None, format!("missing key `{}`", key)))
+    }
+
+    fn num(&self, idx: usize, key: &str, v: &Value) -> Result<f64, SceneError> {
+        v.as_f64()
+            .ok_or_else(|| self.error(idx, Some(key), format!("`{}` must be a number", key)))
+    }
+
+    fn positive(&self, idx: usize, key: &str, v: &Value) -> Result<f64, SceneError> {
+        match v.as_f64() {
+            Some(x) if x > 0.0 => Ok(x),
+            _ => Err(self.error(
+                idx,
+                Some(key),
+                format!("`{}` must be a positive number", key),
+            )),
+        }
+    }
+
+    fn unit(&self, idx: usize, key: &str, v: &Value) -> Result<f64, SceneError> {
+        match v.as_f64() {
+            Some(x) if (0.0..=1.0).contains(&x) => Ok(x),
+            _ => Err(self.error(
+                idx,
+                Some(key),
+                format!("`{}` must be a number between 0 and 1", key),
+            )),
+        }
+    }
+
+    fn size(&self, idx: usize, key: &str, v: &Value) -> Result<usize, SceneError> {
+        match v.as_u64() {
+            Some(n) if n > 0 => Ok(n as usize),
+            _ => Err(self.error(
+                idx,
+                Some(key),
+                format!("`{}` must be a positive integer", key),
+            )),
+        }
+    }
+
+    fn triple(&self, idx: usize, key: &str, v: &Value) -> Result<(f64, f64, f64), SceneError> {
+        let v = self.resolve(idx, key, v, 0)?;
+        match v.as_sequence() {
+            Some(seq) if seq.len() == 3 => Ok((
+                self.num(idx, key, &seq[0])?,
+                self.num(idx, key, &seq[1])?,
+                self.num(idx, key, &seq[2])?,
+            )),
+            _ => Err(self.error(
+                idx,
+                Some(key),
+                format!("`{}` must be a list of three numbers", key),
+            )),
+        }
+    }
+
+    fn point(&self, idx: usize, key: &str, v: &Value) -> Result<Vector, SceneError> {
+        let (x, y, z) = self.triple(idx, key, v)?;
+        Ok(utils::point(x, y, z))
+    }
+
+    fn vector(&self, idx: usize, key: &str, v: &Value) -> Result<Vector, SceneError> {
+        let (x, y, z) = self.triple(idx, key, v)?;
+        Ok(utils::vector(x, y, z))
+    }
+
+    /// Reads a non-zero vector and normalizes it
+    fn direction(&self, idx: usize, key: &str, v: &Value) -> Result<Vector, SceneError> {
+        let dir = self.vector(idx, key, v)?;
+        if dir.magnitude() == 0.0 {
+            return Err(self.error(idx, Some(key), format!("`{}` must not be zero", key)));
+        }
+        Ok(dir.normalize())
+    }
+
+    fn color(&self, idx: usize, key: &str, v: &Value) -> Result<Color, SceneError> {
+        let (r, g, b) = self.triple(idx, key, v)?;
+        Ok(utils::color(r, g, b))
+    }
+}
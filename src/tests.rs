@@ -1,14 +1,28 @@
 use std::f64::consts::PI;
+use std::time::Duration;
 
 use super::math::utils::*;
+use super::math::sampler::Sampler;
 use super::math::{Color, Matrix, TUnit, Transformation};
 
+use super::render::image::{
+    crc32, read_hdr, read_pfm, ImageError, ImageFormat, PngDepth, PpmFormat, PpmWriter,
+    PPM_LINE_LIMIT,
+};
+use super::render::tonemap::{luminance, srgb_decode, srgb_encode, PostProcess, ToneMap, Transfer};
 use super::render::Canvas;
 use crate::render::core::{Computations, Drawable, Is, Material, Pattern, PatternList, PointLight, Ray, II};
 use crate::render::core::I;
 use crate::render::shapes::{Plane, Sphere};
 
-use crate::render::{Camera, World};
+use crate::render::progress::{CancelToken, NoProgress, Progress, RenderStatus};
+use crate::render::progressive::{Accumulator, Budget};
+use crate::render::aov::Aov;
+use crate::render::stereo::{Eye, Stereo, StereoLayout, StereoMode};
+use crate::render::checkpoint::{tile_grid, Checkpoint, Tile};
+use crate::render::{render_parallel, render_parallel_resumable, render_parallel_with, Camera, Projection, RenderError, World};
+use crate::render::Renderer;
+use crate::scene::{load_json, load_yaml, load_yaml_file, to_json, SceneError};
 use crate::{fassert, massert, transform, vassert};
 
 #[test]
@@ -110,6 +124,380 @@ fn canvas_operations() {
     assert_eq!(c[[2, 3]], color(1.0, 0.0, 0.0));
 }
 
+#[test]
+fn ppm_output() {
+    // Constructing the plain PPM header and pixel data
+    let mut c = Canvas::new(5, 3, Color::black());
+    let _ = c.write(0, 0, color(1.5, 0.0, 0.0));
+    let _ = c.write(2, 1, color(0.0, 0.5, 0.0));
+    let _ = c.write(4, 2, color(-0.5, 0.0, 1.0));
+    let mut buf = Vec::new();
+    c.write_ppm(&mut buf, PpmFormat::Plain { wrap: true }).unwrap();
+    let ppm = String::from_utf8(buf).unwrap();
+    let lines: Vec<&str> = ppm.lines().collect();
+    assert_eq!(lines[0..3], ["P3", "5 3", "255"]);
+    assert_eq!(lines[3], "255 0 0 0 0 0 0 0 0 0 0 0 0 0 0");
+    assert_eq!(lines[4], "0 0 0 0 0 0 0 128 0 0 0 0 0 0 0");
+    assert_eq!(lines[5], "0 0 0 0 0 0 0 0 0 0 0 0 0 0 255");
+    assert!(ppm.ends_with('\n'));
+
+    // Splitting long lines in PPM files
+    let mut c = Canvas::new(10, 2, Color::black());
+    for y in 0..2 {
+        for x in 0..10 {
+            let _ = c.write(x, y, color(1.0, 0.8, 0.6));
+        }
+    }
+    let mut buf = Vec::new();
+    c.write_ppm(&mut buf, PpmFormat::default()).unwrap();
+    let ppm = String::from_utf8(buf).unwrap();
+    let lines: Vec<&str> = ppm.lines().collect();
+    assert_eq!(lines.len(), 3 + 4);
+    assert_eq!(
+        lines[3],
+        "255 204 153 255 204 153 255 204 153 255 204 153 255 204 153 255 204"
+    );
+    assert_eq!(lines[4], "153 255 204 153 255 204 153 255 204 153 255 204 153");
+    assert!(lines.iter().all(|l| l.len() <= PPM_LINE_LIMIT));
+
+    // Long lines are kept when wrapping is disabled
+    let mut buf = Vec::new();
+    c.write_ppm(&mut buf, PpmFormat::Plain { wrap: false }).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap().lines().count(), 3 + 2);
+
+    // Binary PPM stores raw bytes right after the header
+    let mut c = Canvas::new(2, 2, Color::black());
+    let _ = c.write(1, 0, color(1.0, 0.5, 0.0));
+    let mut buf = Vec::new();
+    c.write_ppm(&mut buf, PpmFormat::Raw).unwrap();
+    let header = b"P6\n2 2\n255\n";
+    assert_eq!(&buf[..header.len()], header);
+    assert_eq!(buf.len(), header.len() + 2 * 2 * 3);
+    assert_eq!(&buf[header.len()..header.len() + 6], &[0, 0, 0, 255, 128, 0]);
+
+    // The streaming writer validates the rows it receives
+    let mut w = PpmWriter::new(Vec::new(), 2, 1, PpmFormat::Raw).unwrap();
+    assert!(w.write_row(&[Color::black()]).is_err());
+    w.write_row(&[Color::black(), Color::white()]).unwrap();
+    assert!(w.write_row(&[Color::black(), Color::white()]).is_err());
+    assert!(w.finish().is_ok());
+    let w = PpmWriter::new(Vec::new(), 2, 1, PpmFormat::Raw).unwrap();
+    assert!(w.finish().is_err());
+}
+
+/// Decodes an RGB PNG produced by PngWriter, checking its chunks on the way.
+/// Returns width, height, bit depth and unfiltered samples
+fn decode_png(png: &[u8]) -> (usize, usize, u8, Vec<u8>) {
+    assert_eq!(&png[..8], &[137, 80, 78, 71, 13, 10, 26, 10]);
+
+    let mut pos = 8;
+    let mut ihdr = vec![];
+    let mut idat = vec![];
+    let mut kinds = vec![];
+    while pos < png.len() {
+        let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+        let kind = &png[pos + 4..pos + 8];
+        let data = &png[pos + 8..pos + 8 + len];
+        let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+        assert_eq!(crc, crc32(&png[pos + 4..pos + 8 + len]));
+
+        match kind {
+            b"IHDR" => ihdr = data.to_vec(),
+            b"IDAT" => idat.extend_from_slice(data),
+            _ => (),
+        }
+        kinds.push(kind.to_vec());
+        pos += 12 + len;
+    }
+    assert_eq!(kinds.first().unwrap(), b"IHDR");
+    assert_eq!(kinds.last().unwrap(), b"IEND");
+
+    let width = u32::from_be_bytes(ihdr[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(ihdr[4..8].try_into().unwrap()) as usize;
+    let depth = ihdr[8];
+    assert_eq!(ihdr[9], 2);
+
+    let raw = miniz_oxide::inflate::decompress_to_vec_zlib(&idat).expect("invalid zlib stream");
+    let bpp = 3 * depth as usize / 8;
+    let row_len = width * bpp;
+    assert_eq!(raw.len(), height * (row_len + 1));
+
+    let mut samples: Vec<u8> = vec![];
+    let mut prev = vec![0u8; row_len];
+    for row in raw.chunks(row_len + 1) {
+        let mut cur = vec![0u8; row_len];
+        for i in 0..row_len {
+            let a = if i >= bpp { cur[i - bpp] as i16 } else { 0 };
+            let b = prev[i] as i16;
+            let c = if i >= bpp { prev[i - bpp] as i16 } else { 0 };
+            let predictor = match row[0] {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => {
+                    let p = a + b - c;
+                    let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                    if pa <= pb && pa <= pc {
+                        a
+                    } else if pb <= pc {
+                        b
+                    } else {
+                        c
+                    }
+                }
+                f => panic!("invalid filter type {}", f),
+            };
+            cur[i] = row[i + 1].wrapping_add(predictor as u8);
+        }
+        samples.extend_from_slice(&cur);
+        prev = cur;
+    }
+
+    (width, height, depth, samples)
+}
+
+#[test]
+fn png_output() {
+    // CRC-32 check value
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+    // Choosing the image format by the file extension
+    assert_eq!(
+        ImageFormat::from_path("img/a.PNG".as_ref()),
+        Some(ImageFormat::Png(PngDepth::Eight))
+    );
+    assert_eq!(
+        ImageFormat::from_path("a.ppm".as_ref()),
+        Some(ImageFormat::Ppm(PpmFormat::default()))
+    );
+    assert_eq!(ImageFormat::from_path("a.jpg".as_ref()), None);
+    assert_eq!(ImageFormat::from_path("a".as_ref()), None);
+
+    // Encoding an 8-bit PNG that spans several deflate blocks and IDAT chunks
+    let mut c = Canvas::new(300, 200, Color::black());
+    for y in 0..200 {
+        for x in 0..300 {
+            let col = color((x % 17) as f64 / 16.0, (y % 5) as f64 / 4.0, ((x * y) % 251) as f64 / 250.0);
+            let _ = c.write(x, y, col);
+        }
+    }
+    let mut png = vec![];
+    c.write_image(&mut png, ImageFormat::Png(PngDepth::Eight)).unwrap();
+    let (w, h, depth, samples) = decode_png(&png);
+    assert_eq!((w, h, depth), (300, 200, 8));
+    for y in 0..200 {
+        for x in 0..300 {
+            let i = (y * 300 + x) * 3;
+            assert_eq!(samples[i..i + 3], c[[x, y]].to_rgb8());
+        }
+    }
+    assert!(png.len() < samples.len());
+
+    // Encoding a 16-bit PNG
+    let mut c = Canvas::new(3, 2, Color::black());
+    let _ = c.write(0, 0, color(1.0, 0.5, 0.0));
+    let _ = c.write(2, 1, color(2.0, -1.0, 0.25));
+    let mut png = vec![];
+    c.write_image(&mut png, ImageFormat::Png(PngDepth::Sixteen)).unwrap();
+    let (w, h, depth, samples) = decode_png(&png);
+    assert_eq!((w, h, depth), (3, 2, 16));
+    assert_eq!(samples.len(), 3 * 2 * 6);
+    assert_eq!(samples[0..6], [255, 255, 128, 0, 0, 0]);
+    assert_eq!(samples[30..36], [255, 255, 0, 0, 64, 0]);
+}
+
+#[test]
+fn hdr_output() {
+    let mut c = Canvas::new(20, 3, Color::black());
+    for x in 0..20 {
+        let _ = c.write(x, 0, color(5.0, 0.25, 1.0));
+        let _ = c.write(x, 1, color(x as f64 * 0.7, 1000.0, 0.001 * x as f64));
+    }
+    let _ = c.write(3, 2, color(0.5, 0.5, 0.5));
+
+    // PFM keeps the samples as 32-bit floats
+    let mut pfm = vec![];
+    c.write_image(&mut pfm, ImageFormat::Pfm).unwrap();
+    assert!(pfm.starts_with(b"PF\n20 3\n-1.0\n"));
+    let d = read_pfm(&pfm[..]).unwrap();
+    assert_eq!((d.width, d.height), (20, 3));
+    for y in 0..3 {
+        for x in 0..20 {
+            assert_eq!(d[[x, y]], c[[x, y]]);
+        }
+    }
+
+    // Reading a big-endian grayscale PFM (rows are stored bottom to top)
+    let mut pfm = b"Pf\n2 2\n1.0\n".to_vec();
+    for v in [1.0f32, 2.0, 3.0, 4.0] {
+        pfm.extend_from_slice(&v.to_be_bytes());
+    }
+    let d = read_pfm(&pfm[..]).unwrap();
+    assert_eq!(d[[0, 0]], color(3.0, 3.0, 3.0));
+    assert_eq!(d[[1, 1]], color(2.0, 2.0, 2.0));
+
+    // Radiance HDR keeps the samples with the RGBE precision, run-length encoded rows are read back
+    let mut hdr = vec![];
+    c.write_image(&mut hdr, ImageFormat::Hdr).unwrap();
+    assert!(hdr.starts_with(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 3 +X 20\n"));
+    assert!(hdr.len() < 128 + 20 * 3 * 4);
+    let d = read_hdr(&hdr[..]).unwrap();
+    assert_eq!((d.width, d.height), (20, 3));
+    for y in 0..3 {
+        for x in 0..20 {
+            let (a, b) = (c[[x, y]], d[[x, y]]);
+            let max = a.r.max(a.g).max(a.b);
+            for (va, vb) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+                assert!((va - vb).abs() <= max / 128.0);
+            }
+        }
+    }
+    assert_eq!(d[[0, 2]], Color::black());
+
+    // Unbounded and invalid samples are stored as the largest value and as 0
+    let mut c = Canvas::new(3, 1, Color::black());
+    let _ = c.write(0, 0, color(f64::INFINITY, 1.0, 0.5));
+    let _ = c.write(1, 0, color(f64::NAN, 1e300, -f64::INFINITY));
+    let _ = c.write(2, 0, color(1e-35, 0.0, 0.0));
+    let mut hdr = vec![];
+    c.write_image(&mut hdr, ImageFormat::Hdr).unwrap();
+    let d = read_hdr(&hdr[..]).unwrap();
+    let largest = 255.5 * 2f64.powi(119);
+    let (inf, nan) = (d[[0, 0]], d[[1, 0]]);
+    assert_eq!((inf.r, nan.g), (largest, largest));
+    assert!([inf.g, inf.b, nan.r, nan.b].iter().all(|v| *v < largest / 128.0));
+    assert_eq!(d[[2, 0]], Color::black());
+
+    // Narrow images are stored as flat rows
+    let mut c = Canvas::new(2, 1, Color::black());
+    let _ = c.write(1, 0, color(1.0, 0.5, 0.25));
+    let mut hdr = vec![];
+    c.write_image(&mut hdr, ImageFormat::Hdr).unwrap();
+    assert!(hdr.ends_with(&[0, 0, 0, 0, 128, 64, 32, 129]));
+    let d = read_hdr(&hdr[..]).unwrap();
+    assert!((d[[1, 0]].r - 1.0).abs() < 0.01);
+    assert!((d[[1, 0]].b - 0.25).abs() < 0.01);
+
+    // Malformed files are reported as errors
+    assert!(read_pfm(&b"P6\n1 1\n255\n"[..]).is_err());
+    assert!(read_pfm(&b"PF\n2 2\n-1.0\n\0\0"[..]).is_err());
+    assert!(read_hdr(&b"#?RADIANCE\n\n+Y 1 +X 1\n"[..]).is_err());
+    assert!(Canvas::read_image("img/clock.ppm").is_err());
+}
+
+#[test]
+fn tone_mapping() {
+    let c = color(0.5, 0.25, 2.0);
+
+    // The default stage keeps the values unchanged
+    assert_eq!(PostProcess::default().apply(&c), c);
+
+    // Every stop of exposure doubles the radiance
+    let post = PostProcess::new(1.0, ToneMap::None, Transfer::Linear);
+    assert_eq!(post.apply(&c), color(1.0, 0.5, 4.0));
+    let post = PostProcess::new(-2.0, ToneMap::None, Transfer::Linear);
+    assert_eq!(post.apply(&c), color(0.125, 0.0625, 0.5));
+
+    // Clamping
+    let post = PostProcess::new(0.0, ToneMap::Clamp, Transfer::Linear);
+    assert_eq!(post.apply(&color(-1.0, 0.5, 3.0)), color(0.0, 0.5, 1.0));
+
+    // Reinhard compresses the luminance and keeps the hue
+    let post = PostProcess::new(0.0, ToneMap::Reinhard, Transfer::Linear);
+    assert_eq!(post.apply(&color(1.0, 1.0, 1.0)), color(0.5, 0.5, 0.5));
+    let res = post.apply(&c);
+    fassert!(luminance(&res), luminance(&c) / (1.0 + luminance(&c)));
+    fassert!(res.b / res.r, 4.0);
+    assert_eq!(post.apply(&Color::black()), Color::black());
+
+    // ACES filmic curve maps black to black and saturates highlights
+    let post = PostProcess::new(0.0, ToneMap::Aces, Transfer::Linear);
+    assert_eq!(post.apply(&Color::black()), Color::black());
+    assert_eq!(post.apply(&color(0.18, 0.18, 0.18)), color(0.2669, 0.2669, 0.2669));
+    assert_eq!(post.apply(&color(100.0, 100.0, 100.0)), color(1.0, 1.0, 1.0));
+
+    // sRGB transfer encoding
+    fassert!(srgb_encode(0.0), 0.0);
+    fassert!(srgb_encode(0.002), 0.02584);
+    fassert!(srgb_encode(0.5), 0.73536);
+    fassert!(srgb_encode(1.0), 1.0);
+    fassert!(srgb_decode(srgb_encode(0.3)), 0.3);
+
+    // Every output can be configured separately
+    let mut cv = Canvas::new(1, 1, Color::black());
+    let _ = cv.write(0, 0, color(0.5, 0.5, 0.5));
+    let mut linear = vec![];
+    cv.write_image(&mut linear, ImageFormat::Ppm(PpmFormat::default())).unwrap();
+    assert!(linear.ends_with(b"128 128 128\n"));
+    let mut encoded = vec![];
+    let post = PostProcess::new(0.0, ToneMap::Clamp, Transfer::Srgb);
+    cv.write_image_with(&mut encoded, ImageFormat::Ppm(PpmFormat::default()), &post)
+        .unwrap();
+    assert!(encoded.ends_with(b"188 188 188\n"));
+    let mut pfm = vec![];
+    cv.write_image_with(&mut pfm, ImageFormat::Pfm, &PostProcess::new(1.0, ToneMap::None, Transfer::Linear))
+        .unwrap();
+    assert_eq!(read_pfm(&pfm[..]).unwrap()[[0, 0]], color(1.0, 1.0, 1.0));
+}
+
+/// Sink that always fails
+struct BrokenSink;
+
+impl std::io::Write for BrokenSink {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("broken sink"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn image_files_and_errors() {
+    let dir = std::env::temp_dir().join(format!("ray_tracer_images_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut c = Canvas::new(4, 2, Color::black());
+    let _ = c.write(1, 1, color(0.25, 0.5, 4.0));
+
+    // Writing to any path, the format is chosen by the extension
+    c.save(dir.join("a.png")).unwrap();
+    c.save(dir.join("a.pfm")).unwrap();
+    c.to_ppm(dir.join("a.ppm")).unwrap();
+    let ppm = std::fs::read_to_string(dir.join("a.ppm")).unwrap();
+    assert!(ppm.starts_with("P3\n4 2\n255\n"));
+    let d = Canvas::read_image(dir.join("a.pfm")).unwrap();
+    assert_eq!(d[[1, 1]], color(0.25, 0.5, 4.0));
+
+    // Unknown extensions are reported instead of guessed
+    match c.save(dir.join("a.bmp")) {
+        Err(ImageError::UnsupportedFormat(p)) => assert_eq!(p, dir.join("a.bmp")),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // Failures of files keep the path and the underlying io::Error
+    let missing = dir.join("missing").join("a.ppm");
+    match c.to_ppm(&missing) {
+        Err(ImageError::File { path, source }) => {
+            assert_eq!(path, missing);
+            assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    let err = Canvas::read_image(dir.join("missing.hdr")).unwrap_err();
+    assert!(err.to_string().contains("missing.hdr"));
+
+    // Failures of sinks are returned as well
+    match c.write_image(BrokenSink, ImageFormat::Png(PngDepth::Eight)) {
+        Err(ImageError::Io(e)) => assert_eq!(e.to_string(), "broken sink"),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 #[test]
 fn matrix_operations() {
     // Matrix equality
@@ -792,3 +1180,1878 @@ fn check_patterns() {
     assert_eq!(pattern.get(&point(0.5, 0.0, 0.0)).unwrap(), color(0.5, 0.5, 0.5));
     assert_eq!(pattern.get(&point(0.75, 0.0, 0.0)).unwrap(), color(0.25, 0.25, 0.25));
 }
+
+#[test]
+fn yaml_scenes() {
+    // The default world described in YAML
+    let src = "
+- add: camera
+  width: 20
+  height: 10
+  field-of-view: 1.5708
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: light
+  at: [-10, 10, -10]
+  intensity: [1, 1, 1]
+
+- define: outer
+  value:
+    color: [0.8, 1.0, 0.6]
+    diffuse: 0.7
+    specular: 0.2
+
+- define: half
+  value:
+    - [scale, 0.5, 0.5, 0.5]
+
+- add: sphere
+  material: outer
+
+- add: sphere
+  transform: half
+";
+    let r = load_yaml(src).unwrap();
+    let w = World::default();
+    assert_eq!(r.world.objects.len(), 2);
+    assert_eq!(r.world.sources.len(), 1);
+    assert_eq!((r.canvas().width, r.canvas().height), (20, 10));
+    assert_eq!(
+        r.world.objects[0].borrow().get_material(),
+        w.objects[0].borrow().get_material()
+    );
+    massert!(
+        r.world.objects[1].borrow().get_transform().matrix(),
+        w.objects[1].borrow().get_transform().matrix()
+    );
+    let ray = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    assert_eq!(r.world.calc(&ray, &Color::black()), color(0.38066, 0.47583, 0.2855));
+
+    // Defines can be extended, and lists of transformations are spliced
+    let src = "
+- add: camera
+  width: 10
+  height: 10
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+- define: base
+  value:
+    color: [1, 0, 0]
+    ambient: 0.5
+- define: derived
+  extend: base
+  value:
+    color: [0, 0, 1]
+    pattern:
+      type: stripes
+      colors: [[1, 1, 1], [0, 0, 0]]
+      transform:
+        - [scale, 2, 2, 2]
+- define: lift
+  value:
+    - [translate, 0, 1, 0]
+- add: plane
+  material: derived
+  transform:
+    - [rotate-x, 1.5707963]
+    - lift
+";
+    let r = load_yaml(src).unwrap();
+    let obj = r.world.objects[0].borrow();
+    assert_eq!(obj.get_material().color, color(0.0, 0.0, 1.0));
+    fassert!(obj.get_material().ambient, 0.5);
+    massert!(
+        obj.get_transform().matrix(),
+        transform!(TUnit::RotateX(PI / 2.0), TUnit::Translate(0.0, 1.0, 0.0)).matrix()
+    );
+    drop(obj);
+    let c = r.world.objects[0].borrow_mut().get_pattern().get(&point(2.5, 1.0, 0.0));
+    assert_eq!(c.unwrap(), Color::black());
+
+    // Errors point to the line of the problem
+    let line_of = |src: &str| match load_yaml(src) {
+        Err(SceneError::Parse { line, .. }) => line,
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("the scene must not load"),
+    };
+    let header = "- add: camera\n  width: 10\n  height: 10\n  field-of-view: 1\n  from: [0, 0, -5]\n  to: [0, 0, 0]\n  up: [0, 1, 0]\n";
+    assert_eq!(line_of(&format!("{}- add: sphere\n  material: missing\n", header)), Some(9));
+    assert_eq!(line_of(&format!("{}- add: sphere\n  material:\n    difuse: 0.5\n", header)), Some(10));
+    assert_eq!(line_of(&format!("{}- add: cube\n", header)), Some(8));
+    assert_eq!(line_of(&format!("{}- add: sphere\n  transform:\n    - [spin, 1]\n", header)), Some(9));
+    assert_eq!(line_of(&format!("{}- add: light\n  at: [1, 2]\n  intensity: [1, 1, 1]\n", header)), Some(9));
+    assert_eq!(line_of("- add: sphere\n  transform: [\n"), Some(3));
+    assert_eq!(line_of("- add: sphere\n"), None);
+    let e = load_yaml(&format!("{}- add: sphere\n  material: missing\n", header)).err().unwrap();
+    assert_eq!(e.to_string(), "line 9: `missing` is not defined");
+
+    // Loading a scene file
+    let r = load_yaml_file(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/patterns.yaml")).unwrap();
+    assert_eq!(r.world.objects.len(), 4);
+    assert_eq!(r.world.sources.len(), 1);
+    assert!(matches!(load_yaml_file("missing.yaml"), Err(SceneError::Io { .. })));
+}
+
+#[test]
+fn json_scenes() {
+    // Transformation is serialized as the list of its units
+    let t = transform!(TUnit::Scale(2.0, 1.0, 1.0), TUnit::RotateY(0.5));
+    let json = serde_json::to_string(&t).unwrap();
+    assert_eq!(json, r#"[{"Scale":[2.0,1.0,1.0]},{"RotateY":0.5}]"#);
+    let loaded: Transformation = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.units(), t.units());
+    massert!(loaded.matrix(), t.matrix());
+
+    // Material keeps its pattern
+    let mut m = Material::default();
+    let mut p = Pattern::default(PatternList::GradientPattern);
+    p.add_tunit(TUnit::Translate(0.5, 0.0, 0.0));
+    m.change_pattern(p);
+    let loaded: Material = serde_json::from_str(&serde_json::to_string(&m).unwrap()).unwrap();
+    assert_eq!(loaded, m);
+
+    // A serialized-then-loaded World renders identically to the original
+    let mut r = Renderer::new(
+        40,
+        20,
+        PI / 3.0,
+        point(0.0, 1.5, -5.0),
+        point(0.0, 1.0, 0.0),
+        vector(0.0, 1.0, 0.0),
+        color(0.1, 0.1, 0.2),
+    );
+    let mut floor = Plane::default();
+    floor.get_material_mut().specular = 0.0;
+    let mut floor_pattern = Pattern::default(PatternList::StripePattern);
+    floor_pattern.set_colors(color(0.83, 0.83, 0.83), color(0.9, 1.0, 1.0));
+    floor_pattern.add_tunit(TUnit::RotateY(PI / 5.0));
+    floor.set_pattern(floor_pattern);
+    let mut ball = Sphere::default();
+    ball.set_transform(transform!(TUnit::Scale(0.5, 0.5, 0.5), TUnit::Translate(0.5, 0.5, -0.5)));
+    ball.get_material_mut().color = color(0.5, 1.0, 0.1);
+    let mut ball_pattern = Pattern::default(PatternList::GradientPattern);
+    ball_pattern.set_colors(color(1.0, 0.0, 0.0), color(1.0, 0.65, 0.0));
+    ball.set_pattern(ball_pattern);
+    r.world.add_objs(vec![floor.wrap(), ball.wrap()]);
+    r.world.add_src(PointLight::new(point(-10.0, 10.0, -10.0), color(1.0, 1.0, 1.0)).wrap_box());
+
+    let json = to_json(&r).unwrap();
+    let mut loaded = load_json(&json).unwrap();
+    assert_eq!(loaded.world.objects.len(), 2);
+    assert_eq!(loaded.canvas().bg, color(0.1, 0.1, 0.2));
+    massert!(loaded.camera().vtm, r.camera().vtm);
+    r.render();
+    loaded.render();
+    for y in 0..20 {
+        for x in 0..40 {
+            let (a, b) = (r.canvas()[[x, y]], loaded.canvas()[[x, y]]);
+            assert!((a.r, a.g, a.b) == (b.r, b.g, b.b));
+        }
+    }
+    assert_eq!(to_json(&loaded).unwrap(), json);
+
+    // Objects without a serializable form are reported
+    let mut r = Renderer::new(1, 1, 1.0, point(0.0, 0.0, -5.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0), Color::black());
+    r.world.add_obj(crate::render::core::Shape::default().wrap());
+    assert!(matches!(to_json(&r), Err(SceneError::Serialize(_))));
+
+    // Errors of the JSON parser keep the line
+    match load_json("{\n  \"camera\": 1\n}") {
+        Err(SceneError::Parse { line, .. }) => assert_eq!(line, Some(2)),
+        _ => panic!("the scene must not load"),
+    }
+}
+
+#[test]
+fn sampling_and_parallel_rendering() {
+    // Sample streams are deterministic and differ between samples
+    let (mut a, mut b) = (Sampler::for_pixel(3, 7, 0), Sampler::for_pixel(3, 7, 0));
+    let mut c = Sampler::for_pixel(3, 7, 1);
+    for _ in 0..16 {
+        let v = a.next_f64();
+        assert!((0.0..1.0).contains(&v));
+        assert_eq!(v, b.next_f64());
+    }
+    assert_ne!(Sampler::for_pixel(3, 7, 0).next_2d(), c.next_2d());
+
+    // The pixel's center is sampled by ray_for_pixel
+    let mut cam = Camera::new(201, 101, PI / 2.0);
+    cam.set_view(point(0.0, 0.0, -5.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+    let (r1, r2) = (cam.ray_for_pixel(10, 20), cam.ray_for_subpixel(10, 20, 0.5, 0.5));
+    vassert!(r1.direction, r2.direction);
+
+    // Resizing keeps the field of view and the view transformation
+    let mut resized = cam.clone();
+    resized.resize(402, 202);
+    fassert!(resized.fov, cam.fov);
+    massert!(resized.vtm, cam.vtm);
+    vassert!(resized.ray_for_subpixel(201, 101, 0.0, 0.0).direction, cam.ray_for_pixel(100, 50).direction);
+
+    let build = || -> Result<Renderer, SceneError> {
+        let mut r = Renderer::with_camera(World::default(), Camera::new(23, 17, PI / 3.0), color(0.1, 0.1, 0.1));
+        r.camera_mut().set_view(point(0.0, 0.5, -4.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        Ok(r)
+    };
+
+    // Parallel rendering gives the same image as the serial one
+    let mut serial = build().unwrap();
+    serial.render();
+    let parallel = render_parallel(build, 4).unwrap();
+    for y in 0..17 {
+        assert_eq!(serial.canvas().row(y), parallel.canvas().row(y));
+    }
+
+    // Several samples per pixel average jittered rays
+    let mut r = build().unwrap();
+    r.spp = 8;
+    r.render();
+    let mut sum = Color::black();
+    for s in 0..8 {
+        let (dx, dy) = Sampler::for_pixel(11, 8, s).next_2d();
+        let ray = r.camera().ray_for_subpixel(11, 8, dx, dy);
+        sum = sum + r.world.calc(&ray, &r.canvas().bg);
+    }
+    let px = r.canvas()[[11, 8]];
+    fassert!(px.r, sum.r / 8.0);
+    fassert!(px.g, sum.g / 8.0);
+
+    // Errors of the scene builder are returned
+    let failing = || -> Result<Renderer, SceneError> { Err(SceneError::parse(None, "broken")) };
+    assert!(render_parallel(failing, 3).is_err());
+}
+
+#[test]
+fn progress_and_cancellation() {
+    let build = || -> Result<Renderer, SceneError> {
+        let mut r = Renderer::with_camera(World::default(), Camera::new(11, 9, PI / 3.0), color(0.1, 0.1, 0.1));
+        r.camera_mut().set_view(point(0.0, 0.5, -4.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        r.tile_size = 3;
+        Ok(r)
+    };
+    let mut full = build().unwrap();
+    full.render();
+
+    // Progress estimates the remaining time from the finished work
+    let p = Progress::new(1, 4, Duration::from_secs(2));
+    assert_eq!(p.eta, Some(Duration::from_secs(6)));
+    fassert!(p.fraction(), 0.25);
+    assert_eq!(Progress::new(0, 4, Duration::from_secs(2)).eta, None);
+
+    // The observer is called after every tile (4x3 tiles of 3x3 px)
+    let mut r = build().unwrap();
+    let mut seen = vec![];
+    let status = r.render_with(&mut |p: &Progress| seen.push((p.done, p.total)), &CancelToken::new());
+    assert_eq!(status, RenderStatus::Completed);
+    assert_eq!(seen, (1..=12).map(|d| (d, 12)).collect::<Vec<_>>());
+
+    // Cancelling keeps the tiles rendered so far (the first row of tiles)
+    let mut r = build().unwrap();
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let status = r.render_with(
+        &mut |p: &Progress| {
+            if p.done == 4 {
+                token.cancel();
+            }
+        },
+        &cancel,
+    );
+    assert_eq!(status, RenderStatus::Cancelled);
+    assert_eq!(r.canvas().row(2), full.canvas().row(2));
+    assert_eq!(r.canvas().row(3), vec![Color::default(); 11].as_slice());
+
+    // Parallel renders report every tile once and can be cancelled as well
+    let mut count = 0;
+    let (r, status) = render_parallel_with(build, 3, &mut |_: &Progress| count += 1, &CancelToken::new()).unwrap();
+    assert_eq!((status, count), (RenderStatus::Completed, 12));
+    assert_eq!(r.canvas().row(8), full.canvas().row(8));
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let (_, status) = render_parallel_with(build, 3, &mut NoProgress, &cancel).unwrap();
+    assert_eq!(status, RenderStatus::Cancelled);
+}
+
+#[test]
+fn tiles_and_checkpoints() {
+    use crate::render::integrator;
+    use crate::render::spectrum::Spectral;
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join(format!("ray_tracer_checkpoints_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // The grid covers the image, the tiles on the edges are clipped
+    let grid = tile_grid(10, 7, 4);
+    assert_eq!(grid.len(), 6);
+    assert_eq!(grid[2], Tile { x0: 8, y0: 0, x1: 10, y1: 4 });
+    assert_eq!(grid[5], Tile { x0: 8, y0: 4, x1: 10, y1: 7 });
+    assert_eq!(grid.iter().map(|t| t.len()).sum::<usize>(), 70);
+
+    // Checkpoints are stored losslessly
+    let mut ck = Checkpoint::new(10, 7, 4, 2).with_settings("path", 5, 0xfedcba9876543210);
+    ck.insert(4, vec![color(0.1, 1.0 / 3.0, 7.5); 12]);
+    let mut buf = vec![];
+    ck.write(&mut buf).unwrap();
+    let loaded = Checkpoint::read(buf.as_slice()).unwrap();
+    assert_eq!(loaded, ck);
+    assert_eq!(loaded.pending(), vec![0, 1, 2, 3, 5]);
+    assert!(Checkpoint::read(&buf[..buf.len() - 1]).is_err());
+    assert!(Checkpoint::read(&b"P6\n1 1\n255\n"[..]).is_err());
+
+    let build = || -> Result<Renderer, SceneError> {
+        let mut r = Renderer::with_camera(World::default(), Camera::new(13, 10, PI / 3.0), color(0.1, 0.1, 0.1));
+        r.camera_mut().set_view(point(0.0, 0.5, -4.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        r.tile_size = 4;
+        r.spp = 3;
+        Ok(r)
+    };
+    let mut full = build().unwrap();
+    full.render();
+
+    // A cancelled render is resumed from its checkpoint into the same image
+    let path = dir.join("serial.ckpt");
+    let _ = std::fs::remove_file(&path);
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let mut stop_at_5 = |p: &Progress| {
+        if p.done == 5 {
+            token.cancel();
+        }
+    };
+    let mut r = build().unwrap();
+    let status = r.render_resumable(&path, Duration::ZERO, &mut stop_at_5, &cancel).unwrap();
+    assert_eq!(status, RenderStatus::Cancelled);
+    assert_eq!(Checkpoint::load(&path).unwrap().finished(), 5);
+
+    let mut resumed = build().unwrap();
+    let mut first = None;
+    let status = resumed
+        .render_resumable(&path, Duration::from_secs(3600), &mut |p: &Progress| { first.get_or_insert(p.done); }, &CancelToken::new())
+        .unwrap();
+    assert_eq!((status, first), (RenderStatus::Completed, Some(6)));
+    for y in 0..10 {
+        assert_eq!(resumed.canvas().row(y), full.canvas().row(y));
+    }
+
+    // The same works on several threads
+    let path = dir.join("parallel.ckpt");
+    let _ = std::fs::remove_file(&path);
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let (_, status) = render_parallel_resumable(build, 3, &path, Duration::ZERO, &mut NoProgress, &cancel).unwrap();
+    assert_eq!(status, RenderStatus::Cancelled);
+    let mut ck = Checkpoint::load(&path).unwrap();
+    ck.insert(0, full.sample_tile(&ck.grid()[0]));
+    ck.save(&path).unwrap();
+    let (r, status) = render_parallel_resumable(build, 3, &path, Duration::ZERO, &mut NoProgress, &CancelToken::new()).unwrap();
+    assert_eq!(status, RenderStatus::Completed);
+    for y in 0..10 {
+        assert_eq!(r.canvas().row(y), full.canvas().row(y));
+    }
+
+    // Checkpoints of other renders are refused: other settings, integrators or scenes
+    let changes: [fn(&mut Renderer); 5] = [
+        |r| r.spp = 4,
+        |r| r.max_depth = 2,
+        |r| r.integrator = integrator::from_name("path").unwrap(),
+        |r| r.integrator = Arc::new(Spectral::new(r.integrator.clone())),
+        |r| r.world.objects.push(Sphere::default().wrap()),
+    ];
+    for change in changes {
+        let other = || -> Result<Renderer, SceneError> {
+            let mut r = build()?;
+            change(&mut r);
+            Ok(r)
+        };
+        match render_parallel_resumable(other, 2, &path, Duration::ZERO, &mut NoProgress, &CancelToken::new()) {
+            Err(RenderError::Checkpoint(ImageError::File { path: p, source })) => {
+                assert_eq!(p, path);
+                assert_eq!(source.kind(), std::io::ErrorKind::InvalidData);
+            }
+            res => panic!("unexpected result {:?}", res.map(|(_, s)| s)),
+        }
+    }
+    let ck = Checkpoint::load(&path).unwrap();
+    assert_eq!((ck.integrator.as_str(), ck.max_depth), ("whitted", build().unwrap().max_depth));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn progressive_rendering() {
+    let build = || {
+        let mut r = Renderer::with_camera(World::default(), Camera::new(9, 7, PI / 3.0), color(0.1, 0.1, 0.1));
+        r.camera_mut().set_view(point(0.0, 0.5, -4.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        r
+    };
+
+    // The accumulation buffer averages the passes
+    let mut acc = Accumulator::new(2, 1);
+    assert_eq!(acc.average(0, 0), Color::black());
+    acc.add_pass(&[color(1.0, 0.0, 0.0), color(0.0, 1.0, 0.0)]);
+    acc.add_pass(&[color(0.0, 0.0, 1.0), color(0.0, 1.0, 0.0)]);
+    assert_eq!(acc.samples(), 2);
+    assert_eq!(acc.average(0, 0), color(0.5, 0.0, 0.5));
+    assert_eq!(acc.average(1, 0), color(0.0, 1.0, 0.0));
+
+    // Budgets stop at whichever limit comes first
+    let budget = Budget::samples(4).with_time(Duration::from_secs(10));
+    assert!(!budget.reached(3, Duration::from_secs(9)));
+    assert!(budget.reached(4, Duration::ZERO));
+    assert!(budget.reached(0, Duration::from_secs(10)));
+    assert!(!Budget::default().reached(1000, Duration::from_secs(1000)));
+
+    // Every pass exposes the current average, and the result matches a render with the same spp
+    let mut r = build();
+    let mut acc = Accumulator::new(9, 7);
+    let mut passes = vec![];
+    let status = r.render_progressive(
+        &mut acc,
+        Budget::samples(3),
+        &mut |cv: &Canvas, p: &Progress| passes.push((p.done, p.total, cv[[4, 3]])),
+        &CancelToken::new(),
+    );
+    assert_eq!(status, RenderStatus::Completed);
+    assert_eq!(passes.iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), vec![(1, 3), (2, 3), (3, 3)]);
+    assert_eq!(passes[0].2, r.sample(4, 3, 0));
+
+    let mut reference = build();
+    reference.spp = 3;
+    reference.render();
+    for y in 0..7 {
+        assert_eq!(r.canvas().row(y), reference.canvas().row(y));
+    }
+
+    // Rendering continues from the samples in the accumulator
+    let status = r.render_progressive(&mut acc, Budget::samples(5), &mut NoProgress, &CancelToken::new());
+    assert_eq!((status, acc.samples()), (RenderStatus::Completed, 5));
+    reference.spp = 5;
+    reference.render();
+    assert_eq!(r.canvas().row(3), reference.canvas().row(3));
+
+    // A spent time budget adds no pass, and cancelling discards the unfinished pass
+    let mut acc = Accumulator::new(9, 7);
+    r.render_progressive(&mut acc, Budget::time(Duration::ZERO), &mut NoProgress, &CancelToken::new());
+    assert_eq!(acc.samples(), 0);
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+    let status = r.render_progressive(
+        &mut acc,
+        Budget::default(),
+        &mut |_: &Canvas, p: &Progress| {
+            if p.done == 2 {
+                token.cancel();
+            }
+        },
+        &cancel,
+    );
+    assert_eq!((status, acc.samples()), (RenderStatus::Cancelled, 2));
+}
+
+#[test]
+fn output_variables() {
+    assert_eq!("object-id".parse::<Aov>(), Ok(Aov::ObjectId));
+    assert!("beauty".parse::<Aov>().is_err());
+    assert!(Aov::ALL.iter().all(|a| a.name().parse::<Aov>() == Ok(*a)));
+
+    // Albedo is the pattern if the material has one
+    let mut m = Material::default();
+    m.color = color(0.2, 0.3, 0.4);
+    assert_eq!(m.albedo(&point(0.5, 0.0, 0.0)), color(0.2, 0.3, 0.4));
+    m.change_pattern(Pattern::default(PatternList::StripePattern));
+    assert_eq!(m.albedo(&point(1.5, 0.0, 0.0)), Pattern::default(PatternList::StripePattern).get(&point(1.5, 0.0, 0.0)).unwrap());
+
+    // Visibility counts the light sources that are not blocked
+    let mut w = World::default();
+    w.add_src(PointLight::new(point(0.0, 0.0, 20.0), color(1.0, 1.0, 1.0)).wrap_box());
+    fassert!(w.visibility(&point(0.0, 0.0, -1.0 - EPSILON)), 0.5);
+    fassert!(w.visibility(&point(-2.0, 2.0, -2.0)), 1.0);
+    fassert!(World::new().visibility(&point(0.0, 0.0, 0.0)), 0.0);
+    assert_eq!(w.index_of(&w.objects[1].clone()), Some(1));
+    assert_eq!(w.index_of(&Sphere::default().wrap()), None);
+
+    let build = || -> Result<Renderer, SceneError> {
+        let mut c = Camera::new(11, 11, PI / 2.0);
+        c.set_view(point(0.0, 0.0, -5.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        let mut r = Renderer::with_camera(World::default(), c, color(0.5, 0.5, 0.5));
+        for aov in Aov::ALL {
+            r.enable_aov(aov);
+        }
+        Ok(r)
+    };
+
+    // Every variable is rendered for the first hit of the pixel's center ray
+    let mut r = build().unwrap();
+    assert!(r.aov(Aov::Depth).is_some());
+    r.render();
+    let at = |aov: Aov, x: usize, y: usize| r.aov(aov).unwrap()[[x, y]];
+    assert_eq!(at(Aov::Depth, 5, 5), color(4.0, 4.0, 4.0));
+    let n = at(Aov::Normal, 5, 5);
+    vassert!(vector(n.r, n.g, n.b), vector(0.0, 0.0, -1.0));
+    assert_eq!(at(Aov::Albedo, 5, 5), color(0.8, 1.0, 0.6));
+    assert_eq!(at(Aov::ObjectId, 5, 5), color(1.0, 1.0, 1.0));
+    assert_eq!(at(Aov::Visibility, 5, 5), color(1.0, 1.0, 1.0));
+    for aov in Aov::ALL {
+        assert_eq!(at(aov, 0, 0), Color::black());
+    }
+
+    // Parallel renders fill the variables as well, and they can be exported
+    let p = render_parallel(build, 3).unwrap();
+    for aov in Aov::ALL {
+        for y in 0..11 {
+            assert_eq!(p.aov(aov).unwrap().row(y), r.aov(aov).unwrap().row(y));
+        }
+    }
+
+    let dir = std::env::temp_dir().join(format!("ray_tracer_aovs_{}", std::process::id()));
+    let mut r = build().unwrap();
+    r.set_output_dir(&dir);
+    r.resize(4, 4);
+    r.render();
+    r.generate_aov(Aov::Normal, "normal.pfm").unwrap();
+    let loaded = Canvas::read_image(dir.join("normal.pfm")).unwrap();
+    for y in 0..4 {
+        assert_eq!(loaded.row(y).len(), 4);
+        for x in 0..4 {
+            let (a, b) = (loaded[[x, y]], r.aov(Aov::Normal).unwrap()[[x, y]]);
+            fassert!(a.r, b.r);
+            fassert!(a.b, b.b);
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn orthographic_camera() {
+    // Rays of an orthographic camera are parallel and start on the view rectangle
+    let c = Camera::orthographic(200, 100, 4.0);
+    fassert!(c.px_size, 0.02);
+    fassert!(c.hh, 1.0);
+    let r = c.ray_for_pixel(100, 50);
+    vassert!(r.origin, point(-0.01, -0.01, 0.0));
+    vassert!(r.direction, vector(0.0, 0.0, -1.0));
+    let r = c.ray_for_pixel(0, 0);
+    vassert!(r.origin, point(1.99, 0.99, 0.0));
+    vassert!(r.direction, vector(0.0, 0.0, -1.0));
+
+    // The view transformation moves and turns the view rectangle
+    let mut c = Camera::orthographic(11, 11, 2.6);
+    c.set_view(point(0.0, 0.0, -5.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+    let r = c.ray_for_pixel(5, 5);
+    vassert!(r.origin, point(0.0, 0.0, -5.0));
+    vassert!(r.direction, vector(0.0, 0.0, 1.0));
+
+    // Resizing keeps the view width, and the size of objects does not depend on their distance
+    let mut far = c.clone();
+    far.set_view(point(0.0, 0.0, -50.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+    for cam in [&c, &far] {
+        let mut r = Renderer::with_camera(World::default(), cam.clone(), Color::black());
+        r.render();
+        let lit: Vec<bool> = (0..11).map(|x| r.canvas()[[x, 5]] != Color::black()).collect();
+        assert_eq!(lit, [false, true, true, true, true, true, true, true, true, true, false]);
+    }
+    c.resize(26, 11);
+    fassert!(c.px_size, 0.1);
+    fassert!(c.hh, 0.55);
+
+    // Scenes choose the projection, older JSON scenes stay perspective
+    let src = "
+- add: camera
+  width: 20
+  height: 10
+  projection: orthographic
+  view-width: 8
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+";
+    let r = load_yaml(src).unwrap();
+    assert_eq!(r.camera().projection, Projection::Orthographic { width: 8.0 });
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    assert_eq!(loaded.camera().projection, Projection::Orthographic { width: 8.0 });
+    fassert!(loaded.camera().hh, 2.0);
+
+    let mut json: serde_json::Value = serde_json::to_value(Camera::new(4, 4, 1.0)).unwrap();
+    json.as_object_mut().unwrap().remove("projection");
+    let old: Camera = serde_json::from_value(json).unwrap();
+    assert_eq!(old.projection, Projection::Perspective);
+
+    let bad = src.replace("view-width: 8", "");
+    assert!(matches!(load_yaml(&bad), Err(SceneError::Parse { .. })));
+    let bad = src.replace("orthographic", "isometric");
+    match load_yaml(&bad) {
+        Err(SceneError::Parse { line, .. }) => assert_eq!(line, Some(5)),
+        _ => panic!("the scene must not load"),
+    }
+}
+
+#[test]
+fn panoramic_cameras() {
+    assert_eq!("fisheye".parse::<Projection>(), Ok(Projection::Fisheye));
+    assert_eq!("orthographic:2.5".parse::<Projection>(), Ok(Projection::Orthographic { width: 2.5 }));
+    assert!("orthographic:-1".parse::<Projection>().is_err());
+    assert!("pinhole".parse::<Projection>().is_err());
+
+    // Equirectangular: longitude along the columns, latitude along the rows
+    let c = Camera::with_projection(200, 100, 1.0, Projection::Equirectangular);
+    vassert!(c.ray_for_subpixel(100, 50, 0.0, 0.0).direction, vector(0.0, 0.0, -1.0));
+    vassert!(c.ray_for_subpixel(50, 50, 0.0, 0.0).direction, vector(1.0, 0.0, 0.0));
+    vassert!(c.ray_for_subpixel(150, 50, 0.0, 0.0).direction, vector(-1.0, 0.0, 0.0));
+    vassert!(c.ray_for_subpixel(0, 50, 0.0, 0.0).direction, vector(0.0, 0.0, 1.0));
+    vassert!(c.ray_for_subpixel(77, 0, 0.0, 0.0).direction, vector(0.0, 1.0, 0.0));
+    vassert!(c.ray_for_subpixel(100, 25, 0.0, 0.0).direction, vector(0.0, 0.5f64.sqrt(), -(0.5f64.sqrt())));
+
+    // Fisheye: the angle grows linearly with the distance from the center
+    let mut c = Camera::with_projection(100, 60, PI, Projection::Fisheye);
+    vassert!(c.ray_for_subpixel(50, 30, 0.0, 0.0).direction, vector(0.0, 0.0, -1.0));
+    vassert!(c.ray_for_subpixel(50, 0, 0.0, 0.0).direction, vector(0.0, 1.0, 0.0));
+    let d = c.ray_for_subpixel(65, 30, 0.0, 0.0).direction;
+    vassert!(d, vector(-(PI / 4.0).sin(), 0.0, -(PI / 4.0).cos()));
+    assert!(c.ray_for_subpixel(0, 30, 0.0, 0.0).direction.z > 0.0);
+
+    // Every pixel gets a ray, turned by the view transformation
+    c.set_view(point(1.0, 2.0, 3.0), point(2.0, 2.0, 3.0), vector(0.0, 1.0, 0.0));
+    let r = c.ray_for_subpixel(50, 30, 0.0, 0.0);
+    vassert!(r.origin, point(1.0, 2.0, 3.0));
+    vassert!(r.direction, vector(1.0, 0.0, 0.0));
+    for cam in [c.clone(), Camera::with_projection(16, 8, 1.0, Projection::Equirectangular)] {
+        for y in 0..cam.vsize {
+            for x in 0..cam.hsize {
+                fassert!(cam.ray_for_pixel(x, y).direction.magnitude(), 1.0);
+            }
+        }
+    }
+
+    // A panorama from inside the default world sees the inner sphere everywhere
+    let c = Camera::with_projection(16, 8, 1.0, Projection::Equirectangular);
+    let mut r = Renderer::with_camera(World::default(), c, Color::black());
+    r.enable_aov(Aov::ObjectId);
+    r.render();
+    for y in 0..8 {
+        assert!(r.aov(Aov::ObjectId).unwrap().row(y).iter().all(|c| *c == color(2.0, 2.0, 2.0)));
+    }
+
+    // Scenes select the projection
+    let src = "
+- add: camera
+  width: 20
+  height: 10
+  projection: equirectangular
+  from: [0, 0, 0]
+  to: [0, 0, 1]
+  up: [0, 1, 0]
+";
+    assert_eq!(load_yaml(src).unwrap().camera().projection, Projection::Equirectangular);
+    let fisheye = src.replace("equirectangular", "fisheye");
+    assert!(load_yaml(&fisheye).is_err());
+    let fisheye = fisheye.replace("  from:", "  field-of-view: 3.14\n  from:");
+    assert_eq!(load_yaml(&fisheye).unwrap().camera().projection, Projection::Fisheye);
+}
+
+#[test]
+fn stereo_rendering() {
+    let mut c = Camera::new(21, 11, PI / 3.0);
+    c.set_view(point(0.0, 0.0, -5.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+
+    // Parallel eyes are moved sideways and keep the view direction
+    let stereo = Stereo::new(0.5, 0.0, StereoMode::Parallel);
+    let (l, r) = (stereo.eye(&c, Eye::Left), stereo.eye(&c, Eye::Right));
+    vassert!(l.ray_for_subpixel(10, 5, 0.5, 0.5).origin, point(-0.25, 0.0, -5.0));
+    vassert!(r.ray_for_subpixel(10, 5, 0.5, 0.5).origin, point(0.25, 0.0, -5.0));
+    vassert!(l.ray_for_subpixel(10, 5, 0.5, 0.5).direction, vector(0.0, 0.0, 1.0));
+    vassert!(r.ray_for_subpixel(10, 5, 0.5, 0.5).direction, vector(0.0, 0.0, 1.0));
+    assert_eq!((l.hsize, l.vsize, l.fov), (21, 11, PI / 3.0));
+
+    // Toe-in eyes look at the point at the convergence distance
+    let stereo = Stereo::new(0.5, 5.0, StereoMode::ToeIn);
+    for eye in [Eye::Left, Eye::Right] {
+        let ray = stereo.eye(&c, eye).ray_for_subpixel(10, 5, 0.5, 0.5);
+        vassert!(ray.pos(5.0062460962), point(0.0, 0.0, 0.0));
+    }
+
+    // Both eyes are rendered from the Renderer's camera, which is restored
+    let mut r = Renderer::with_camera(World::default(), c.clone(), color(0.1, 0.1, 0.1));
+    let img = r.render_stereo(&Stereo::new(0.5, 0.0, StereoMode::Parallel));
+    massert!(r.camera().vtm, c.vtm);
+    let parallel = Stereo::new(0.5, 0.0, StereoMode::Parallel);
+    let mut eye = Renderer::with_camera(World::default(), parallel.eye(&c, Eye::Left), color(0.1, 0.1, 0.1));
+    eye.render();
+    assert_eq!(img.left.row(5), eye.canvas().row(5));
+    assert_ne!(img.left.row(5), img.right.row(5));
+
+    // Composite images place the eyes next to or over each other
+    let sbs = img.compose(StereoLayout::SideBySide);
+    assert_eq!((sbs.width, sbs.height), (42, 11));
+    assert_eq!(&sbs.row(3)[..21], img.left.row(3));
+    assert_eq!(&sbs.row(3)[21..], img.right.row(3));
+    let ou = img.compose(StereoLayout::OverUnder);
+    assert_eq!((ou.width, ou.height), (21, 22));
+    assert_eq!(ou.row(4), img.left.row(4));
+    assert_eq!(ou.row(15), img.right.row(4));
+
+    let dir = std::env::temp_dir().join(format!("ray_tracer_stereo_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    img.save(dir.join("sbs.pfm"), StereoLayout::SideBySide).unwrap();
+    img.save_eyes(dir.join("l.pfm"), dir.join("r.pfm")).unwrap();
+    assert_eq!(Canvas::read_image(dir.join("sbs.pfm")).unwrap().width, 42);
+    assert_eq!(Canvas::read_image(dir.join("r.pfm")).unwrap().width, 21);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn path_tracing() {
+    use crate::math::sampler::cosine_hemisphere;
+    use crate::render::integrator::{self, Integrator, PathTracer, Whitted};
+    use std::sync::Arc;
+
+    // Cosine-weighted directions are unit vectors around the normal with mean cosine 2/3
+    let n = vector(0.0, 0.6, -0.8);
+    let mut sampler = Sampler::new(7, 1);
+    let mut mean = 0.0;
+    for _ in 0..10000 {
+        let d = cosine_hemisphere(&n, sampler.next_2d());
+        fassert!(d.magnitude(), 1.0);
+        assert!(dot(&d, &n) >= 0.0);
+        mean += dot(&d, &n) / 10000.0;
+    }
+    assert!((mean - 2.0 / 3.0).abs() < 0.01);
+
+    let pt = PathTracer::default();
+    let ray = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    let world_with = |m: Material, light: Option<PointLight>| {
+        let mut s = Sphere::default();
+        *s.get_material_mut() = m;
+        let mut w = World::new();
+        w.add_obj(s.wrap());
+        if let Some(light) = light {
+            w.add_src(light.wrap_box());
+        }
+        w
+    };
+
+    // Emissive surfaces are seen directly
+    let mut m = Material::default();
+    m.emission = color(2.0, 1.0, 0.5);
+    m.diffuse = 0.0;
+    let w = world_with(m, None);
+    let c = pt.li(&w, &ray, &Color::black(), &mut Sampler::new(1, 1), 5);
+    assert_eq!(c, color(2.0, 1.0, 0.5));
+
+    // A diffuse convex object under a uniform sky reflects its albedo
+    let mut m = Material::default();
+    m.color = color(0.5, 0.25, 1.0);
+    m.diffuse = 1.0;
+    let w = world_with(m, None);
+    let c = pt.li(&w, &ray, &color(1.0, 1.0, 1.0), &mut Sampler::new(1, 1), 5);
+    assert_eq!(c, color(0.5, 0.25, 1.0));
+
+    // Next-event estimation matches the diffuse term of the Phong model
+    let mut m = Material::default();
+    m.ambient = 0.0;
+    m.specular = 0.0;
+    let w = world_with(m, Some(PointLight::new(point(-2.0, 3.0, -10.0), color(1.0, 0.8, 0.6))));
+    let c = pt.li(&w, &ray, &Color::black(), &mut Sampler::new(1, 1), 5);
+    let phong = Whitted.li(&w, &ray, &Color::black(), &mut Sampler::new(1, 1), 5);
+    assert_eq!(c, phong);
+
+    // Inside an emissive diffuse sphere the radiance converges to emission / (1 - albedo),
+    // Russian roulette keeps the estimate unbiased
+    let mut m = Material::default();
+    m.color = color(0.5, 0.5, 0.5);
+    m.diffuse = 1.0;
+    m.emission = color(1.0, 1.0, 1.0);
+    let w = world_with(m, None);
+    let inside = Ray::new(point(0.0, 0.0, 0.0), vector(0.0, 0.0, 1.0));
+    let mut sum = 0.0;
+    for s in 0..4000 {
+        sum += pt.li(&w, &inside, &Color::black(), &mut Sampler::for_pixel(0, 0, s), 64).r;
+    }
+    assert!((sum / 4000.0 - 2.0).abs() < 0.1);
+
+    // The path tracer is selected by name and shared with the render threads
+    assert_eq!(integrator::from_name("path").unwrap().name(), "path");
+    assert_eq!(integrator::from_name("whitted").unwrap().name(), "whitted");
+    assert!(integrator::from_name("bogus").is_err());
+    let build = || -> Result<Renderer, SceneError> {
+        let mut r = Renderer::with_camera(World::default(), Camera::new(13, 9, PI / 3.0), color(0.2, 0.2, 0.3));
+        r.camera_mut().set_view(point(0.0, 0.5, -4.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+        r.integrator = Arc::new(PathTracer::default());
+        r.spp = 2;
+        Ok(r)
+    };
+    let mut serial = build().unwrap();
+    serial.render();
+    let parallel = render_parallel(build, 3).unwrap();
+    for y in 0..9 {
+        assert_eq!(serial.canvas().row(y), parallel.canvas().row(y));
+    }
+    assert_ne!(serial.canvas()[[6, 4]], serial.canvas().bg);
+}
+
+#[test]
+fn emissive_materials() {
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+
+    // Spheres are sampled uniformly by area in world space
+    let mut s = Sphere::default();
+    s.set_transform(transform!(TUnit::Scale(2.0, 2.0, 2.0), TUnit::Translate(1.0, 2.0, 3.0)));
+    let mut sampler = Sampler::new(3, 0);
+    for _ in 0..100 {
+        let smp = s.sample_surface(sampler.next_2d()).unwrap();
+        fassert!((smp.p - point(1.0, 2.0, 3.0)).magnitude(), 2.0);
+        vassert!(smp.n, (smp.p - point(1.0, 2.0, 3.0)) * 0.5);
+        fassert!(smp.pdf, 1.0 / (16.0 * PI));
+    }
+
+    // The density follows non-uniform scaling: the mean of 1 / pdf is the area of the spheroid
+    let mut s = Sphere::default();
+    s.set_tunit(TUnit::Scale(1.0, 1.0, 2.0));
+    let e: f64 = 0.75_f64.sqrt();
+    let area = 2.0 * PI * (1.0 + 2.0 / e * e.asin());
+    let mut mean = 0.0;
+    for _ in 0..20000 {
+        mean += 1.0 / s.sample_surface(sampler.next_2d()).unwrap().pdf / 20000.0;
+    }
+    assert!((mean - area).abs() / area < 0.01);
+
+    // Infinite planes cannot be sampled
+    assert!(Plane::default().sample_surface((0.5, 0.5)).is_none());
+
+    // The World collects the emissive objects that can be sampled
+    let mut lamp = Sphere::default();
+    lamp.set_transform(transform!(TUnit::Scale(0.1, 0.1, 0.1), TUnit::Translate(0.0, 10.0, 0.0)));
+    lamp.get_material_mut().emission = color(10000.0, 5000.0, 10000.0);
+    lamp.get_material_mut().diffuse = 0.0;
+    let mut glowing_floor = Plane::default();
+    glowing_floor.get_material_mut().emission = color(0.5, 0.5, 0.5);
+    let mut w = World::new();
+    w.add_obj(Plane::default().wrap());
+    w.add_obj(lamp.wrap());
+    assert!(w.objects[1].borrow().get_material().is_emissive());
+    assert!(!w.objects[0].borrow().get_material().is_emissive());
+    assert_eq!(w.emitters().len(), 1);
+    let mut other = World::new();
+    other.add_obj(glowing_floor.wrap());
+    assert!(other.emitters().is_empty());
+
+    // A small emitter lights like a point light: E / PI = L * r^2 / d^2
+    let emitters = w.emitters();
+    let (p, n) = (point(0.0, 0.0001, 0.0), vector(0.0, 1.0, 0.0));
+    let mut mean = Color::black();
+    for _ in 0..4000 {
+        mean = mean + w.sample_emitters(&emitters, &p, &n, &mut sampler) * (1.0 / 4000.0);
+    }
+    assert!((mean.r - 1.0).abs() < 0.03);
+    assert!((mean.g - 0.5).abs() < 0.015);
+
+    // Both integrators light the floor with the emitter, the path tracer without counting it twice
+    let ray = Ray::new(point(0.0, 1.0, -1.0), vector(0.0, -1.0, 1.0).normalize());
+    let (mut whitted, mut path) = (Color::black(), Color::black());
+    for s in 0..4000 {
+        whitted = whitted + Whitted.li(&w, &ray, &Color::black(), &mut Sampler::for_pixel(0, 0, s), 5) * (1.0 / 4000.0);
+        path = path + PathTracer::default().li(&w, &ray, &Color::black(), &mut Sampler::for_pixel(0, 0, s), 5) * (1.0 / 4000.0);
+    }
+    assert!((whitted.r - 0.9).abs() < 0.03);
+    assert!((path.r - 0.9).abs() < 0.03);
+
+    // Emitters are seen directly, even without point lights
+    let ray = Ray::new(point(0.0, 10.0, -5.0), vector(0.0, 0.0, 1.0));
+    assert_eq!(Whitted.li(&w, &ray, &Color::black(), &mut sampler, 5), color(10000.0, 5000.0, 10000.0));
+    assert_eq!(PathTracer::default().li(&w, &ray, &Color::black(), &mut sampler, 5), color(10000.0, 5000.0, 10000.0));
+
+    // The emitters may be found once by the caller, and renders find them again every time
+    let ray = Ray::new(point(0.0, 1.0, -1.0), vector(0.0, -1.0, 1.0).normalize());
+    for s in 0..8 {
+        let li = PathTracer::default().li(&w, &ray, &Color::black(), &mut Sampler::for_pixel(0, 0, s), 5);
+        let li_with = PathTracer::default().li_with(&w, &emitters, &ray, &Color::black(), &mut Sampler::for_pixel(0, 0, s), 5);
+        assert_eq!(li, li_with);
+    }
+    let mut r = Renderer::with_camera(w, Camera::new(3, 3, 0.5), Color::black());
+    r.camera_mut().set_view(point(0.0, 1.0, -1.0), point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+    r.render();
+    let lit = r.canvas()[[1, 1]];
+    r.world.objects[1].borrow_mut().get_material_mut().emission = Color::black();
+    r.render();
+    assert!(r.canvas()[[1, 1]].r < 0.5 * lit.r);
+
+    // The emission is read from YAML scenes
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    emission: [4, 2, 1]
+";
+    let r = load_yaml(src).unwrap();
+    assert_eq!(r.world.emitters().len(), 1);
+    assert_eq!(r.world.objects[0].borrow().get_material().emission, color(4.0, 2.0, 1.0));
+}
+
+#[test]
+fn microfacet_materials() {
+    use crate::render::bsdf::{ggx_d, Bsdf, BsdfKind};
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+    use crate::math::sampler::cosine_hemisphere;
+
+    // The GGX distribution is normalized over the projected hemisphere
+    let z = vector(0.0, 0.0, 1.0);
+    let mut sampler = Sampler::new(11, 0);
+    let mut sum = 0.0;
+    for _ in 0..20000 {
+        let h = cosine_hemisphere(&z, sampler.next_2d());
+        sum += PI * ggx_d(&h, 0.5) / 20000.0;
+    }
+    assert!((sum - 1.0).abs() < 0.03);
+
+    // Phong materials stay Lambertian, metallic/roughness materials use the microfacet model
+    let mut m = Material::default();
+    assert_eq!(Bsdf::new(&m, &point(0.0, 0.0, 0.0)), Bsdf::Lambert { albedo: color(0.9, 0.9, 0.9) });
+    m.bsdf = BsdfKind::Microfacet { metallic: 1.0, roughness: 0.5 };
+    let bsdf = Bsdf::new(&m, &point(0.0, 0.0, 0.0));
+
+    // The BSDF is reciprocal, and samples carry their density and weight
+    let n = vector(0.0, 1.0, 0.0);
+    let wo = vector(0.3, 0.8, -0.2).normalize();
+    let wi = vector(-0.5, 0.6, 0.1).normalize();
+    assert_eq!(bsdf.eval(&wo, &wi, &n), bsdf.eval(&wi, &wo, &n));
+    assert_eq!(bsdf.eval(&wo, &vector(0.0, -1.0, 0.0), &n), Color::black());
+    for _ in 0..100 {
+        let Some(s) = bsdf.sample(&wo, &n, &mut sampler) else {
+            continue; // reflected below the surface
+        };
+        assert!(dot(&s.wi, &n) > 0.0);
+        fassert!(s.pdf, bsdf.pdf(&wo, &s.wi, &n));
+        assert_eq!(s.weight, bsdf.eval(&wo, &s.wi, &n) * (dot(&s.wi, &n) / s.pdf));
+    }
+
+    // A white rough metal and a white dielectric reflect at most the incoming light
+    for metallic in [0.0, 1.0] {
+        m.bsdf = BsdfKind::Microfacet { metallic, roughness: 0.5 };
+        let bsdf = Bsdf::new(&m, &point(0.0, 0.0, 0.0));
+        let mut albedo = 0.0;
+        for _ in 0..20000 {
+            if let Some(s) = bsdf.sample(&wo, &n, &mut sampler) {
+                albedo += s.weight.r / 20000.0;
+            }
+        }
+        assert!(albedo > 0.8 && albedo < 1.01);
+    }
+
+    // The light loop and the path tracer agree on the direct light of point lights
+    m.bsdf = BsdfKind::Microfacet { metallic: 0.3, roughness: 0.4 };
+    m.color = color(0.9, 0.5, 0.2);
+    m.ambient = 0.0;
+    let mut s = Sphere::default();
+    s.set_material(m);
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    w.add_src(PointLight::new(point(-3.0, 4.0, -10.0), color(1.0, 1.0, 1.0)).wrap_box());
+    let ray = Ray::new(point(0.0, 0.3, -5.0), vector(0.0, 0.0, 1.0));
+    let phong = Whitted.li(&w, &ray, &Color::black(), &mut Sampler::new(1, 1), 5);
+    let path = PathTracer::default().li(&w, &ray, &Color::black(), &mut Sampler::new(1, 1), 1);
+    assert_eq!(phong, path);
+    assert!(phong.r > phong.g && phong.g > phong.b);
+
+    // Metallic and roughness are read from YAML scenes
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    color: [1, 0.8, 0.3]
+    metallic: 1
+";
+    let r = load_yaml(src).unwrap();
+    let bsdf = r.world.objects[0].borrow().get_material().bsdf;
+    assert_eq!(bsdf, BsdfKind::Microfacet { metallic: 1.0, roughness: 0.5 });
+    assert!(load_yaml(&src.replace("metallic: 1", "roughness: 1.5")).is_err());
+}
+
+#[test]
+fn dielectrics_and_conductors() {
+    use crate::render::bsdf::{fresnel_conductor, fresnel_dielectric, Bsdf, BsdfKind};
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+
+    // Fresnel reflectance of glass, and total internal reflection from the inside
+    fassert!(fresnel_dielectric(1.0, 1.5), 0.04);
+    fassert!(fresnel_dielectric(0.0, 1.5), 1.0);
+    fassert!(fresnel_dielectric(0.3, 1.0 / 1.5), 1.0);
+
+    // Conductors reflect ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2) at normal incidence, gold is yellow
+    let gold = BsdfKind::conductor("gold", 0.0).unwrap();
+    let BsdfKind::Conductor { eta, k, .. } = gold else { panic!("gold is not a conductor") };
+    let f = fresnel_conductor(1.0, &eta, &k);
+    fassert!(f.r, ((eta.r - 1.0).powi(2) + k.r * k.r) / ((eta.r + 1.0).powi(2) + k.r * k.r));
+    assert!(f.r > f.g && f.g > f.b);
+    assert!(BsdfKind::conductor("copper", 0.0).is_some() && BsdfKind::conductor("aluminium", 0.0).is_some());
+    assert!(BsdfKind::conductor("unobtainium", 0.0).is_none());
+
+    // Smooth glass splits the light into a mirror and a refracted direction that obey Snell's law
+    let mut m = Material::default();
+    m.bsdf = BsdfKind::Dielectric { ior: 1.5, roughness: 0.0 };
+    let glass = Bsdf::new(&m, &point(0.0, 0.0, 0.0));
+    assert!(glass.is_delta());
+    let n = vector(0.0, 1.0, 0.0);
+    let wo = vector(0.6, 0.8, 0.0);
+    let lobes = glass.specular_lobes(&wo, &n);
+    assert_eq!(lobes.len(), 2);
+    vassert!(lobes[0].0, vector(-0.6, 0.8, 0.0));
+    fassert!(lobes[1].0.x, -0.6 / 1.5);
+    assert!(lobes[1].0.y < 0.0);
+    fassert!(lobes[0].1.r + lobes[1].1.r, 1.0);
+    let mut sampler = Sampler::new(5, 0);
+    let s = glass.sample(&wo, &n, &mut sampler).unwrap();
+    assert!(s.delta);
+    assert_eq!(s.weight, color(1.0, 1.0, 1.0));
+    assert_eq!(glass.eval(&wo, &lobes[0].0, &n), Color::black());
+
+    // Rough glass and rough metal sample consistently and conserve energy
+    for kind in [BsdfKind::Dielectric { ior: 1.5, roughness: 0.3 }, BsdfKind::conductor("aluminium", 0.3).unwrap()] {
+        m.bsdf = kind;
+        let bsdf = Bsdf::new(&m, &point(0.0, 0.0, 0.0));
+        assert!(!bsdf.is_delta());
+        let mut albedo = 0.0;
+        for _ in 0..20000 {
+            let Some(s) = bsdf.sample(&wo, &n, &mut sampler) else {
+                continue;
+            };
+            fassert!(s.pdf, bsdf.pdf(&wo, &s.wi, &n));
+            assert_eq!(s.weight, bsdf.eval(&wo, &s.wi, &n) * (dot(&s.wi, &n).abs() / s.pdf));
+            albedo += s.weight.r / 20000.0;
+        }
+        assert!(albedo > 0.85 && albedo < 1.01);
+    }
+
+    // A smooth mirror reflects the background tinted by the Fresnel reflectance, in both integrators
+    m.bsdf = BsdfKind::conductor("aluminium", 0.0).unwrap();
+    let mut s = Sphere::default();
+    s.set_material(m.clone());
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    let ray = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    let BsdfKind::Conductor { eta, k, .. } = m.bsdf else { panic!("aluminium is not a conductor") };
+    let expected = fresnel_conductor(1.0, &eta, &k) * color(0.5, 0.6, 0.7);
+    assert_eq!(Whitted.li(&w, &ray, &color(0.5, 0.6, 0.7), &mut sampler, 5), expected);
+    assert_eq!(PathTracer::default().li(&w, &ray, &color(0.5, 0.6, 0.7), &mut sampler, 5), expected);
+
+    // Clear glass under a white sky lets all the light through
+    m.bsdf = BsdfKind::Dielectric { ior: 1.5, roughness: 0.0 };
+    let mut s = Sphere::default();
+    s.set_material(m);
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    let white = color(1.0, 1.0, 1.0);
+    assert!(Whitted.li(&w, &ray, &white, &mut sampler, 5).r > 0.99);
+    let pt = PathTracer { roulette_depth: 100 };
+    let ray = Ray::new(point(0.0, 0.0, -5.0), vector(0.05, 0.2, 1.0).normalize());
+    for s in 0..100 {
+        assert_eq!(pt.li(&w, &ray, &white, &mut Sampler::for_pixel(0, 0, s), 100), white);
+    }
+
+    // Dielectrics and conductors are read from YAML scenes
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    ior: 1.33
+
+- add: sphere
+  material:
+    conductor: copper
+    roughness: 0.2
+
+- add: sphere
+  material:
+    conductor:
+      eta: [0.2, 0.4, 1.4]
+      k: [3.4, 2.3, 1.8]
+";
+    let r = load_yaml(src).unwrap();
+    let kind = |i: usize| r.world.objects[i].borrow().get_material().bsdf;
+    assert_eq!(kind(0), BsdfKind::Dielectric { ior: 1.33, roughness: 0.0 });
+    assert_eq!(kind(1), BsdfKind::conductor("copper", 0.2).unwrap());
+    assert_eq!(kind(2), BsdfKind::Conductor { eta: color(0.2, 0.4, 1.4), k: color(3.4, 2.3, 1.8), roughness: 0.0 });
+    assert!(load_yaml(&src.replace("ior: 1.33", "ior: 1.33\n    metallic: 1")).is_err());
+    assert!(load_yaml(&src.replace("conductor: copper", "conductor: [1, 2, 3]")).is_err());
+    assert!(load_yaml(&src.replace("ior: 1.33", "ior: -1")).is_err());
+}
+
+#[test]
+fn environment_lighting() {
+    use crate::render::environment::{direction_to_uv, uv_to_direction, Environment, EnvironmentMap};
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+
+    // Directions map to the image like the equirectangular camera, and back
+    assert_eq!(direction_to_uv(&vector(0.0, 0.0, -1.0)), (0.5, 0.5));
+    assert_eq!(direction_to_uv(&vector(1.0, 0.0, 0.0)), (0.25, 0.5));
+    fassert!(direction_to_uv(&vector(0.0, 1.0, 0.0)).1, 0.0);
+    for d in [vector(0.3, -0.5, 0.8), vector(-0.9, 0.1, 0.2), vector(0.0, 0.2, 1.0)] {
+        let d = d.normalize();
+        let (u, v) = direction_to_uv(&d);
+        vassert!(uv_to_direction(u, v), d);
+    }
+
+    // A panorama of an empty World shows the environment map unchanged
+    let mut img = Canvas::new(16, 8, Color::black());
+    for y in 0..8 {
+        for x in 0..16 {
+            img[[x, y]] = color(x as f64, y as f64, 1.0);
+        }
+    }
+    img[[3, 2]] = color(500.0, 400.0, 300.0);
+    let env = EnvironmentMap::new(&img);
+    let mut w = World::new();
+    w.set_environment(Box::new(env.clone()));
+    let c = Camera::with_projection(16, 8, 1.0, Projection::Equirectangular);
+    let mut r = Renderer::with_camera(w, c, Color::black());
+    r.render();
+    for y in 0..8 {
+        assert_eq!(r.canvas().row(y), img.row(y));
+    }
+
+    // Sampling follows the luminance, and agrees with pdf() and radiance()
+    let mut sampler = Sampler::new(7, 0);
+    let (mut power, mut bright) = (Color::black(), 0);
+    for _ in 0..20000 {
+        let (d, li, pdf) = env.sample(sampler.next_2d());
+        assert!(pdf > 0.0);
+        assert!((env.pdf(&d) - pdf).abs() < 1e-6 * pdf);
+        assert_eq!(li, env.radiance(&d) * (1.0 / pdf));
+        if env.radiance(&d) == img[[3, 2]] {
+            bright += 1;
+        }
+        power = power + li * (1.0 / 20000.0);
+    }
+    assert!(bright > 10000);
+    let mut expected = Color::black();
+    for y in 0..8 {
+        let solid = (PI * y as f64 / 8.0).cos() - (PI * (y + 1) as f64 / 8.0).cos();
+        for x in 0..16 {
+            expected = expected + img[[x, y]] * (solid * 2.0 * PI / 16.0);
+        }
+    }
+    assert!((power.r / expected.r - 1.0).abs() < 0.02);
+
+    // Furnace test: a white diffuse sphere under a uniform environment reflects it unchanged
+    let mut m = Material::default();
+    m.color = color(1.0, 1.0, 1.0);
+    m.diffuse = 1.0;
+    let mut s = Sphere::default();
+    s.set_material(m);
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    let mut white = Canvas::new(8, 4, Color::black());
+    for y in 0..4 {
+        white.row_mut(y).fill(color(1.0, 1.0, 1.0));
+    }
+    let mut env = EnvironmentMap::new(&white);
+    env.intensity = 0.5;
+    w.set_environment(Box::new(env));
+    let ray = Ray::new(point(0.3, 0.2, -5.0), vector(0.0, 0.0, 1.0));
+    let bg = color(9.0, 9.0, 9.0);
+    let mut avg = Color::black();
+    for s in 0..2000 {
+        avg = avg + PathTracer::default().li(&w, &ray, &bg, &mut Sampler::for_pixel(0, 0, s), 5) * (1.0 / 2000.0);
+    }
+    assert!((avg.r - 0.5).abs() < 0.02);
+
+    // Missed rays see the environment instead of the background in both integrators
+    let miss = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 1.0, 0.0));
+    assert_eq!(Whitted.li(&w, &miss, &bg, &mut sampler, 5), color(0.5, 0.5, 0.5));
+    assert_eq!(w.calc(&miss, &bg), color(0.5, 0.5, 0.5));
+
+    // Scenes load environment maps relative to the scene file
+    let dir = std::env::temp_dir().join(format!("ray_tracer_environment_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    img.save(dir.join("sky.pfm")).unwrap();
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: environment
+  file: sky.pfm
+  intensity: 2
+";
+    std::fs::write(dir.join("scene.yaml"), src).unwrap();
+    let r = load_yaml_file(dir.join("scene.yaml")).unwrap();
+    let env = r.world.environment.as_ref().unwrap();
+    assert_eq!(env.radiance(&uv_to_direction(3.5 / 16.0, 2.5 / 8.0)), color(1000.0, 800.0, 600.0));
+
+    // The map is saved with the World as its file, maps made from a Canvas cannot be saved
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    let reloaded = loaded.world.environment.as_ref().unwrap();
+    for (u, v) in [(3.5 / 16.0, 2.5 / 8.0), (0.7, 0.6)] {
+        assert_eq!(reloaded.radiance(&uv_to_direction(u, v)), env.radiance(&uv_to_direction(u, v)));
+    }
+    assert_eq!(reloaded.desc(), env.desc());
+    let mut w = World::new();
+    w.set_environment(Box::new(EnvironmentMap::new(&img)));
+    let unsaved = Renderer::with_camera(w, Camera::new(4, 4, 1.0), Color::black());
+    assert!(matches!(to_json(&unsaved), Err(SceneError::Serialize(_))));
+    std::fs::write(dir.join("bad.yaml"), src.replace("sky.pfm", "missing.pfm")).unwrap();
+    assert!(load_yaml_file(dir.join("bad.yaml")).is_err());
+    std::fs::write(dir.join("bad.yaml"), src.replace("sky.pfm", "sky.txt")).unwrap();
+    assert!(load_yaml_file(dir.join("bad.yaml")).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sky_and_sun() {
+    use crate::render::core::DirectionalLight;
+    use crate::render::environment::{uv_to_direction, Environment};
+    use crate::render::integrator::{Integrator, PathTracer};
+    use crate::render::sky::Sky;
+
+    // The sky is blue overhead, brighter around the sun and black below the horizon
+    let sky = Sky::new(vector(0.0, 1.0, -1.0), 3.0);
+    vassert!(sky.sun_direction(), vector(0.0, 1.0, -1.0).normalize());
+    let zenith = sky.radiance(&vector(0.0, 1.0, 0.0));
+    assert!(zenith.b > zenith.r && zenith.r > 0.0);
+    assert!(luminance(&zenith) > 0.1 && luminance(&zenith) < 10.0);
+    let near_sun = sky.radiance(&vector(0.0, 1.0, -1.2).normalize());
+    let away = sky.radiance(&vector(0.0, 1.0, 1.2).normalize());
+    assert!(luminance(&near_sun) > 2.0 * luminance(&away));
+    assert_eq!(sky.radiance(&vector(0.3, -0.1, 1.0).normalize()), Color::black());
+
+    // The sun is dimmer and redder low on the horizon, and behind haze
+    let high = Sky::new(vector(0.0, 1.0, 0.0), 3.0).sun().intensity();
+    let low = Sky::new(vector(0.0, 0.1, 1.0), 3.0).sun().intensity();
+    let hazy = Sky::new(vector(0.0, 1.0, 0.0), 9.0).sun().intensity();
+    assert!(high.r > low.r && high.b > low.b && low.r > low.b);
+    assert!(luminance(&hazy) < luminance(&high));
+    assert_eq!(Sky::new(vector(0.0, -1.0, 1.0), 3.0).sun().intensity(), Color::black());
+
+    // Importance sampling agrees with pdf() and radiance(), and integrates the sky
+    let mut sampler = Sampler::new(11, 0);
+    let mut power = Color::black();
+    for _ in 0..20000 {
+        let (d, li, pdf) = sky.sample(sampler.next_2d());
+        assert!(pdf > 0.0 && d.y > 0.0);
+        assert!((sky.pdf(&d) - pdf).abs() < 1e-6 * pdf);
+        assert_eq!(li, sky.radiance(&d) * (1.0 / pdf));
+        power = power + li * (1.0 / 20000.0);
+    }
+    let (w, h) = (512, 256);
+    let mut expected = Color::black();
+    for y in 0..h {
+        let v = (y as f64 + 0.5) / h as f64;
+        let solid = (PI * v).sin() * 2.0 * PI * PI / (w * h) as f64;
+        for x in 0..w {
+            expected = expected + sky.radiance(&uv_to_direction((x as f64 + 0.5) / w as f64, v)) * solid;
+        }
+    }
+    assert!((luminance(&power) / luminance(&expected) - 1.0).abs() < 0.03);
+
+    // A directional light shades like a point light very far away in its direction, and casts shadows
+    let dir = vector(-1.0, 1.0, -1.0).normalize();
+    let sun = DirectionalLight::new(vector(-2.0, 2.0, -2.0), color(1.0, 0.9, 0.8));
+    vassert!(sun.dir, dir);
+    let far = PointLight::new(point(0.0, 0.0, 0.0) + dir * 1e9, color(1.0, 0.9, 0.8));
+    let m = Material::default();
+    let (p, e, n) = (point(0.0, 0.0, -1.0), vector(0.0, 0.0, -1.0), vector(0.0, 0.0, -1.0));
+    assert_eq!(sun.shade(&m, &p, &e, &n, false), far.shade(&m, &p, &e, &n, false));
+    assert_eq!(sun.shade(&m, &p, &e, &n, true), far.shade(&m, &p, &e, &n, true));
+
+    let mut w = World::default();
+    w.sources.clear();
+    w.add_directional(sun.clone());
+    assert_eq!(w.visibility(&point(-3.0, 3.0, -3.0)), 1.0);
+    assert_eq!(w.visibility(&point(3.0, -3.0, 3.0)), 0.0);
+    let r = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    let lit = w.calc(&r, &Color::black());
+    w.sources.push(far.wrap_box());
+    w.directional.clear();
+    assert_eq!(w.calc(&r, &Color::black()), lit);
+
+    // The path tracer lights a white diffuse floor with I * cos
+    let mut m = Material::default();
+    m.color = color(1.0, 1.0, 1.0);
+    m.diffuse = 1.0;
+    let mut floor = Plane::default();
+    floor.set_material(m);
+    let mut w = World::new();
+    w.add_obj(floor.wrap());
+    w.add_directional(sun.clone());
+    let r = Ray::new(point(0.0, 1.0, 0.0), vector(0.0, -1.0, 0.0));
+    let expected = color(1.0, 0.9, 0.8) * dir.y;
+    assert_eq!(PathTracer::default().li(&w, &r, &Color::black(), &mut sampler, 5), expected);
+
+    // Directional lights are saved with the World
+    let json = to_json(&Renderer::with_camera(w, Camera::new(4, 4, 1.0), Color::black())).unwrap();
+    assert_eq!(load_json(&json).unwrap().world.directional, vec![sun]);
+
+    // Scenes add directional lights and the sky with its sun
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: light
+  direction: [0, 2, 0]
+  intensity: [1, 1, 1]
+
+- add: sky
+  sun: [0, 1, -1]
+  turbidity: 3
+";
+    let r = load_yaml(src).unwrap();
+    assert_eq!(r.world.directional.len(), 2);
+    vassert!(r.world.directional[0].dir, vector(0.0, 1.0, 0.0));
+    assert_eq!(r.world.directional[1], sky.sun());
+    let env = r.world.environment.as_ref().unwrap();
+    assert_eq!(env.radiance(&vector(0.0, 1.0, 0.0)), zenith);
+
+    // The sky is saved with the World
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    let reloaded = loaded.world.environment.as_ref().unwrap();
+    assert_eq!(reloaded.desc(), env.desc());
+    for d in [vector(0.0, 1.0, 0.0), vector(0.5, 0.3, -0.2).normalize(), vector(-0.1, 0.9, 0.4).normalize()] {
+        assert_eq!(reloaded.radiance(&d), env.radiance(&d));
+    }
+    assert_eq!(loaded.world.directional, r.world.directional);
+    let r = load_yaml(&src.replace("turbidity: 3", "sun-light: false")).unwrap();
+    assert_eq!(r.world.directional.len(), 1);
+    assert!(load_yaml(&src.replace("turbidity: 3", "turbidity: 20")).is_err());
+    assert!(load_yaml(&src.replace("[0, 2, 0]", "[0, 0, 0]")).is_err());
+    assert!(load_yaml(&format!("{}\n- add: sky\n  sun: [0, 1, 0]\n", src)).is_err());
+}
+
+#[test]
+fn participating_media() {
+    use crate::render::core::DirectionalLight;
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+    use crate::render::medium::{Fog, Medium};
+
+    // Beer-Lambert attenuation, and phase functions that integrate to one
+    let m = Medium::new(color(0.1, 0.2, 0.3), color(0.2, 0.2, 0.2));
+    assert_eq!(m.extinction(), color(0.3, 0.4, 0.5));
+    assert_eq!(m.transmittance(2.0), color((-0.6f64).exp(), (-0.8f64).exp(), (-1.0f64).exp()));
+    assert_eq!(m.transmittance(f64::INFINITY), Color::black());
+    fassert!(m.phase(0.3), 1.0 / (4.0 * PI));
+    for g in [-0.5, 0.0, 0.8] {
+        let m = Medium { g, ..m };
+        let n = 100000;
+        let total: f64 = (0..n).map(|i| m.phase(-1.0 + (i as f64 + 0.5) * 2.0 / n as f64) * 4.0 * PI / n as f64).sum();
+        assert!((total - 1.0).abs() < 1e-3);
+    }
+    assert!(Medium { g: 0.8, ..m }.phase(1.0) > Medium { g: 0.8, ..m }.phase(-1.0));
+
+    // The scattering points integrate a constant light exactly
+    let gray = Medium::new(color(0.1, 0.1, 0.1), color(0.3, 0.3, 0.3));
+    for (t0, t1) in [(1.0, 3.0), (0.0, f64::INFINITY)] {
+        let sum = gray.scatter_points(t0, t1).iter().fold(Color::black(), |s, (t, w)| s + *w * gray.transmittance(t - t0));
+        let expected = (Color::white() - gray.transmittance(t1 - t0)) * 0.75;
+        assert_eq!(sum, expected);
+    }
+    assert!(Medium::new(color(0.1, 0.1, 0.1), Color::black()).scatter_points(0.0, 1.0).is_empty());
+
+    // Fog below a given height
+    let mut fog = Fog::new(gray);
+    assert_eq!(fog.segment(&Ray::new(point(0.0, 5.0, 0.0), vector(0.0, 1.0, 0.0)), 3.0), Some((0.0, 3.0)));
+    fog.height = Some(2.0);
+    assert_eq!(fog.segment(&Ray::new(point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0)), f64::INFINITY), Some((0.0, 2.0)));
+    assert_eq!(fog.segment(&Ray::new(point(0.0, 4.0, 0.0), vector(0.0, -1.0, 0.0)), 10.0), Some((2.0, 10.0)));
+    assert_eq!(fog.segment(&Ray::new(point(0.0, 4.0, 0.0), vector(1.0, 0.0, 0.0)), 10.0), None);
+    assert_eq!(fog.segment(&Ray::new(point(0.0, 4.0, 0.0), vector(0.0, 1.0, 0.0)), 10.0), None);
+
+    // Infinite fog hides the background, fog below a height dims it
+    let bg = color(0.5, 0.6, 0.7);
+    let mut w = World::new();
+    w.set_fog(Fog::new(gray));
+    let up = Ray::new(point(0.0, 0.0, 0.0), vector(0.0, 1.0, 0.0));
+    assert_eq!(w.calc(&up, &bg), Color::black());
+    w.fog = Some(fog);
+    assert_eq!(w.calc(&up, &bg), bg * (-0.8f64).exp());
+
+    // Absorbing fog dims the surfaces and the light arriving at them
+    let mut w = World::default();
+    let r = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    let xs = w.intersect(&r);
+    let info = Computations::new(w.surface_hit(&xs).unwrap().clone(), &r);
+    let light = w.sources[0].clone();
+    let obj = info.obj.clone();
+    let obj = obj.borrow();
+    let shade = |shadowed| light.shade(obj.get_material(), &info.p, &info.e, &info.n, shadowed);
+    let dist = (light.pos - info.over_p).magnitude();
+    let haze = Medium::new(color(0.05, 0.05, 0.05), Color::black());
+    w.set_fog(Fog::new(haze));
+    let lit = shade(true) + (shade(false) - shade(true)) * haze.transmittance(dist);
+    assert_eq!(w.calc(&r, &Color::black()), lit * haze.transmittance(4.0));
+
+    // Single scattering of a directional light shining into a layer of fog
+    let mut w = World::new();
+    let mut fog = Fog::new(gray);
+    fog.height = Some(0.0);
+    w.set_fog(fog);
+    w.add_directional(DirectionalLight::new(vector(0.0, 1.0, 0.0), color(1.0, 1.0, 1.0)));
+    let r = Ray::new(point(0.0, -1.0, 0.0), vector(0.0, 0.0, 1.0));
+    let expected = Color::white() * (0.75 / 4.0 * (-0.4f64).exp());
+    assert_eq!(w.calc(&r, &bg), expected);
+    let mut sampler = Sampler::new(1, 0);
+    assert_eq!(Whitted.li(&w, &r, &bg, &mut sampler, 5), expected);
+    assert_eq!(PathTracer::default().li(&w, &r, &bg, &mut sampler, 5), expected);
+
+    // A sphere of smoke is not drawn, but dims the light passing through it and casts soft shadows
+    let smoke = Medium::new(color(0.2, 0.2, 0.2), Color::black());
+    let mut m = Material::default();
+    m.medium = Some(smoke);
+    let mut s = Sphere::default();
+    s.set_material(m.clone());
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    let r = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    assert!(w.surface_hit(&w.intersect(&r)).is_none());
+    assert_eq!(w.calc(&r, &bg), bg * (-0.4f64).exp());
+    assert_eq!(w.calc(&Ray::new(point(0.0, 0.0, 0.0), vector(0.0, 0.0, 1.0)), &bg), bg * (-0.2f64).exp());
+    assert_eq!(w.transmittance(&point(0.0, 0.0, -5.0), &point(0.0, 0.0, 5.0)), Color::white() * (-0.4f64).exp());
+    assert!(!w.is_occluded(&point(0.0, 0.0, -5.0), &point(0.0, 0.0, 5.0)));
+    w.add_src(PointLight::new(point(0.0, 10.0, 0.0), color(1.0, 1.0, 1.0)).wrap_box());
+    assert_eq!(w.visibility(&point(0.0, -5.0, 0.0)), 1.0);
+
+    // Media are read from scenes and saved with the World
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: fog
+  absorption: [0.1, 0.1, 0.1]
+  scattering: [0.3, 0.3, 0.3]
+  height: 2
+
+- add: sphere
+  material:
+    medium:
+      absorption: [0.2, 0.2, 0.2]
+      anisotropy: 0.5
+";
+    let r = load_yaml(src).unwrap();
+    assert_eq!(r.world.fog, Some(Fog { medium: gray, height: Some(2.0) }));
+    let volume = r.world.objects[0].borrow().get_material().medium;
+    assert_eq!(volume, Some(Medium { g: 0.5, ..smoke }));
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    assert_eq!(loaded.world.fog, r.world.fog);
+    assert_eq!(loaded.world.objects[0].borrow().get_material().medium, volume);
+    assert!(load_yaml(&src.replace("anisotropy: 0.5", "anisotropy: 1")).is_err());
+    assert!(load_yaml(&src.replace("[0.1, 0.1, 0.1]", "[-0.1, 0.1, 0.1]")).is_err());
+    assert!(load_yaml(&src.replace("height: 2", "density: 2")).is_err());
+    assert!(load_yaml(&format!("{}\n- add: fog\n", src)).is_err());
+}
+
+#[test]
+fn heterogeneous_volumes() {
+    use crate::render::density::{Density, DensityGrid, Noise};
+    use crate::render::medium::{Medium, MediumSegment};
+    use std::rc::Rc;
+
+    // Grids are interpolated trilinearly between the centers of their cells
+    let ramp = DensityGrid::new(2, 1, 1, vec![0.0, 1.0]).unwrap();
+    assert_eq!(ramp.dims(), (2, 1, 1));
+    fassert!(ramp.at(&point(-0.75, 0.3, -0.2)), 0.0);
+    fassert!(ramp.at(&point(0.0, 0.0, 0.0)), 0.5);
+    fassert!(ramp.at(&point(0.25, 0.9, 0.9)), 0.75);
+    fassert!(ramp.at(&point(0.8, 0.0, 0.0)), 1.0);
+    fassert!(ramp.at(&point(1.5, 0.0, 0.0)), 0.0);
+    fassert!(ramp.max(), 1.0);
+    assert!(DensityGrid::new(2, 2, 1, vec![0.0, 1.0]).is_none());
+    assert!(DensityGrid::new(2, 1, 1, vec![0.0, -1.0]).is_none());
+    assert!(DensityGrid::new(0, 1, 1, vec![]).is_none());
+
+    // The raw format stores the dimensions and the values
+    let grid = DensityGrid::new(3, 2, 2, (0..12).map(|i| i as f64 / 4.0).collect()).unwrap();
+    let mut bytes = vec![];
+    grid.write(&mut bytes).unwrap();
+    assert_eq!(bytes.len(), 12 + 12 * 4);
+    assert_eq!(&bytes[..4], &3u32.to_le_bytes());
+    assert_eq!(DensityGrid::read(&bytes[..]).unwrap(), grid);
+    fassert!(grid.value(2, 1, 1), 11.0 / 4.0);
+    assert!(DensityGrid::read(&bytes[..40]).is_err());
+    let mut bad = bytes.clone();
+    bad[..4].copy_from_slice(&0u32.to_le_bytes());
+    assert!(DensityGrid::read(&bad[..]).is_err());
+    let mut bad = bytes.clone();
+    bad[12..16].copy_from_slice(&(-1.0f32).to_le_bytes());
+    assert!(DensityGrid::read(&bad[..]).is_err());
+
+    // Noise lies in [0, 1], varies, and depends only on its parameters
+    let noise = Noise::new(2.0, 3, 7);
+    let values: Vec<f64> = (0..200).map(|i| noise.at(&point(i as f64 * 0.037, 0.3, -0.1 * i as f64))).collect();
+    assert!(values.iter().all(|v| (0.0..=1.0).contains(v)));
+    assert!(values.iter().any(|v| (v - values[0]).abs() > 0.05));
+    fassert!(values[17], Noise::new(2.0, 3, 7).at(&point(17.0 * 0.037, 0.3, -1.7)));
+    assert!(Noise::new(2.0, 3, 8).at(&point(0.3, 0.3, 0.3)) != noise.at(&point(0.3, 0.3, 0.3)));
+
+    // Ratio tracking estimates the transmittance of a varying density without bias
+    // (the ramp integrates to 1 along the x axis of the cube)
+    let medium = Medium::new(color(0.5, 0.5, 0.5), color(0.5, 0.5, 0.5));
+    let segment = MediumSegment {
+        medium,
+        t0: 4.0,
+        t1: 6.0,
+        density: Some((Density::Grid(Rc::new(ramp)), Matrix::identity())),
+    };
+    let r = Ray::new(point(-5.0, 0.0, 0.0), vector(1.0, 0.0, 0.0));
+    let n = 20000;
+    let (mut tr, mut scattered) = (0.0, 0.0);
+    for i in 0..n {
+        let mut sampler = Sampler::new(i, 0);
+        let collisions = segment.track(&r, &mut sampler);
+        assert!(collisions.iter().all(|(t, _)| *t > 4.0 && *t < 6.0));
+        tr += segment.transmittance(&collisions, 6.0).r;
+        // a constant light scattered along the segment
+        scattered += segment
+            .scatter_points(&collisions)
+            .iter()
+            .map(|(t, w)| w.r * segment.transmittance(&collisions, *t).r)
+            .sum::<f64>();
+    }
+    assert!((tr / n as f64 - (-1.0f64).exp()).abs() < 0.01);
+    assert!((scattered / n as f64 - 0.5 * (1.0 - (-1.0f64).exp())).abs() < 0.01);
+
+    // A volume of constant density matches the homogeneous one on average, repeatably
+    let smoke = Medium::new(color(0.4, 0.4, 0.4), Color::black());
+    let mut m = Material::default();
+    m.medium = Some(smoke);
+    m.density = Some(Density::Grid(Rc::new(DensityGrid::new(1, 1, 1, vec![0.5]).unwrap())));
+    let m_density = m.density.clone();
+    let mut s = Sphere::default();
+    s.set_material(m.clone());
+    let mut w = World::new();
+    w.add_obj(s.wrap());
+    let p = |i: usize| point(0.0, i as f64 * 1e-9, -5.0);
+    let q = point(0.0, 0.0, 5.0);
+    let mean = (0..n as usize).map(|i| w.transmittance(&p(i), &q).r).sum::<f64>() / n as f64;
+    assert!((mean - (-0.4f64).exp()).abs() < 0.01);
+    assert_eq!(w.transmittance(&p(3), &q), w.transmittance(&p(3), &q));
+
+    // Densities are read from scenes, grids relative to the scene file
+    let dir = std::env::temp_dir().join(format!("ray_tracer_volumes_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    grid.save(dir.join("smoke.raw")).unwrap();
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    medium:
+      scattering: [0.5, 0.5, 0.5]
+      grid: smoke.raw
+
+- add: sphere
+  material:
+    medium:
+      absorption: [0.5, 0.5, 0.5]
+      noise:
+        frequency: 2
+        octaves: 3
+        seed: 7
+";
+    std::fs::write(dir.join("scene.yaml"), src).unwrap();
+    let r = load_yaml_file(dir.join("scene.yaml")).unwrap();
+    let density = |i: usize| r.world.objects[i].borrow().get_material().density.clone();
+    assert_eq!(density(0), Some(Density::Grid(Rc::new(grid.clone()))));
+    assert_eq!(density(1), Some(Density::Noise(noise)));
+    let json = to_json(&r).unwrap();
+    assert!(json.contains("smoke.raw") && !json.contains("values"));
+    let loaded = load_json(&json).unwrap();
+    assert_eq!(loaded.world.objects[0].borrow().get_material().density, density(0));
+    let mut s = Sphere::default();
+    s.set_material(m);
+    let mut built = Renderer::with_camera(World::new(), Camera::new(4, 4, 1.0), Color::black());
+    built.world.add_obj(s.wrap());
+    let json = to_json(&built).unwrap();
+    assert!(json.contains("values"));
+    let loaded = load_json(&json).unwrap();
+    assert_eq!(loaded.world.objects[0].borrow().get_material().density, m_density);
+    std::fs::remove_file(dir.join("smoke.raw")).unwrap();
+    assert!(load_json(&to_json(&r).unwrap()).is_err());
+    grid.save(dir.join("smoke.raw")).unwrap();
+    for bad in [
+        src.replace("smoke.raw", "missing.raw"),
+        src.replace("octaves: 3", "octaves: 0"),
+        src.replace("seed: 7", "seed: -7"),
+        src.replace("      grid: smoke.raw", "      grid: smoke.raw\n      noise: {}"),
+    ] {
+        std::fs::write(dir.join("bad.yaml"), bad).unwrap();
+        assert!(load_yaml_file(dir.join("bad.yaml")).is_err());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn subsurface_scattering() {
+    use crate::render::core::DirectionalLight;
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+    use crate::render::subsurface::Subsurface;
+
+    // The profile spreads the albedo over the plane, and its samples estimate it without bias
+    let albedo = color(0.8, 0.5, 0.3);
+    let ss = Subsurface::new(color(0.4, 0.2, 0.1), albedo);
+    let n = 200000;
+    let dr = ss.max_radius() * 2.0 / n as f64;
+    let (mut total, mut pdf) = (Color::black(), 0.0);
+    for i in 0..n {
+        let r = (i as f64 + 0.5) * dr;
+        total = total + ss.profile(r) * (2.0 * PI * r * dr);
+        pdf += ss.pdf(r) * 2.0 * PI * r * dr;
+    }
+    assert!((total.r - 0.8).abs() < 1e-3 && (total.g - 0.5).abs() < 1e-3 && (total.b - 0.3).abs() < 1e-3);
+    assert!((pdf - 1.0).abs() < 1e-3);
+    let mut sampler = Sampler::new(3, 0);
+    let mean = (0..n).fold(Color::black(), |s, _| {
+        let r = ss.sample_radius(&mut sampler);
+        s + ss.profile(r) * (1.0 / ss.pdf(r))
+    }) * (1.0 / n as f64);
+    assert!((mean.r - 0.8).abs() < 0.02 && (mean.g - 0.5).abs() < 0.02 && (mean.b - 0.3).abs() < 0.02);
+
+    // A uniformly lit flat surface reflects its albedo, like a diffuse one
+    let mut m = Material::default();
+    m.ambient = 0.0;
+    m.specular = 0.0;
+    m.subsurface = Some(ss);
+    let mut floor = Plane::default();
+    floor.set_material(m);
+    let mut w = World::new();
+    w.add_obj(floor.wrap());
+    w.add_directional(DirectionalLight::new(vector(0.0, 1.0, 0.0), color(1.0, 1.0, 1.0)));
+    let down = |x: f64, z: f64| Ray::new(point(x, 0.5, z), vector(0.0, -1.0, 0.0));
+    let rays: Vec<Ray> = (0..400).map(|i| down((i % 20) as f64 * 0.37, (i / 20) as f64 * 0.29)).collect();
+    let average = |f: &mut dyn FnMut(&Ray) -> Color| {
+        rays.iter().fold(Color::black(), |s, r| s + f(r)) * (1.0 / rays.len() as f64)
+    };
+    let bg = Color::black();
+    let phong = average(&mut |r| w.calc(r, &bg));
+    assert!((phong.r - 0.8).abs() < 0.03 && (phong.g - 0.5).abs() < 0.03 && (phong.b - 0.3).abs() < 0.03);
+    assert_eq!(w.calc(&rays[7], &bg), w.calc(&rays[7], &bg));
+    let mut sampler = Sampler::new(5, 0);
+    let whitted = average(&mut |r| Whitted.li(&w, r, &bg, &mut sampler, 5));
+    assert_eq!(whitted, phong);
+    let path = average(&mut |r| {
+        (0..16).fold(Color::black(), |s, _| s + PathTracer::default().li(&w, r, &bg, &mut sampler, 5)) * (1.0 / 16.0)
+    });
+    assert!((path.r - 0.8).abs() < 0.05 && (path.g - 0.5).abs() < 0.05 && (path.b - 0.3).abs() < 0.05);
+
+    // Light bleeds into a shadow, the further the longer the mean free path
+    let mut blocker = Sphere::default();
+    blocker.set_transform(transform!(TUnit::Translate(0.0, 2.0, 0.0)));
+    w.add_obj(blocker.wrap());
+    let edge = w.calc(&down(0.95, 0.0), &bg);
+    let center = w.calc(&down(0.0, 0.0), &bg);
+    assert!(edge.g > 0.05 && center.g < 0.01);
+    assert!(center.r > 0.1 && center.r > center.g && center.g > center.b);
+    assert_eq!(w.calc(&down(5.0, 0.0), &bg), w.calc(&down(5.0, 0.0), &bg));
+
+    // Subsurface scattering is read from scenes and saved with the World
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    color: [1, 0.9, 0.7]
+    subsurface:
+      mean-free-path: [0.4, 0.2, 0.1]
+      albedo: [0.8, 0.5, 0.3]
+";
+    let r = load_yaml(src).unwrap();
+    assert_eq!(r.world.objects[0].borrow().get_material().subsurface, Some(ss));
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    assert_eq!(loaded.world.objects[0].borrow().get_material().subsurface, Some(ss));
+    assert!(load_yaml(&src.replace("[0.4, 0.2, 0.1]", "[0.4, 0, 0.1]")).is_err());
+    assert!(load_yaml(&src.replace("[0.8, 0.5, 0.3]", "[1.5, 0.5, 0.3]")).is_err());
+    assert!(load_yaml(&src.replace("      albedo: [0.8, 0.5, 0.3]\n", "")).is_err());
+    assert!(load_yaml(&src.replace("    color:", "    metallic: 1\n    color:")).is_err());
+}
+
+#[test]
+fn spectral_rendering() {
+    use crate::render::bsdf::{Bsdf, BsdfKind};
+    use crate::render::integrator::{Integrator, PathTracer, Whitted};
+    use crate::render::spectrum::*;
+    use std::sync::Arc;
+
+    // The color matching functions enclose equal areas, and the wavelength density integrates to one
+    let (mut area, mut pdf) = ((0.0, 0.0, 0.0), 0.0);
+    for i in 0..4700 {
+        let l = MIN_WAVELENGTH + (i as f64 + 0.5) * 0.1;
+        let (x, y, z) = cie_xyz(l);
+        area = (area.0 + x * 0.1, area.1 + y * 0.1, area.2 + z * 0.1);
+        pdf += wavelength_pdf(l) * 0.1;
+    }
+    assert!((area.0 / area.1 - 1.0).abs() < 0.02 && (area.2 / area.1 - 1.0).abs() < 0.02);
+    assert!((pdf - 1.0).abs() < 1e-3);
+    assert!(cie_xyz(560.0).1 > 0.99 && cie_xyz(400.0).1 < 0.01);
+    let (l, p) = sample_wavelength(0.3);
+    assert!((MIN_WAVELENGTH..=MAX_WAVELENGTH).contains(&l));
+    fassert!(p, wavelength_pdf(l));
+    assert!(sample_wavelength(0.2).0 < sample_wavelength(0.8).0);
+
+    // Upsampled spectra: grays are flat, and colors peak at their wavelengths
+    for l in [400.0, 500.0, 600.0, 700.0] {
+        assert!((rgb_to_spectrum(&color(0.5, 0.5, 0.5), l) - 0.5).abs() < 1e-3);
+    }
+    let red = color(0.9, 0.1, 0.1);
+    assert!(rgb_to_spectrum(&red, 650.0) > 0.8 && rgb_to_spectrum(&red, 450.0) < 0.2);
+    let blue = color(0.1, 0.2, 0.9);
+    assert!(rgb_to_spectrum(&blue, 450.0) > 0.8 && rgb_to_spectrum(&blue, 650.0) < 0.2);
+
+    // Upsampling and converting back keeps colors close to the original
+    let white = white_point();
+    let round_trip = |c: &Color| {
+        let mut sum = Color::black();
+        for i in 0..470 {
+            let l = MIN_WAVELENGTH + i as f64 + 0.5;
+            let (x, y, z) = cie_xyz(l);
+            sum = sum + xyz_to_rgb(x, y, z) * rgb_to_spectrum(c, l);
+        }
+        color(sum.r / white.r, sum.g / white.g, sum.b / white.b)
+    };
+    let gray = round_trip(&color(0.3, 0.3, 0.3));
+    assert!((gray.r - 0.3).abs() < 1e-3 && (gray.g - 0.3).abs() < 1e-3 && (gray.b - 0.3).abs() < 1e-3);
+    for c in [red, blue, color(0.2, 0.7, 0.3), color(0.9, 0.8, 0.2)] {
+        let rgb = round_trip(&c);
+        assert!((rgb.r - c.r).abs() < 0.1 && (rgb.g - c.g).abs() < 0.1 && (rgb.b - c.b).abs() < 0.1);
+    }
+
+    // Glasses refract blue light more than red light
+    let bk7 = Dispersion::glass("bk7").unwrap();
+    assert!((bk7.ior(REFERENCE_WAVELENGTH) - 1.5168).abs() < 1e-3);
+    assert!(bk7.ior(486.1) > bk7.ior(656.3));
+    assert!(Dispersion::glass("sf11").unwrap().ior(589.3) > 1.7);
+    assert!(Dispersion::glass("crown").is_none());
+    fassert!(Dispersion::Cauchy { a: 1.5, b: 0.01 }.ior(500.0), 1.54);
+
+    // Rays carry their wavelength to the BSDF of dispersive dielectrics
+    let mut m = Material::default();
+    m.bsdf = BsdfKind::Dielectric { ior: 1.5, roughness: 0.0 };
+    m.dispersion = Some(bk7);
+    let mut s = Sphere::default();
+    s.set_material(m);
+    let s = s.wrap();
+    let eta = |wavelength| {
+        let r = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0)).with_wavelength(wavelength);
+        match Bsdf::from_hit(&Computations::new(I::new(4.0, s.clone()), &r)) {
+            Bsdf::Dielectric { eta, .. } => eta,
+            _ => panic!("the sphere is not a dielectric"),
+        }
+    };
+    fassert!(eta(None), 1.5);
+    fassert!(eta(Some(450.0)), bk7.ior(450.0));
+    assert!(eta(Some(450.0)) > eta(Some(700.0)));
+
+    // Without dispersion, the spectral samples average to the RGB render
+    let w = World::default();
+    let r = Ray::new(point(0.0, 0.0, -5.0), vector(0.0, 0.0, 1.0));
+    let bg = Color::black();
+    let mut sampler = Sampler::new(11, 0);
+    let spectral = Spectral::new(Arc::new(Whitted));
+    assert_eq!(spectral.name(), "spectral");
+    let n = 20000;
+    let mean = (0..n).fold(Color::black(), |s, _| s + spectral.li(&w, &r, &bg, &mut sampler, 5)) * (1.0 / n as f64);
+    let rgb = Whitted.li(&w, &r, &bg, &mut sampler, 5);
+    assert!((mean.r - rgb.r).abs() < 0.05 * rgb.r && (mean.g - rgb.g).abs() < 0.05 * rgb.g);
+    assert!((mean.b - rgb.b).abs() < 0.05 * rgb.b.max(0.1));
+    let path = Spectral::new(Arc::new(PathTracer::default()));
+    let mean = (0..n).fold(Color::black(), |s, _| s + path.li(&w, &r, &bg, &mut sampler, 5)) * (1.0 / n as f64);
+    assert!(mean.r > 0.0 && mean.g > mean.b);
+
+    // Dispersion is read from scenes and saved with the World
+    let src = "
+- add: camera
+  width: 4
+  height: 4
+  field-of-view: 1
+  from: [0, 0, -5]
+  to: [0, 0, 0]
+  up: [0, 1, 0]
+
+- add: sphere
+  material:
+    dispersion: bk7
+
+- add: sphere
+  material:
+    roughness: 0.2
+    dispersion:
+      a: 1.5
+      b: 0.01
+
+- add: sphere
+  material:
+    dispersion:
+      b: [1.03961212, 0.231792344, 1.01046945]
+      c: [0.00600069867, 0.0200179144, 103.560653]
+";
+    let r = load_yaml(src).unwrap();
+    let material = |i: usize| r.world.objects[i].borrow().get_material().clone();
+    let BsdfKind::Dielectric { ior, .. } = material(0).bsdf else { panic!("bk7 is not a dielectric") };
+    fassert!(ior, bk7.ior(REFERENCE_WAVELENGTH));
+    assert_eq!(material(0).dispersion, Some(bk7));
+    let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.01 };
+    assert_eq!(material(1).bsdf, BsdfKind::Dielectric { ior: cauchy.ior(REFERENCE_WAVELENGTH), roughness: 0.2 });
+    assert_eq!(material(1).dispersion, Some(Dispersion::Cauchy { a: 1.5, b: 0.01 }));
+    assert_eq!(material(2).dispersion, Some(bk7));
+    let loaded = load_json(&to_json(&r).unwrap()).unwrap();
+    assert_eq!(loaded.world.objects[1].borrow().get_material().dispersion, material(1).dispersion);
+    assert!(load_yaml(&src.replace("dispersion: bk7", "dispersion: crown")).is_err());
+    assert!(load_yaml(&src.replace("dispersion: bk7", "dispersion: bk7\n    metallic: 1")).is_err());
+    assert!(load_yaml(&src.replace("      a: 1.5\n", "")).is_err());
+
+    // The index of refraction comes from the dispersion only, and stays above 1 without poles
+    assert!(load_yaml(&src.replace("dispersion: bk7", "dispersion: bk7\n    ior: 1.5")).is_err());
+    assert!(load_yaml(&src.replace("b: 0.01", "b: -0.1")).is_err());
+    assert!(load_yaml(&src.replace("0.0200179144", "0.3")).is_err());
+    assert!(load_yaml(&src.replace("1.03961212", "-3")).is_err());
+    assert!(Dispersion::Cauchy { a: 1.5, b: -0.1 }.validate().is_err());
+    for name in GLASSES {
+        assert!(Dispersion::glass(name).unwrap().validate().is_ok());
+    }
+}